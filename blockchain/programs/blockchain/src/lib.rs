@@ -1,29 +1,19 @@
 use anchor_lang::prelude::*;
+use fhe_handles::{fhe_type, opcode, Origin, Scalar};
 
 declare_id!("GEFoAn6CNJiG9dq8xgm24fjzjip7n5GcH5AyqVC6QzdD");
 
-// Helper function for generating ciphertexts
-fn generate_ciphertext(clock: &Clock) -> [u8; 32] {
-    let timestamp = clock.unix_timestamp;
-    let slot = clock.slot;
-    
-    let mut value = [0u8; 32];
-    for i in 0..32 {
-        let mixed = (
-            (slot.wrapping_mul(1337 + i as u64)) ^
-            (timestamp as u64).wrapping_mul(7919 + i as u64)
-        ) as u8;
-        value[i] = mixed;
-    }
-    value
-}
-
-// Custom function to generate unique hash
-fn generate_unique_hash() -> [u8; 32] {
-    let clock = Clock::get().unwrap();
-    let mut hasher = anchor_lang::solana_program::hash::Hasher::default();
-    hasher.hash(&clock.slot.to_le_bytes());
-    hasher.result().to_bytes()
+// Derives the handle of the next result computed for `account` and bumps its nonce,
+// so every operation gets a handle of its own, see fhe_handles
+fn next_handle(nonce: &mut HandleNonce, account: &Pubkey, opcode: u8, operands: &[[u8; 32]], scalar: Option<Scalar>, result_type: u8) -> [u8; 32] {
+    let origin = Origin {
+        program_id: crate::ID.to_bytes(),
+        account: account.to_bytes(),
+        nonce: nonce.next,
+    };
+    msg!("Handle nonce: {}", nonce.next);
+    nonce.next += 1;
+    fhe_handles::derive(&origin, opcode, operands, scalar, result_type)
 }
 
 #[account]
@@ -51,9 +41,15 @@ pub mod blockchain {
         );
         anchor_lang::system_program::transfer(cpi_context, amount)?;
 
-        let clock = Clock::get()?;
-        let value = generate_ciphertext(&clock);
-        
+        let value = next_handle(
+            &mut ctx.accounts.handle_nonce,
+            &ctx.accounts.user.key(),
+            opcode::ENCRYPT,
+            &[],
+            Some(Scalar { fhe_type: fhe_type::UINT64, bytes: &amount.to_le_bytes() }),
+            fhe_type::UINT64,
+        );
+
         ctx.accounts.deposit_info.owner = ctx.accounts.user.key();
         ctx.accounts.deposit_info.value = value;
         
@@ -67,8 +63,15 @@ pub mod blockchain {
         msg!("FHE Add - LHS: {:?}", lhs);
         msg!("FHE Add - RHS: {:?}", rhs);
         
-        let result_value = generate_unique_hash();
-        
+        let result_value = next_handle(
+            &mut ctx.accounts.handle_nonce,
+            &ctx.accounts.user.key(),
+            opcode::ADD,
+            &[lhs, rhs],
+            None,
+            fhe_type::UINT8,
+        );
+
         ctx.accounts.result_info.owner = ctx.accounts.user.key();
         ctx.accounts.result_info.value = result_value;
         
@@ -127,6 +130,12 @@ pub mod blockchain {
     }
 }
 
+/// Handles derived for an account so far, the nonce of its next handle.
+#[account]
+pub struct HandleNonce {
+    pub next: u64,
+}
+
 #[account]
 pub struct DepositInfo {
     owner: Pubkey,   
@@ -144,6 +153,15 @@ pub struct Deposit<'info> {
     )]
     pub deposit_info: Account<'info, DepositInfo>,
 
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + 8,
+        seeds = [b"handle_nonce", user.key().as_ref()],
+        bump
+    )]
+    pub handle_nonce: Account<'info, HandleNonce>,
+
     /// CHECK: This is the PDA that will hold SOL
     #[account(
         mut,
@@ -202,6 +220,15 @@ pub struct FHEOperation<'info> {
         bump
     )]
     pub result_info: Account<'info, DepositInfo>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + 8,
+        seeds = [b"handle_nonce", user.key().as_ref()],
+        bump
+    )]
+    pub handle_nonce: Account<'info, HandleNonce>,
     
     #[account(mut)]
     pub user: Signer<'info>,
@@ -13,6 +13,7 @@ pub mod operations {
         let cpi_accounts = blockchain::cpi::accounts::FHEOperation {
             user: ctx.accounts.user.to_account_info(),
             result_info: ctx.accounts.result_info.to_account_info(),
+            handle_nonce: ctx.accounts.handle_nonce.to_account_info(),
             system_program: ctx.accounts.system_program.to_account_info(),
         };
         
@@ -40,6 +41,10 @@ pub struct TestOperation<'info> {
         bump
     )]
     pub result_info: Account<'info, blockchain::DepositInfo>,  // Using DepositInfo from blockchain
+
+    /// CHECK: The user's handle nonce, initialized by the blockchain program in the CPI call
+    #[account(mut)]
+    pub handle_nonce: UncheckedAccount<'info>,
     
     pub system_program: Program<'info, System>,
 }
\ No newline at end of file
@@ -52,6 +52,7 @@ pub mod app {
         let cpi_accounts_sum = FheOp {
             signer: ctx.accounts.signer.to_account_info(),
             result: ctx.accounts.storage_sum.to_account_info(),
+            handle_nonce: ctx.accounts.handle_nonce.to_account_info(),
             system_program: ctx.accounts.system_program.to_account_info(),
         };
 
@@ -81,5 +82,8 @@ pub struct UseFhe<'info> {
     /// CHECK: This account stores the result of the addition
     #[account(mut)]
     pub storage_sum: UncheckedAccount<'info>,
+    /// CHECK: The signer's handle nonce, initialized by the fhe_lib program in the CPI call
+    #[account(mut)]
+    pub handle_nonce: UncheckedAccount<'info>,
 }
 
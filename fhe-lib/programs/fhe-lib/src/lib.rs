@@ -3,7 +3,8 @@ use anchor_lang::solana_program::pubkey::Pubkey;
 mod utils;
 use crate::utils::fhe_types::*;
 pub use crate::utils::fhe_types::CipherText;
-use crate::utils::internals::generate_hash;
+use crate::utils::internals::next_handle;
+use fhe_handles::{fhe_type, opcode};
 use crate::utils::events::*;
 
 declare_id!("Fuj5qpvT66C7pz4fvyLDV6d8YCUS9idJH2i66Qj5vedh");
@@ -25,11 +26,23 @@ pub mod fhe_lib {
     }
 
     pub fn fhe_add(ctx: Context<FheOp>, lhs: CipherText, rhs: CipherText) -> Result<CipherText> {
-        let sum = generate_hash(&Clock::get()?);
+        let result_type = fhe_type::uint(lhs.bit_length).ok_or(ProgramError::InvalidArgument)?;
+        let account = ctx.accounts.signer.key();
+        let nonce = ctx.accounts.handle_nonce.next;
+        let sum = next_handle(
+            &mut ctx.accounts.handle_nonce,
+            &account,
+            opcode::ADD,
+            &[lhs.key, rhs.key],
+            None,
+            result_type,
+        );
         emit!(Add8 {
             lhs: lhs.key,
             rhs: rhs.key,
             sum: sum,
+            account: account,
+            nonce: nonce,
         });
         let result = &mut ctx.accounts.result;
         result.key = sum;
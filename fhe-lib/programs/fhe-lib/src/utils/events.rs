@@ -5,6 +5,9 @@ pub struct Add8 {
     pub lhs: [u8; 32],
     pub rhs: [u8; 32],
     pub sum: [u8; 32],
+    /// The account and nonce `sum` was derived with, for the server to verify it
+    pub account: Pubkey,
+    pub nonce: u64,
 }
 
 #[event]
@@ -9,6 +9,13 @@ pub struct CipherText {
     pub bit_length: u16,
 }
 
+/// Handles derived for an account so far, the nonce of its next handle.
+#[account]
+#[derive(InitSpace)]
+pub struct HandleNonce {
+    pub next: u64,
+}
+
 #[derive(Accounts)]
 #[instruction(key: [u8; 32])]
 pub struct CreateStorage<'info>{
@@ -39,6 +46,14 @@ pub struct FheOp<'info> {
         bump
     )]
     pub result: Account<'info, CipherText>,
+    #[account(
+        init_if_needed,
+        payer = signer,
+        space = 8 + HandleNonce::INIT_SPACE,
+        seeds = [b"handle_nonce", signer.key().as_ref()],
+        bump
+    )]
+    pub handle_nonce: Account<'info, HandleNonce>,
 }
 
 
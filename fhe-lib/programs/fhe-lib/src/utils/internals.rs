@@ -1,18 +1,16 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::pubkey::Pubkey;
+use fhe_handles::{Origin, Scalar};
+use crate::utils::fhe_types::HandleNonce;
 
-pub fn generate_hash(clock: &Clock) -> [u8; 32] {
-    let timestamp = clock.unix_timestamp; 
-    let slot = clock.slot;
-    
-    let mut value = [0u8; 32];
-    for i in 0..32 {
-        let mixed = (
-            (slot.wrapping_mul(1337 + i as u64)) ^
-            (timestamp as u64).wrapping_mul(7919 + i as u64)
-        ) as u8;
-        value[i] = mixed;
-    }
-    value
+// Derives the handle of the next result computed for `account` and bumps its nonce,
+// so every operation gets a handle of its own, see fhe_handles
+pub fn next_handle(nonce: &mut HandleNonce, account: &Pubkey, opcode: u8, operands: &[[u8; 32]], scalar: Option<Scalar>, result_type: u8) -> [u8; 32] {
+    let origin = Origin {
+        program_id: crate::ID.to_bytes(),
+        account: account.to_bytes(),
+        nonce: nonce.next,
+    };
+    nonce.next += 1;
+    fhe_handles::derive(&origin, opcode, operands, scalar, result_type)
 }
-
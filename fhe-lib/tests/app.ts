@@ -30,6 +30,11 @@ describe("app", () => {
             appProgram.programId  // Note: Using app program ID here, not fheLibId
         );
 
+        const [handleNoncePDA] = await PublicKey.findProgramAddress(
+            [Buffer.from("handle_nonce"), provider.wallet.publicKey.toBuffer()],
+            fheLibId
+        );
+
         console.log("storagePDA_A", storagePDA_A.toBase58());
         console.log("storagePDA_B", storagePDA_B.toBase58());
         console.log("storagePDA_Sum", storagePDA_Sum.toBase58());
@@ -58,7 +63,7 @@ describe("app", () => {
             storageSum: storagePDA_Sum,
             storageA: storagePDA_A,
             storageB: storagePDA_B,
-            
+            handleNonce: handleNoncePDA,
         })
         .rpc();
 
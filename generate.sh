@@ -7,6 +7,13 @@ NC='\033[0m' # No Color
 
 echo "Building and running keys.rs..."
 
+# Secret key files are only written encrypted. Without a passphrase, a local
+# master key file outside the keys directory stands in for a KMS
+if [ -z "$FHE_KEY_PASSPHRASE" ]; then
+    export FHE_KMS_KEY_FILE=${FHE_KMS_KEY_FILE:-kms/master.key}
+    mkdir -p "$(dirname "$FHE_KMS_KEY_FILE")"
+fi
+
 # Run the keys binary with the new name
 if cargo run --bin generate_keys; then
     echo -e "${GREEN}Keys program completed successfully${NC}"
@@ -0,0 +1,208 @@
+//! Derivation of ciphertext handles, shared by the on-chain programs and the
+//! FHE server.
+//!
+//! A handle names the ciphertext an operation writes. It is the SHA-256 of
+//! the program requesting the operation, the account it runs for with that
+//! account's nonce, the opcode, the result type, the operand handles and the
+//! plaintext operand if there is one.
+//! Programs bump the account's nonce with every handle they derive, so two
+//! operations never share a result handle, even within one slot. The server
+//! derives the handle again from the same inputs before writing the result.
+#![no_std]
+
+use sha2::{Digest, Sha256};
+
+/// Separates handle preimages from other SHA-256 inputs, a new encoding gets a new version
+const DOMAIN: &[u8] = b"svm-fhe/handle/v2";
+
+/// Opcodes as the server numbers its operations.
+pub mod opcode {
+    pub const ADD: u8 = 0;
+    pub const SUB: u8 = 1;
+    pub const MUL: u8 = 2;
+    pub const DIV: u8 = 3;
+    pub const REM: u8 = 4;
+    pub const MIN: u8 = 5;
+    pub const MAX: u8 = 6;
+    pub const AND: u8 = 7;
+    pub const OR: u8 = 8;
+    pub const XOR: u8 = 9;
+    pub const SHL: u8 = 10;
+    pub const SHR: u8 = 11;
+    pub const EQ: u8 = 12;
+    pub const NE: u8 = 13;
+    pub const LT: u8 = 14;
+    pub const LE: u8 = 15;
+    pub const GT: u8 = 16;
+    pub const GE: u8 = 17;
+    pub const NEG: u8 = 18;
+    pub const NOT: u8 = 19;
+    pub const SELECT: u8 = 20;
+    /// A value encrypted from a plaintext, e.g. a deposit, with no operands
+    pub const ENCRYPT: u8 = 128;
+}
+
+/// Result types, the tags the server stores ciphertexts under.
+pub mod fhe_type {
+    pub const BOOL: u8 = 0;
+    pub const UINT8: u8 = 1;
+    pub const UINT16: u8 = 2;
+    pub const UINT32: u8 = 3;
+    pub const UINT64: u8 = 4;
+    pub const UINT128: u8 = 5;
+    pub const UINT256: u8 = 6;
+    pub const INT8: u8 = 7;
+    pub const INT16: u8 = 8;
+    pub const INT32: u8 = 9;
+    pub const INT64: u8 = 10;
+    pub const INT128: u8 = 11;
+    pub const INT256: u8 = 12;
+
+    /// The unsigned type of `bits` bits.
+    pub fn uint(bits: u16) -> Option<u8> {
+        match bits {
+            8 => Some(UINT8),
+            16 => Some(UINT16),
+            32 => Some(UINT32),
+            64 => Some(UINT64),
+            128 => Some(UINT128),
+            256 => Some(UINT256),
+            _ => None,
+        }
+    }
+}
+
+/// Where an operation comes from: the requesting program, and the account it
+/// runs for with the nonce of that account's next handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub program_id: [u8; 32],
+    pub account: [u8; 32],
+    pub nonce: u64,
+}
+
+/// A plaintext operand: the tag of its type in `fhe_type` and its value in
+/// little-endian two's complement at the width of that type, one byte for a
+/// boolean. An encrypted plaintext, e.g. a deposited amount, is the scalar
+/// of its `ENCRYPT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scalar<'a> {
+    pub fhe_type: u8,
+    pub bytes: &'a [u8],
+}
+
+/// Handle of the result of `opcode` on `operands` and `scalar` requested from `origin`.
+pub fn derive(origin: &Origin, opcode: u8, operands: &[[u8; 32]], scalar: Option<Scalar>, result_type: u8) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(DOMAIN);
+    hasher.update(origin.program_id);
+    hasher.update(origin.account);
+    hasher.update(origin.nonce.to_le_bytes());
+    hasher.update([opcode, result_type]);
+    // Prefixing the count keeps the encoding unambiguous
+    hasher.update((operands.len() as u32).to_le_bytes());
+    for operand in operands {
+        hasher.update(operand);
+    }
+    match scalar {
+        None => hasher.update([0]),
+        Some(scalar) => {
+            hasher.update([1, scalar.fhe_type]);
+            hasher.update((scalar.bytes.len() as u32).to_le_bytes());
+            hasher.update(scalar.bytes);
+        }
+    }
+    hasher.finalize().into()
+}
+
+/// Whether `handle` is the one `derive` gives for the same inputs.
+pub fn verify(handle: &[u8; 32], origin: &Origin, opcode: u8, operands: &[[u8; 32]], scalar: Option<Scalar>, result_type: u8) -> bool {
+    derive(origin, opcode, operands, scalar, result_type) == *handle
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: Origin = Origin { program_id: [1; 32], account: [2; 32], nonce: 0 };
+
+    fn hex(handle: [u8; 32]) -> [u8; 64] {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut out = [0; 64];
+        for (i, byte) in handle.iter().enumerate() {
+            out[2 * i] = DIGITS[(byte >> 4) as usize];
+            out[2 * i + 1] = DIGITS[(byte & 0xf) as usize];
+        }
+        out
+    }
+
+    fn at(nonce: u64) -> Origin {
+        Origin { nonce, ..ORIGIN }
+    }
+
+    // Fixed vectors, the programs and the server must both reproduce them.
+    // A change to any of them is a change of the encoding and needs a new DOMAIN.
+    #[test]
+    fn binary_operation() {
+        let handle = derive(&at(7), opcode::ADD, &[[3; 32], [4; 32]], None, fhe_type::UINT64);
+        assert_eq!(&hex(handle), b"0a3d2f6c351ce92d37c6590a84db2172fb0e6d5b17bd8001905347bd0a8352fb");
+    }
+
+    #[test]
+    fn comparison() {
+        let handle = derive(&at(2), opcode::LT, &[[3; 32], [4; 32]], None, fhe_type::BOOL);
+        assert_eq!(&hex(handle), b"bc444911973b4f97dc293f4485f7c3810f31d3c9bdfc5e7e7589421856e102e0");
+    }
+
+    #[test]
+    fn encrypted_deposit() {
+        let amount = 1_000_000u64.to_le_bytes();
+        let scalar = Scalar { fhe_type: fhe_type::UINT64, bytes: &amount };
+        let handle = derive(&at(0), opcode::ENCRYPT, &[], Some(scalar), fhe_type::UINT64);
+        assert_eq!(&hex(handle), b"96f478c8840a353846677b7b3ecf3e667be69b299887d5fc752a009796be365f");
+    }
+
+    #[test]
+    fn signed_scalar_operation() {
+        let scalar = Scalar { fhe_type: fhe_type::INT8, bytes: &(-1i8).to_le_bytes() };
+        let handle = derive(&at(1), opcode::SUB, &[[3; 32]], Some(scalar), fhe_type::INT8);
+        assert_eq!(&hex(handle), b"143fe3931431bccf654135b8aa78d3214865c52b07e737c26ca8dd93f045612d");
+    }
+
+    #[test]
+    fn every_input_changes_the_handle() {
+        let scalar = Scalar { fhe_type: fhe_type::UINT8, bytes: &[5] };
+        let base = derive(&ORIGIN, opcode::ADD, &[[3; 32]], Some(scalar), fhe_type::UINT8);
+        let variants = [
+            derive(&Origin { program_id: [9; 32], ..ORIGIN }, opcode::ADD, &[[3; 32]], Some(scalar), fhe_type::UINT8),
+            derive(&Origin { account: [9; 32], ..ORIGIN }, opcode::ADD, &[[3; 32]], Some(scalar), fhe_type::UINT8),
+            derive(&at(1), opcode::ADD, &[[3; 32]], Some(scalar), fhe_type::UINT8),
+            derive(&ORIGIN, opcode::SUB, &[[3; 32]], Some(scalar), fhe_type::UINT8),
+            derive(&ORIGIN, opcode::ADD, &[[4; 32]], Some(scalar), fhe_type::UINT8),
+            derive(&ORIGIN, opcode::ADD, &[[3; 32]], Some(Scalar { bytes: &[6], ..scalar }), fhe_type::UINT8),
+            derive(&ORIGIN, opcode::ADD, &[[3; 32]], Some(Scalar { fhe_type: fhe_type::INT8, ..scalar }), fhe_type::UINT8),
+            derive(&ORIGIN, opcode::ADD, &[[3; 32]], None, fhe_type::UINT8),
+            derive(&ORIGIN, opcode::ADD, &[[3; 32]], Some(scalar), fhe_type::UINT16),
+        ];
+        for variant in variants {
+            assert_ne!(variant, base);
+        }
+    }
+
+    #[test]
+    fn no_scalar_differs_from_an_empty_one() {
+        let empty = Scalar { fhe_type: fhe_type::BOOL, bytes: &[] };
+        assert_ne!(
+            derive(&ORIGIN, opcode::ENCRYPT, &[], None, fhe_type::BOOL),
+            derive(&ORIGIN, opcode::ENCRYPT, &[], Some(empty), fhe_type::BOOL),
+        );
+    }
+
+    #[test]
+    fn verify_matches_derive() {
+        let handle = derive(&at(3), opcode::NOT, &[[3; 32]], None, fhe_type::BOOL);
+        assert!(verify(&handle, &at(3), opcode::NOT, &[[3; 32]], None, fhe_type::BOOL));
+        assert!(!verify(&handle, &at(4), opcode::NOT, &[[3; 32]], None, fhe_type::BOOL));
+    }
+}
@@ -1,6 +1,15 @@
-use anyhow::Result;
-use serde::Serialize;
-use tokio::runtime::Handle;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const MAX_RETRIES: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Error codes of jobs that may succeed when submitted again. The backend
+/// releases the idempotency key of such a job, so the same key runs it anew.
+const RETRYABLE_CODES: [&str; 3] = ["conflict", "server_busy", "decryption_unavailable"];
 
 #[derive(Serialize)]
 struct TransferRequest {
@@ -13,49 +22,149 @@ struct TransferRequest {
 struct DepositRequest {
     value: u64,
     key: [u8; 32],
+    owner: Option<[u8; 32]>,
+    origin: Option<Origin>,
+}
+
+/// What the program derived a deposit's key from besides the amount, the
+/// backend checks the key against it
+#[derive(Serialize, Clone, Copy)]
+pub struct Origin {
+    pub program_id: [u8; 32],
+    pub account: [u8; 32],
+    pub nonce: u64,
+}
+
+/// A job as the backend's `/jobs` endpoints report it
+#[derive(Deserialize)]
+struct Job {
+    id: String,
+    status: String,
+    error: Option<JobError>,
+}
+
+#[derive(Deserialize)]
+struct JobError {
+    code: String,
+    message: String,
+}
+
+impl Job {
+    fn is_pending(&self) -> bool {
+        self.status == "queued" || self.status == "running"
+    }
+
+    fn is_retryable(&self) -> bool {
+        self.error.as_ref().is_some_and(|error| RETRYABLE_CODES.contains(&error.code.as_str()))
+    }
 }
 
-pub async fn transfer(sender: [u8; 32], recipient: [u8; 32], amount: [u8; 32]) -> Result<()> {
+// Why a job could not be run to completion
+enum RunError {
+    // The backend could not be reached or answered 503, trying later may succeed
+    Unavailable(String),
+    Failed(anyhow::Error),
+}
+
+/// Submits the transfer of the on-chain event `event_id`. Resubmitting the same
+/// event, e.g. after a restart, returns the backend's earlier job instead of
+/// moving the amount again.
+pub async fn transfer(server_url: &str, event_id: &str, sender: [u8; 32], recipient: [u8; 32], amount: [u8; 32]) -> Result<()> {
     let request = TransferRequest {
         sender_key: sender,
         recipient_key: recipient,
         transfer_value: amount,
     };
     println!("Sending transfer request to backend");
-    let result = tokio::task::block_in_place(move || {
-        let response = ureq::post("http://localhost:3000/transfer")
-            .set("Content-Type", "application/json")
-            .send_json(&request);
-        Ok(())
-    });
-    result
+    let job = relay(server_url, "transfer", event_id, &request).await?;
+    match job.error {
+        None => {
+            println!("Transfer job {} done", job.id);
+            Ok(())
+        }
+        Some(error) => Err(anyhow!("Transfer job {} failed: {} ({})", job.id, error.message, error.code)),
+    }
 }
 
-pub async fn deposit(value: u64, key: [u8; 32]) -> Result<()> {
+pub async fn deposit(server_url: &str, event_id: &str, value: u64, key: [u8; 32], owner: Option<[u8; 32]>, origin: Option<Origin>) -> Result<()> {
     let request = DepositRequest {
         value,
         key,
+        owner,
+        origin,
     };
-    
-    let result = tokio::task::block_in_place(move || {  
-        // Send the request and check response
-        match ureq::post("http://localhost:3000/post")
-            .set("Content-Type", "application/json")
-            .send_json(&request)
-        {
-            Ok(response) => {
-                println!("Deposit successful: status {}", response.status());
-                Ok(())
-            },
-            Err(err) => {
-                println!("Deposit failed: {}", err);
-                // Return the error instead of ignoring it
-                Err(anyhow::anyhow!("Failed to send deposit: {}", err))
-            }
+    let job = relay(server_url, "post", event_id, &request).await?;
+    match job.error {
+        None => {
+            println!("Deposit job {} done", job.id);
+            Ok(())
+        }
+        // Relayed before, after the backend forgot the idempotency key. Keys are
+        // written once, so the stored value is the one relayed then
+        Some(error) if error.code == "already_exists" => {
+            println!("Deposit of {} was stored before", event_id);
+            Ok(())
         }
-    });
-    
-    result
+        Some(error) => Err(anyhow!("Deposit job {} failed: {} ({})", job.id, error.message, error.code)),
+    }
 }
 
+// Runs `request` as a `kind` job under `idempotency_key`, submitting it again
+// with the same key while the backend is unavailable or the job failed in a way
+// that may pass on retry. Resubmitting a job that is still known returns it, so
+// the event is applied at most once.
+async fn relay<T: Serialize>(server_url: &str, kind: &str, idempotency_key: &str, request: &T) -> Result<Job> {
+    let mut backoff = RETRY_BACKOFF;
+    for attempt in 1..=MAX_RETRIES {
+        let reason = match run_job(server_url, kind, idempotency_key, request).await {
+            Ok(job) if job.is_retryable() => job.error.map(|error| error.message).unwrap_or_default(),
+            Ok(job) => return Ok(job),
+            Err(RunError::Unavailable(reason)) => reason,
+            Err(RunError::Failed(err)) => return Err(err),
+        };
+        println!("{} job {} not done: {}, retrying ({}/{})", kind, idempotency_key, reason, attempt, MAX_RETRIES);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+    }
+    match run_job(server_url, kind, idempotency_key, request).await {
+        Ok(job) => Ok(job),
+        Err(RunError::Unavailable(reason)) => Err(anyhow!("Backend unavailable for {} job {}: {}", kind, idempotency_key, reason)),
+        Err(RunError::Failed(err)) => Err(err),
+    }
+}
 
+// Submits `request` as a `kind` job under `idempotency_key` and polls it until it is finished
+async fn run_job<T: Serialize>(server_url: &str, kind: &str, idempotency_key: &str, request: &T) -> Result<Job, RunError> {
+    let url = format!("{}/jobs/{}", server_url, kind);
+    let mut job: Job = tokio::task::block_in_place(|| {
+        let response = ureq::post(&url)
+            .set("Content-Type", "application/json")
+            .set("Idempotency-Key", idempotency_key)
+            .send_json(request)
+            .map_err(|err| request_error(&format!("Failed to submit {} job", kind), err))?;
+        response
+            .into_json()
+            .map_err(|err| RunError::Failed(anyhow!("Invalid {} job: {}", kind, err)))
+    })?;
+    println!("Submitted {} job {}", kind, job.id);
+    let url = format!("{}/jobs/{}", server_url, job.id);
+    while job.is_pending() {
+        tokio::time::sleep(JOB_POLL_INTERVAL).await;
+        job = tokio::task::block_in_place(|| {
+            let response = ureq::get(&url)
+                .call()
+                .map_err(|err| request_error("Failed to poll job", err))?;
+            response
+                .into_json()
+                .map_err(|err| RunError::Failed(anyhow!("Invalid job status: {}", err)))
+        })?;
+    }
+    Ok(job)
+}
+
+fn request_error(context: &str, err: ureq::Error) -> RunError {
+    match err {
+        ureq::Error::Status(503, _) | ureq::Error::Transport(_) => RunError::Unavailable(format!("{}: {}", context, err)),
+        err => RunError::Failed(anyhow!("{}: {}", context, err)),
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Read from the working directory when `--config` is not given
+pub const DEFAULT_CONFIG_PATH: &str = "relayer.toml";
+
+/// Overrides of the configuration file, environment variables apply unless
+/// the flag is given.
+#[derive(Parser, Debug)]
+#[command(about = "Relays FHE program events from Solana to the FHE server")]
+pub struct Args {
+    /// TOML configuration file, defaults to relayer.toml if present
+    #[arg(long, env = "RELAYER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Solana JSON RPC endpoint
+    #[arg(long, env = "SOLANA_RPC_URL")]
+    pub rpc_url: Option<String>,
+    /// Solana websocket endpoint the program logs are subscribed on
+    #[arg(long, env = "SOLANA_WS_URL")]
+    pub ws_url: Option<String>,
+    /// Address of the FHE program whose events are relayed
+    #[arg(long, env = "PROGRAM_ID")]
+    pub program_id: Option<String>,
+    /// Base URL of the FHE server
+    #[arg(long, env = "FHE_SERVER_URL")]
+    pub server_url: Option<String>,
+    /// Print the resolved configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+/// Relayer settings. From lowest to highest precedence they come from the
+/// defaults, the TOML file, environment variables and command line flags.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub solana: SolanaConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SolanaConfig {
+    pub rpc_url: String,
+    pub ws_url: String,
+    pub program_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub url: String,
+}
+
+impl Default for SolanaConfig {
+    fn default() -> Self {
+        Self {
+            rpc_url: "http://localhost:8899".to_string(),
+            ws_url: "ws://localhost:8900".to_string(),
+            program_id: "GEFoAn6CNJiG9dq8xgm24fjzjip7n5GcH5AyqVC6QzdD".to_string(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { url: "http://localhost:3000".to_string() }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file named by `args`, or `relayer.toml` if it
+    /// exists, and applies the overrides in `args` on top.
+    pub fn load(args: &Args) -> Result<Self> {
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        if let Some(rpc_url) = &args.rpc_url {
+            config.solana.rpc_url = rpc_url.clone();
+        }
+        if let Some(ws_url) = &args.ws_url {
+            config.solana.ws_url = ws_url.clone();
+        }
+        if let Some(program_id) = &args.program_id {
+            config.solana.program_id = program_id.clone();
+        }
+        if let Some(server_url) = &args.server_url {
+            config.server.url = server_url.clone();
+        }
+        // Requests are built as `{url}/transfer`
+        config.server.url = config.server.url.trim_end_matches('/').to_string();
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Checks the settings before connecting to anything, so a typo fails at
+    /// startup rather than as a dropped subscription.
+    pub fn validate(&self) -> Result<()> {
+        check_scheme("solana.rpc_url", &self.solana.rpc_url, &["http://", "https://"])?;
+        check_scheme("solana.ws_url", &self.solana.ws_url, &["ws://", "wss://"])?;
+        check_scheme("server.url", &self.server.url, &["http://", "https://"])?;
+        Pubkey::from_str(&self.solana.program_id)
+            .map_err(|e| anyhow!("solana.program_id {:?} is not a valid pubkey: {}", self.solana.program_id, e))?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+fn check_scheme(name: &str, url: &str, schemes: &[&str]) -> Result<()> {
+    if !schemes.iter().any(|scheme| url.starts_with(scheme)) {
+        bail!("{} {:?} must start with {}", name, url, schemes.join(" or "));
+    }
+    Ok(())
+}
@@ -19,4 +19,13 @@ pub fn parse_array_from_log(log: &str) -> Option<[u8; 32]> {
         }
     }
     None
-}
\ No newline at end of file
+}
+// Index of the top level instruction that wrote `logs[line]`. The runtime logs
+// "Program <id> invoke [1]" as each top level instruction starts
+pub fn instruction_index(logs: &[String], line: usize) -> usize {
+    logs[..=line]
+        .iter()
+        .filter(|log| log.ends_with(" invoke [1]"))
+        .count()
+        .saturating_sub(1)
+}
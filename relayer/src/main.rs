@@ -8,15 +8,46 @@ use std::str::FromStr;
 use anyhow::Result;
 use futures_util::StreamExt; 
 use async_trait::async_trait;
+use clap::Parser;
+mod config;
+use config::{Args, Config};
 mod listener;
-use listener::utils::parse_array_from_log;
+use listener::utils::{instruction_index, parse_array_from_log};
 mod api;
-use api::transfer::{transfer, deposit};
+use api::transfer::{transfer, deposit, Origin};
+use tokio::sync::mpsc;
 
 struct SolanaConnection {
     client: RpcClient,
     ws_url: String,
     program_id: Pubkey,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+/// A program event to relay to the backend, keyed by its idempotency key
+enum Event {
+    Transfer { event_id: String, sender: [u8; 32], recipient: [u8; 32], amount: [u8; 32] },
+    Deposit { event_id: String, amount: u64, ciphertext: [u8; 32], owner: Option<[u8; 32]>, origin: Option<Origin> },
+}
+
+// Relays `events` one at a time in the order they were logged, a transfer
+// reads the balances the events before it wrote
+async fn relay_events(server_url: String, mut events: mpsc::UnboundedReceiver<Event>) {
+    while let Some(event) = events.recv().await {
+        let (event_id, result) = match event {
+            Event::Transfer { event_id, sender, recipient, amount } => {
+                let result = transfer(&server_url, &event_id, sender, recipient, amount).await;
+                (event_id, result)
+            }
+            Event::Deposit { event_id, amount, ciphertext, owner, origin } => {
+                let result = deposit(&server_url, &event_id, amount, ciphertext, owner, origin).await;
+                (event_id, result)
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to relay event {}: {}", event_id, err);
+        }
+    }
 }
 
 #[async_trait]
@@ -37,7 +68,7 @@ trait BackendRelayer {
 }
 
 impl SolanaConnection {
-    pub fn new(rpc_url: &str, ws_url: &str, program_id_str: &str) -> Result<Self> {
+    pub fn new(rpc_url: &str, ws_url: &str, program_id_str: &str, events: mpsc::UnboundedSender<Event>) -> Result<Self> {
         let client = RpcClient::new_with_commitment(
             rpc_url.to_string(),
             CommitmentConfig::confirmed(),
@@ -48,6 +79,7 @@ impl SolanaConnection {
             client,
             program_id,
             ws_url: ws_url.to_string(),
+            events,
         })
     }
 }
@@ -69,7 +101,11 @@ impl ProgramListenerTransfer for SolanaConnection {
         .await?;
 
         while let Some(response) = logs_subscription.next().await {
-            for log in &response.value.logs {
+            let signature = &response.value.signature;
+            for (line, log) in response.value.logs.iter().enumerate() {
+                // Idempotency key of the event, seeing the transaction again after a
+                // reconnect or fork does not repeat it
+                let event_id = format!("{}:{}", signature, instruction_index(&response.value.logs, line));
                 if log.contains("Instruction: Transfer") {
                     println!("Transfer event detected!");
                     struct TransferData {
@@ -101,7 +137,8 @@ impl ProgramListenerTransfer for SolanaConnection {
                         println!("  From: {:?}", sender);
                         println!("  To:   {:?}", recipient);
                         println!("  Amount: {:?}", amount);
-                        transfer(sender, recipient, amount).await?;
+                        // Queued, waiting on the backend's job must not hold up later logs
+                        self.events.send(Event::Transfer { event_id: event_id.clone(), sender, recipient, amount })?;
                     }
                 }
 
@@ -119,6 +156,8 @@ impl ProgramListenerTransfer for SolanaConnection {
                     ];
                     
                     let mut lamport_value: Option<u64> = None;
+                    let mut owner: Option<[u8; 32]> = None;
+                    let mut nonce: Option<u64> = None;
                     
                     for detail_log in &response.value.logs {
                         // Extract the ciphertext
@@ -131,27 +170,42 @@ impl ProgramListenerTransfer for SolanaConnection {
                             }
                         }
                         
+                        // Nonce the program derived the deposit's key with
+                        if let Some(value) = detail_log.split("Handle nonce: ").nth(1) {
+                            nonce = value.trim().parse().ok();
+                        }
+
                         if detail_log.contains("deposited") && detail_log.contains("lamports") {
                             let parts: Vec<&str> = detail_log.split_whitespace().collect();
-                            // Format: "... deposited <AMOUNT> lamports"
+                            // Format: "... User <PUBKEY> deposited <AMOUNT> lamports"
                             for (i, part) in parts.iter().enumerate() {
                                 if part.contains("deposited") && i + 1 < parts.len() {
                                     if let Ok(amount) = parts[i + 1].parse::<u64>() {
                                         lamport_value = Some(amount);
                                     }
                                 }
+                                if part.contains("deposited") && i > 0 {
+                                    if let Ok(user) = Pubkey::from_str(parts[i - 1]) {
+                                        owner = Some(user.to_bytes());
+                                    }
+                                }
                             }
                         }
                     }
                     
                     let ciphertext = deposit_data[0].value;
+                    let origin = owner.zip(nonce).map(|(account, nonce)| Origin {
+                        program_id: self.program_id.to_bytes(),
+                        account,
+                        nonce,
+                    });
                     
                     // Process the complete deposit if we have all needed data
                     if let (Some(amount), Some(cipher)) = (lamport_value, ciphertext) {
                         println!("Complete deposit detected:");
                         println!("  Amount: {} lamports", amount);
                         println!("  Ciphertext: {:?}", cipher);
-                        deposit(amount, cipher).await?;
+                        self.events.send(Event::Deposit { event_id: event_id.clone(), amount, ciphertext: cipher, owner, origin })?;
                     }
                 }
             }
@@ -163,12 +217,24 @@ impl ProgramListenerTransfer for SolanaConnection {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {  
+    let args = Args::parse();
+    let config = Config::load(&args)?;
+    // Printed before validation, so an invalid configuration can be inspected
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        config.validate()?;
+        return Ok(());
+    }
+    config.validate()?;
+
+    let (events, queue) = mpsc::unbounded_channel();
     let connection = SolanaConnection::new(
-        "http://localhost:8899",
-        "ws://localhost:8900",
-        "GEFoAn6CNJiG9dq8xgm24fjzjip7n5GcH5AyqVC6QzdD"
+        &config.solana.rpc_url,
+        &config.solana.ws_url,
+        &config.solana.program_id,
+        events,
     )?;
-    deposit(0, [0; 32]).await?;
+    tokio::spawn(relay_events(config.server.url.clone(), queue));
     println!("Starting Solana relayer...");
     connection.listen().await?;
     Ok(())
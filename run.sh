@@ -18,8 +18,21 @@ echo "Starting TypeScript listner..."
 cd $PROJECT_DIR/listner && ts-node listner.ts &
 sleep 5
 
+echo "Starting decryptors..."
+# Shared by the server and the decryptors, a local test secret
+export DECRYPTOR_TOKEN=${DECRYPTOR_TOKEN:-local-test-token}
+# The shares are encrypted under the master key generate.sh uses by default
+if [ -z "$FHE_KEY_PASSPHRASE" ]; then
+    export FHE_KMS_KEY_FILE=${FHE_KMS_KEY_FILE:-kms/master.key}
+fi
+for party in 0 1 2; do
+    cd $PROJECT_DIR && cargo run --bin decryptor -- --party $party &
+done
+sleep 5
+
 echo "Starting Rust server..."
-cd $PROJECT_DIR && cargo run &
+# Deposits are relayed with their public amount
+cd $PROJECT_DIR && ALLOW_PLAINTEXT_DEPOSITS=true cargo run &
 sleep 5
 
 echo "Running Anchor tests..."
@@ -32,5 +45,6 @@ sleep 5
 echo "Cleaning up processes..."
 pkill -9 -f solana-test-validator  # Force kill (-9)
 pkill -9 -f "cargo run"
+pkill -9 -x decryptor
 pkill -9 -f "ts-node"
 echo "All processes terminated"
\ No newline at end of file
@@ -0,0 +1,88 @@
+use ed25519_dalek::{Signature, VerifyingKey};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Domain separators so an authorization cannot be confused with a signature
+// the owner produced for anything else, e.g. a Solana transaction
+const DECRYPT_DOMAIN: &[u8] = b"svm-fhe:decrypt";
+const REENCRYPT_DOMAIN: &[u8] = b"svm-fhe:reencrypt";
+const WITHDRAW_DOMAIN: &[u8] = b"svm-fhe:withdraw";
+// Longest validity window accepted, bounds how long nonces must be remembered
+pub const MAX_AUTHORIZATION_SECS: i64 = 300;
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// The ciphertext has no recorded owner, so nobody may decrypt it
+    NoOwner,
+    /// The signature is malformed or was not produced by the owner
+    InvalidSignature,
+    /// The authorization is past its expiry or too far in the future
+    Expired,
+    /// The nonce was already used by this owner
+    Replayed,
+    /// A value is computed from values of another owner than the one requested
+    OwnerMismatch,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::NoOwner => write!(f, "ciphertext has no owner"),
+            AuthError::InvalidSignature => write!(f, "invalid owner signature"),
+            AuthError::Expired => write!(f, "authorization expired or outside the validity window"),
+            AuthError::Replayed => write!(f, "nonce already used"),
+            AuthError::OwnerMismatch => write!(f, "result owner differs from the owner of its inputs"),
+        }
+    }
+}
+
+/// The bytes an owner signs to authorize decryption of `key`.
+pub fn decrypt_message(key: &[u8; 32], nonce: u64, expiry: i64) -> Vec<u8> {
+    [DECRYPT_DOMAIN, key, &nonce.to_le_bytes(), &expiry.to_le_bytes()].concat()
+}
+
+/// The bytes an owner signs to have `key` sealed to the X25519 key `recipient`.
+/// Covering the recipient stops a relayer from substituting its own key.
+pub fn reencrypt_message(key: &[u8; 32], recipient: &[u8; 32], nonce: u64, expiry: i64) -> Vec<u8> {
+    [REENCRYPT_DOMAIN, key, recipient, &nonce.to_le_bytes(), &expiry.to_le_bytes()].concat()
+}
+
+/// The bytes an owner signs to withdraw from `key` the encrypted amount at
+/// `value` or the plaintext `amount`. Each is preceded by whether it is set,
+/// so one cannot be read as the other.
+pub fn withdraw_message(key: &[u8; 32], value: Option<&[u8; 32]>, amount: Option<u64>, nonce: u64, expiry: i64) -> Vec<u8> {
+    let value = value.map(|value| [&[1u8][..], value].concat()).unwrap_or_else(|| vec![0]);
+    let amount = amount.map(|amount| [&[1u8][..], &amount.to_le_bytes()].concat()).unwrap_or_else(|| vec![0]);
+    [WITHDRAW_DOMAIN, key, &value, &amount, &nonce.to_le_bytes(), &expiry.to_le_bytes()].concat()
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Checks that `signature` is the owner's ed25519 signature over `message`
+/// and that `expiry` lies within the accepted window.
+///
+/// The owner is the 32-byte Solana pubkey recorded with the ciphertext.
+/// Nonce uniqueness is enforced separately by the storage layer.
+pub fn verify_owner_signature(
+    owner: Option<[u8; 32]>,
+    message: &[u8],
+    expiry: i64,
+    signature: &[u8],
+) -> Result<[u8; 32], AuthError> {
+    let owner = owner.ok_or(AuthError::NoOwner)?;
+    let now = now();
+    if expiry < now || expiry > now + MAX_AUTHORIZATION_SECS {
+        return Err(AuthError::Expired);
+    }
+    let verifying_key = VerifyingKey::from_bytes(&owner)
+        .map_err(|_| AuthError::InvalidSignature)?;
+    let signature = Signature::from_slice(signature)
+        .map_err(|_| AuthError::InvalidSignature)?;
+    verifying_key.verify_strict(message, &signature)
+        .map_err(|_| AuthError::InvalidSignature)?;
+    Ok(owner)
+}
@@ -1,36 +1,103 @@
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use std::collections::HashMap;
-use tfhe::{FheUint8, FheUint64};
-
-// Make the value type generic
-#[derive(Clone)]
-pub enum CacheValue {
-    U8(FheUint8),
-    U64(FheUint64),
+use std::sync::atomic::{AtomicU64, Ordering};
+use lru::LruCache;
+use tokio::sync::Mutex;
+use crate::ciphertext::TypedCiphertext;
+use crate::types::CacheStats;
+
+struct Entry {
+    value: TypedCiphertext,
+    version: i64,
+    size: usize,
+}
+
+struct Store {
+    entries: LruCache<[u8; 32], Entry>,
+    size: usize,
 }
 
+/// Decompressed ciphertexts keyed by handle, with the row version they were
+/// read or written at.
+///
+/// Bounded by the approximate memory of the cached values, evicting the least
+/// recently used handles first. Only the storage layer writes to it, after a
+/// commit, so it never holds a value that is not in the database.
 pub struct Cache {
-    store: Arc<RwLock<HashMap<[u8; 32], CacheValue>>>,
+    store: Mutex<Store>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
 }
 
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(capacity: usize) -> Self {
         Self {
-            store: Arc::new(RwLock::new(HashMap::new())),
+            store: Mutex::new(Store { entries: LruCache::unbounded(), size: 0 }),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Caches `value` at `version`, unless a newer version of `key` is cached.
+    /// A read that finishes after a concurrent commit must not put back the
+    /// value the commit replaced.
+    pub async fn insert(&self, key: [u8; 32], value: TypedCiphertext, version: i64) {
+        let size = value.size_in_bytes();
+        let mut store = self.store.lock().await;
+        if store.entries.peek(&key).is_some_and(|cached| cached.version > version) {
+            return;
+        }
+        if let Some(old) = store.entries.pop(&key) {
+            store.size -= old.size;
         }
+        // A value larger than the whole cache would only evict everything else
+        if size > self.capacity {
+            return;
+        }
+        while store.size + size > self.capacity {
+            let Some((_, evicted)) = store.entries.pop_lru() else { break };
+            store.size -= evicted.size;
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        store.size += size;
+        store.entries.put(key, Entry { value, version, size });
     }
 
-    pub async fn insert_u64(&self, key: [u8; 32], value: FheUint64) {
-        let mut store = self.store.write().await;
-        store.insert(key, CacheValue::U64(value));
+    pub async fn get(&self, key: &[u8; 32]) -> Option<(TypedCiphertext, i64)> {
+        let mut store = self.store.lock().await;
+        match store.entries.get(key) {
+            Some(entry) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some((entry.value.clone(), entry.version))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
     }
 
-    pub async fn get_u64(&self, key: &[u8; 32]) -> Option<FheUint64> {
-        let store = self.store.read().await;
-        match store.get(key) {
-            Some(CacheValue::U64(v)) => Some(v.clone()),
-            _ => None,
+    pub async fn remove(&self, key: &[u8; 32]) {
+        let mut store = self.store.lock().await;
+        if let Some(old) = store.entries.pop(key) {
+            store.size -= old.size;
         }
     }
-}
\ No newline at end of file
+
+    pub async fn stats(&self) -> CacheStats {
+        let store = self.store.lock().await;
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 },
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: store.entries.len(),
+            size_bytes: store.size,
+            capacity_bytes: self.capacity,
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use tfhe::prelude::*;
+use tfhe::{
+    ClientKey, CompressedCiphertextList, CompressedCiphertextListBuilder, FheTypes, KeySwitchingKey,
+    FheBool, FheUint8, FheUint16, FheUint32, FheUint64, FheUint128, FheUint256,
+    FheInt8, FheInt16, FheInt32, FheInt64, FheInt128, FheInt256,
+};
+use tfhe::integer::{I256, IntegerCiphertext, U256};
+use crate::types::FheType;
+
+#[derive(Debug)]
+pub enum CiphertextError {
+    /// The stored value is of a different type than the caller asked for
+    TypeMismatch { expected: FheType, found: FheType },
+    /// The value was encrypted under another key set than the server's
+    KeyMismatch { expected: String, found: String },
+    /// The type tag or list element is not one of the supported `FheType`s
+    UnsupportedType(String),
+    Deserialization(String),
+    Compression(String),
+}
+
+impl std::fmt::Display for CiphertextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CiphertextError::TypeMismatch { expected, found } =>
+                write!(f, "expected {} ciphertext, found {}", expected, found),
+            CiphertextError::KeyMismatch { expected, found } =>
+                write!(f, "ciphertext is under key set {}, the server uses {}", found, expected),
+            CiphertextError::UnsupportedType(e) => write!(f, "unsupported ciphertext type: {}", e),
+            CiphertextError::Deserialization(e) => write!(f, "deserialization error: {}", e),
+            CiphertextError::Compression(e) => write!(f, "compression error: {}", e),
+        }
+    }
+}
+
+/// Rust types that can be loaded from a stored row of the matching `FheType`.
+pub trait StoredType: Sized {
+    const FHE_TYPE: FheType;
+    fn from_typed(value: TypedCiphertext) -> Result<Self, CiphertextError>;
+}
+
+macro_rules! typed_ciphertexts {
+    ($($variant:ident => $fhe:ty as $clear:ty),* $(,)?) => {
+        /// A decompressed ciphertext of any supported type.
+        #[derive(Clone)]
+        pub enum TypedCiphertext {
+            $($variant($fhe)),*
+        }
+
+        impl TypedCiphertext {
+            pub fn fhe_type(&self) -> FheType {
+                match self {
+                    $(TypedCiphertext::$variant(_) => FheType::$variant),*
+                }
+            }
+
+            /// Approximate memory held by the decompressed value.
+            pub fn size_in_bytes(&self) -> usize {
+                let size = match self {
+                    $(TypedCiphertext::$variant(value) => bincode::serialized_size(value)),*
+                };
+                size.unwrap_or_default() as usize
+            }
+
+            /// Switches the value to the key set `rekey` switches to.
+            pub fn keyswitch(&self, rekey: &KeySwitchingKey) -> Self {
+                match self {
+                    $(TypedCiphertext::$variant(value) => TypedCiphertext::$variant(rekey.keyswitch(value))),*
+                }
+            }
+
+            /// Decrypts the value with `from` and encrypts it again with `to`, for
+            /// key sets with no key switching key between them.
+            pub fn reencrypt(&self, from: &ClientKey, to: &ClientKey) -> Self {
+                match self {
+                    $(TypedCiphertext::$variant(value) => {
+                        let clear: $clear = value.decrypt(from);
+                        TypedCiphertext::$variant(<$fhe>::encrypt(clear, to))
+                    }),*
+                }
+            }
+
+            fn push_into(&self, builder: &mut CompressedCiphertextListBuilder) {
+                match self {
+                    $(TypedCiphertext::$variant(value) => { builder.push(value.clone()); }),*
+                }
+            }
+
+            /// Reads element `index` of `list` as the type recorded in the list.
+            pub fn from_list<L: CiphertextList>(list: &L, index: usize) -> Result<Self, CiphertextError> {
+                let missing = || CiphertextError::Deserialization(format!("no element at index {}", index));
+                let kind = list.get_kind_of(index).ok_or_else(missing)?;
+                let value = match FheType::try_from(kind)? {
+                    $(FheType::$variant => list.get::<$fhe>(index)
+                        .map(|value| value.map(TypedCiphertext::$variant))),*
+                };
+                value
+                    .map_err(|e| CiphertextError::Deserialization(e.to_string()))?
+                    .ok_or_else(missing)
+            }
+        }
+
+        impl TryFrom<FheTypes> for FheType {
+            type Error = CiphertextError;
+
+            fn try_from(kind: FheTypes) -> Result<Self, Self::Error> {
+                match kind {
+                    $(FheTypes::$variant => Ok(FheType::$variant),)*
+                    other => Err(CiphertextError::UnsupportedType(format!("{:?}", other))),
+                }
+            }
+        }
+
+        $(
+            impl StoredType for $fhe {
+                const FHE_TYPE: FheType = FheType::$variant;
+
+                fn from_typed(value: TypedCiphertext) -> Result<Self, CiphertextError> {
+                    match value {
+                        TypedCiphertext::$variant(value) => Ok(value),
+                        other => Err(CiphertextError::TypeMismatch { expected: Self::FHE_TYPE, found: other.fhe_type() }),
+                    }
+                }
+            }
+
+            impl From<$fhe> for TypedCiphertext {
+                fn from(value: $fhe) -> Self {
+                    TypedCiphertext::$variant(value)
+                }
+            }
+        )*
+    };
+}
+
+typed_ciphertexts! {
+    Bool => FheBool as bool,
+    Uint8 => FheUint8 as u8,
+    Uint16 => FheUint16 as u16,
+    Uint32 => FheUint32 as u32,
+    Uint64 => FheUint64 as u64,
+    Uint128 => FheUint128 as u128,
+    Uint256 => FheUint256 as U256,
+    Int8 => FheInt8 as i8,
+    Int16 => FheInt16 as i16,
+    Int32 => FheInt32 as i32,
+    Int64 => FheInt64 as i64,
+    Int128 => FheInt128 as i128,
+    Int256 => FheInt256 as I256,
+}
+
+// Radix integers split into blocks alike, a boolean is a single block
+macro_rules! radix_blocks {
+    ($value:expr, $($variant:ident),*) => {
+        match $value {
+            TypedCiphertext::Bool(value) => vec![value.into_raw_parts()],
+            $(TypedCiphertext::$variant(value) => value.into_raw_parts().0.blocks().to_vec(),)*
+        }
+    };
+}
+
+impl TypedCiphertext {
+    /// The shortint blocks of the value, least significant first, what
+    /// threshold decryption decrypts.
+    pub fn into_blocks(self) -> Vec<tfhe::shortint::Ciphertext> {
+        radix_blocks!(self, Uint8, Uint16, Uint32, Uint64, Uint128, Uint256, Int8, Int16, Int32, Int64, Int128, Int256)
+    }
+
+    /// Compresses and serializes the value as stored in the `computations` table.
+    /// Requires the server key to be set on the calling thread.
+    pub fn compress(&self) -> Result<Vec<u8>, CiphertextError> {
+        let mut builder = CompressedCiphertextListBuilder::new();
+        self.push_into(&mut builder);
+        let compressed = builder.build()
+            .map_err(|e| CiphertextError::Compression(e.to_string()))?;
+        bincode::serialize(&compressed)
+            .map_err(|e| CiphertextError::Compression(e.to_string()))
+    }
+
+    /// Inverse of `compress` for a row tagged `fhe_type`.
+    /// Requires the server key to be set on the calling thread.
+    pub fn decompress(fhe_type: FheType, data: &[u8]) -> Result<Self, CiphertextError> {
+        let compressed: CompressedCiphertextList = bincode::deserialize(data)
+            .map_err(|e| CiphertextError::Deserialization(e.to_string()))?;
+        // The row's tag and the list's recorded kind must agree, otherwise the row is corrupt
+        let value = Self::from_list(&compressed, 0)?;
+        if value.fhe_type() != fhe_type {
+            return Err(CiphertextError::TypeMismatch { expected: fhe_type, found: value.fhe_type() });
+        }
+        Ok(value)
+    }
+}
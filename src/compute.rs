@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use rayon::prelude::*;
+use tfhe::prelude::*;
+use tfhe::integer::{I256, U256};
+use crate::ciphertext::TypedCiphertext;
+use crate::types::{FheType, GraphInput, GraphNode, Opcode, Scalar};
+
+#[derive(Debug)]
+pub enum ComputeError {
+    /// The opcode was given the wrong number of operands
+    Arity { opcode: Opcode, expected: usize, found: usize },
+    /// The opcode is not defined for this combination of operand types
+    UnsupportedOperands { opcode: Opcode, types: Vec<FheType> },
+    /// The scalar is not a valid plaintext of the type it is combined with,
+    /// or is a divisor of zero
+    InvalidScalar { scalar: String, fhe_type: FheType },
+    /// A graph node references a later node or a handle with no stored value
+    InvalidGraph(String),
+    /// A graph node failed, `index` is its position in the request
+    Node { index: usize, error: Box<ComputeError> },
+}
+
+impl std::fmt::Display for ComputeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComputeError::Arity { opcode, expected, found } =>
+                write!(f, "{} takes {} operands, {} given", opcode, expected, found),
+            ComputeError::UnsupportedOperands { opcode, types } =>
+                write!(f, "{} is not defined for operands {:?}", opcode, types),
+            ComputeError::InvalidScalar { scalar, fhe_type } =>
+                write!(f, "scalar {} is not a valid {} plaintext", scalar, fhe_type),
+            ComputeError::InvalidGraph(e) => write!(f, "invalid graph: {}", e),
+            ComputeError::Node { index, error } => write!(f, "node {}: {}", index, error),
+        }
+    }
+}
+
+/// Applies `opcode` to `operands` homomorphically.
+///
+/// Binary operations need both operands of the same type, except shifts whose
+/// amount is the unsigned type of the same width. Comparisons return an
+/// `FheBool`, and `select` takes an `FheBool` condition followed by two values
+/// of the same type. Requires the server key to be set on the calling thread.
+pub fn execute(opcode: Opcode, operands: &[TypedCiphertext]) -> Result<TypedCiphertext, ComputeError> {
+    if operands.len() != opcode.arity() {
+        return Err(ComputeError::Arity { opcode, expected: opcode.arity(), found: operands.len() });
+    }
+    let result = match operands {
+        [value] => unary(opcode, value),
+        [lhs, rhs] => binary(opcode, lhs, rhs),
+        [condition, if_true, if_false] => select(condition, if_true, if_false),
+        _ => None,
+    };
+    result.ok_or_else(|| ComputeError::UnsupportedOperands {
+        opcode,
+        types: operands.iter().map(TypedCiphertext::fhe_type).collect(),
+    })
+}
+
+/// Applies `opcode` to `operands` with `scalar` as the right-hand operand.
+///
+/// Defined for the two-operand opcodes. The scalar is parsed as the plaintext
+/// type of the left operand, except for shifts where it is a `u32` amount. Requires the server key to be set on the calling thread.
+pub fn execute_scalar(opcode: Opcode, operands: &[TypedCiphertext], scalar: &Scalar) -> Result<TypedCiphertext, ComputeError> {
+    if opcode.arity() != 2 || operands.len() != 1 {
+        return Err(ComputeError::Arity { opcode, expected: opcode.arity().saturating_sub(1), found: operands.len() });
+    }
+    let lhs = &operands[0];
+    let scalar = scalar.to_string();
+    binary_scalar(opcode, lhs, &scalar)?.ok_or_else(|| ComputeError::UnsupportedOperands {
+        opcode,
+        types: vec![lhs.fhe_type()],
+    })
+}
+
+/// Type of the value `opcode` gives for operands of `operand_types`, with a
+/// scalar as the right-hand operand if `scalar` is set.
+///
+/// Comparisons give an `FheBool`, `select` the type of its values and the
+/// other opcodes the type of their left operand. Whether the types are
+/// supported is only known once the operation runs, so result handles can be
+/// checked before anything is computed.
+pub fn result_type(opcode: Opcode, operand_types: &[FheType], scalar: bool) -> Result<FheType, ComputeError> {
+    let expected = match scalar {
+        true => opcode.arity().saturating_sub(1),
+        false => opcode.arity(),
+    };
+    if operand_types.len() != expected || (scalar && opcode.arity() != 2) {
+        return Err(ComputeError::Arity { opcode, expected, found: operand_types.len() });
+    }
+    Ok(match opcode {
+        Opcode::Eq | Opcode::Ne | Opcode::Lt | Opcode::Le | Opcode::Gt | Opcode::Ge => FheType::Bool,
+        Opcode::Select => operand_types[1],
+        _ => operand_types[0],
+    })
+}
+
+/// The plaintext `execute_scalar` parses `scalar` into when `opcode` applies
+/// it to a value of `lhs_type`: its type, and its value in little-endian two's
+/// complement at the width of that type, one byte for a boolean. Result
+/// handles are derived from it, see `fhe_handles::Scalar`.
+pub fn scalar_operand(opcode: Opcode, lhs_type: FheType, scalar: &Scalar) -> Result<(FheType, Vec<u8>), ComputeError> {
+    let fhe_type = match opcode {
+        Opcode::Shl | Opcode::Shr if lhs_type != FheType::Bool => FheType::Uint32,
+        _ => lhs_type,
+    };
+    let scalar = scalar.to_string();
+    let bytes = match fhe_type {
+        FheType::Bool => vec![parse::<bool>(&scalar, fhe_type)? as u8],
+        FheType::Uint8 => parse::<u8>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Uint16 => parse::<u16>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Uint32 => parse::<u32>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Uint64 => parse::<u64>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Uint128 => parse::<u128>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Int8 => parse::<i8>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Int16 => parse::<i16>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Int32 => parse::<i32>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Int64 => parse::<i64>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Int128 => parse::<i128>(&scalar, fhe_type)?.to_le_bytes().to_vec(),
+        FheType::Uint256 | FheType::Int256 => {
+            let value = parse_wide(&scalar, fhe_type.is_signed())
+                .ok_or_else(|| ComputeError::InvalidScalar { scalar: scalar.clone(), fhe_type })?;
+            let mut bytes = vec![0; 32];
+            value.to_little_endian(&mut bytes);
+            bytes
+        }
+    };
+    Ok((fhe_type, bytes))
+}
+
+/// Runs every node of a computation graph and returns their results in order.
+///
+/// `handles` holds the stored values the graph reads. Nodes are grouped by
+/// depth, and the nodes of a level only depend on earlier levels, so each
+/// level runs in parallel on the rayon pool. The server key must be set on
+/// the pool threads.
+pub fn execute_graph(nodes: &[GraphNode], handles: &HashMap<[u8; 32], TypedCiphertext>) -> Result<Vec<TypedCiphertext>, ComputeError> {
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+    let mut depths = Vec::with_capacity(nodes.len());
+    for (index, node) in nodes.iter().enumerate() {
+        let mut depth = 0;
+        for input in &node.inputs {
+            match *input {
+                GraphInput::Node(dependency) if dependency < index => depth = depth.max(depths[dependency] + 1),
+                GraphInput::Node(dependency) => return Err(ComputeError::InvalidGraph(
+                    format!("node {} reads node {}, only earlier nodes can be inputs", index, dependency)
+                )),
+                GraphInput::Handle(key) if !handles.contains_key(&key) => return Err(ComputeError::InvalidGraph(
+                    format!("node {} reads a handle with no stored value: {:?}", index, key)
+                )),
+                GraphInput::Handle(_) => {}
+            }
+        }
+        depths.push(depth);
+        if levels.len() <= depth {
+            levels.push(Vec::new());
+        }
+        levels[depth].push(index);
+    }
+
+    let mut results: Vec<Option<TypedCiphertext>> = vec![None; nodes.len()];
+    for level in levels {
+        let outputs = level.par_iter()
+            .map(|&index| {
+                let node = &nodes[index];
+                // Earlier levels are complete, so every node input is present
+                let operands: Vec<TypedCiphertext> = node.inputs.iter()
+                    .filter_map(|input| match *input {
+                        GraphInput::Handle(key) => handles.get(&key).cloned(),
+                        GraphInput::Node(dependency) => results[dependency].clone(),
+                    })
+                    .collect();
+                let result = match &node.scalar {
+                    Some(scalar) => execute_scalar(node.opcode, &operands, scalar),
+                    None => execute(node.opcode, &operands),
+                };
+                result.map_err(|error| ComputeError::Node { index, error: Box::new(error) })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (index, output) in level.into_iter().zip(outputs) {
+            results[index] = Some(output);
+        }
+    }
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Plaintext types a scalar operand can be parsed into.
+trait ParseScalar: Sized {
+    fn parse_scalar(scalar: &str) -> Option<Self>;
+}
+
+macro_rules! parse_scalar_via_from_str {
+    ($($clear:ty),*) => {
+        $(impl ParseScalar for $clear {
+            fn parse_scalar(scalar: &str) -> Option<Self> {
+                scalar.parse().ok()
+            }
+        })*
+    };
+}
+
+parse_scalar_via_from_str!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl ParseScalar for bool {
+    fn parse_scalar(scalar: &str) -> Option<Self> {
+        match scalar {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl ParseScalar for U256 {
+    fn parse_scalar(scalar: &str) -> Option<Self> {
+        let value = parse_wide(scalar, false)?;
+        Some(U256::from((value.low_u128(), (value >> 128).low_u128())))
+    }
+}
+
+impl ParseScalar for I256 {
+    fn parse_scalar(scalar: &str) -> Option<Self> {
+        let value = parse_wide(scalar, true)?;
+        Some(I256::from((value.low_u128(), (value >> 128).low_u128())))
+    }
+}
+
+// The 256-bit pattern of a decimal scalar, in two's complement if `signed`
+fn parse_wide(scalar: &str, signed: bool) -> Option<primitive_types::U256> {
+    let (negative, digits) = match scalar.strip_prefix('-') {
+        Some(digits) if signed => (true, digits),
+        _ => (false, scalar),
+    };
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let magnitude = primitive_types::U256::from_dec_str(digits).ok()?;
+    if !signed {
+        return Some(magnitude);
+    }
+    let limit = primitive_types::U256::one() << 255;
+    // Two's complement, the magnitude of the most negative value is one past the positive range
+    match negative {
+        false if magnitude < limit => Some(magnitude),
+        true if magnitude <= limit => Some((!magnitude).overflowing_add(primitive_types::U256::one()).0),
+        _ => None,
+    }
+}
+
+fn parse<T: ParseScalar>(scalar: &str, fhe_type: FheType) -> Result<T, ComputeError> {
+    T::parse_scalar(scalar).ok_or_else(|| ComputeError::InvalidScalar { scalar: scalar.to_string(), fhe_type })
+}
+
+// Each integer variant is listed with its plaintext type and the unsigned
+// variant of the same width, which is the type its shift amount must have
+macro_rules! integer_ops {
+    ($($variant:ident($clear:ty) => $shift:ident),* $(,)?) => {
+        fn unary(opcode: Opcode, value: &TypedCiphertext) -> Option<TypedCiphertext> {
+            use TypedCiphertext as T;
+            Some(match (opcode, value) {
+                (Opcode::Not, T::Bool(a)) => (!a).into(),
+                $(
+                    (Opcode::Not, T::$variant(a)) => (!a).into(),
+                    (Opcode::Neg, T::$variant(a)) => (-a).into(),
+                )*
+                _ => return None,
+            })
+        }
+
+        fn binary(opcode: Opcode, lhs: &TypedCiphertext, rhs: &TypedCiphertext) -> Option<TypedCiphertext> {
+            use TypedCiphertext as T;
+            if let Opcode::Shl | Opcode::Shr = opcode {
+                return match (lhs, rhs) {
+                    $(
+                        (T::$variant(a), T::$shift(b)) if opcode == Opcode::Shl => Some((a << b).into()),
+                        (T::$variant(a), T::$shift(b)) => Some((a >> b).into()),
+                    )*
+                    _ => None,
+                };
+            }
+            Some(match (lhs, rhs) {
+                (T::Bool(a), T::Bool(b)) => match opcode {
+                    Opcode::And => (a & b).into(),
+                    Opcode::Or => (a | b).into(),
+                    Opcode::Xor => (a ^ b).into(),
+                    Opcode::Eq => a.eq(b).into(),
+                    Opcode::Ne => a.ne(b).into(),
+                    _ => return None,
+                },
+                $(
+                    (T::$variant(a), T::$variant(b)) => match opcode {
+                        Opcode::Add => (a + b).into(),
+                        Opcode::Sub => (a - b).into(),
+                        Opcode::Mul => (a * b).into(),
+                        Opcode::Div => (a / b).into(),
+                        Opcode::Rem => (a % b).into(),
+                        Opcode::Min => a.min(b).into(),
+                        Opcode::Max => a.max(b).into(),
+                        Opcode::And => (a & b).into(),
+                        Opcode::Or => (a | b).into(),
+                        Opcode::Xor => (a ^ b).into(),
+                        Opcode::Eq => a.eq(b).into(),
+                        Opcode::Ne => a.ne(b).into(),
+                        Opcode::Lt => a.lt(b).into(),
+                        Opcode::Le => a.le(b).into(),
+                        Opcode::Gt => a.gt(b).into(),
+                        Opcode::Ge => a.ge(b).into(),
+                        _ => return None,
+                    },
+                )*
+                _ => return None,
+            })
+        }
+
+        fn binary_scalar(opcode: Opcode, lhs: &TypedCiphertext, scalar: &str) -> Result<Option<TypedCiphertext>, ComputeError> {
+            use TypedCiphertext as T;
+            let fhe_type = lhs.fhe_type();
+            Ok(Some(match lhs {
+                T::Bool(a) => {
+                    let b: bool = parse(scalar, fhe_type)?;
+                    match opcode {
+                        Opcode::And => (a & b).into(),
+                        Opcode::Or => (a | b).into(),
+                        Opcode::Xor => (a ^ b).into(),
+                        Opcode::Eq => a.eq(b).into(),
+                        Opcode::Ne => a.ne(b).into(),
+                        _ => return Ok(None),
+                    }
+                }
+                $(
+                    T::$variant(a) if matches!(opcode, Opcode::Shl | Opcode::Shr) => {
+                        let b: u32 = parse(scalar, FheType::Uint32)?;
+                        match opcode {
+                            Opcode::Shl => (a << b).into(),
+                            _ => (a >> b).into(),
+                        }
+                    }
+                    T::$variant(a) => {
+                        let b: $clear = parse(scalar, fhe_type)?;
+                        // tfhe panics on a plaintext divisor of zero, unlike an encrypted one
+                        if matches!(opcode, Opcode::Div | Opcode::Rem) && b == <$clear>::default() {
+                            return Err(ComputeError::InvalidScalar { scalar: scalar.to_string(), fhe_type });
+                        }
+                        match opcode {
+                            Opcode::Add => (a + b).into(),
+                            Opcode::Sub => (a - b).into(),
+                            Opcode::Mul => (a * b).into(),
+                            Opcode::Div => (a / b).into(),
+                            Opcode::Rem => (a % b).into(),
+                            Opcode::Min => a.min(b).into(),
+                            Opcode::Max => a.max(b).into(),
+                            Opcode::And => (a & b).into(),
+                            Opcode::Or => (a | b).into(),
+                            Opcode::Xor => (a ^ b).into(),
+                            Opcode::Eq => a.eq(b).into(),
+                            Opcode::Ne => a.ne(b).into(),
+                            Opcode::Lt => a.lt(b).into(),
+                            Opcode::Le => a.le(b).into(),
+                            Opcode::Gt => a.gt(b).into(),
+                            Opcode::Ge => a.ge(b).into(),
+                            _ => return Ok(None),
+                        }
+                    }
+                )*
+            }))
+        }
+
+        fn select(condition: &TypedCiphertext, if_true: &TypedCiphertext, if_false: &TypedCiphertext) -> Option<TypedCiphertext> {
+            use TypedCiphertext as T;
+            let T::Bool(condition) = condition else {
+                return None;
+            };
+            Some(match (if_true, if_false) {
+                (T::Bool(a), T::Bool(b)) => condition.select(a, b).into(),
+                $((T::$variant(a), T::$variant(b)) => condition.select(a, b).into(),)*
+                _ => return None,
+            })
+        }
+    };
+}
+
+integer_ops! {
+    Uint8(u8) => Uint8,
+    Uint16(u16) => Uint16,
+    Uint32(u32) => Uint32,
+    Uint64(u64) => Uint64,
+    Uint128(u128) => Uint128,
+    Uint256(U256) => Uint256,
+    Int8(i8) => Uint8,
+    Int16(i16) => Uint16,
+    Int32(i32) => Uint32,
+    Int64(i64) => Uint64,
+    Int128(i128) => Uint128,
+    Int256(I256) => Uint256,
+}
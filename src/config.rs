@@ -0,0 +1,463 @@
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use crate::keys;
+use crate::store::Backend;
+
+/// Read from the working directory when `--config` is not given
+pub const DEFAULT_CONFIG_PATH: &str = "fhe.toml";
+const DEFAULT_BIND: &str = "0.0.0.0:3000";
+// Decompressed 64-bit ciphertexts are a few hundred KiB each
+const DEFAULT_CACHE_CAPACITY_BYTES: usize = 512 * 1024 * 1024;
+// A transfer takes five jobs, four loads and the arithmetic
+const DEFAULT_COMPUTE_QUEUE_LIMIT: usize = 64;
+const DEFAULT_JOB_RETENTION_SECS: u64 = 3600;
+// Relayers retry events for a while after an outage, a day covers that
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 86400;
+// Where `decryptor --party <i>` listens by default
+const DEFAULT_DECRYPTORS: [&str; 3] = ["http://127.0.0.1:4001", "http://127.0.0.1:4002", "http://127.0.0.1:4003"];
+const DEFAULT_DECRYPTION_THRESHOLD: usize = 2;
+
+/// Overrides of the configuration file, environment variables apply unless
+/// the flag is given.
+#[derive(Parser, Debug)]
+#[command(about = "FHE coprocessor server")]
+pub struct Args {
+    /// TOML configuration file, defaults to fhe.toml if present
+    #[arg(long, env = "FHE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address the HTTP API listens on
+    #[arg(long, env = "FHE_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// Directory holding the server, public and CRS keys
+    #[arg(long, env = "FHE_KEYS_DIR")]
+    pub keys_dir: Option<PathBuf>,
+    /// Master key file the secret key files are encrypted under, see generate_keys
+    #[arg(long, env = "FHE_KMS_KEY_FILE")]
+    pub kms_key_file: Option<PathBuf>,
+    /// Storage backend: sqlite, memory or rocksdb
+    #[arg(long, env = "STORAGE_BACKEND")]
+    pub storage_backend: Option<Backend>,
+    /// Database file or directory of the storage backend
+    #[arg(long, env = "STORAGE_PATH")]
+    pub storage_path: Option<String>,
+    /// Memory bound of the decompressed ciphertext cache
+    #[arg(long, env = "CACHE_CAPACITY_BYTES")]
+    pub cache_capacity_bytes: Option<usize>,
+    /// Threads of the compute pool, 0 for one per core
+    #[arg(long, env = "COMPUTE_WORKERS")]
+    pub compute_workers: Option<usize>,
+    /// Compute jobs queued or running before requests are refused with 503
+    #[arg(long, env = "COMPUTE_QUEUE_LIMIT")]
+    pub compute_queue_limit: Option<usize>,
+    /// URL every finished job is posted to
+    #[arg(long, env = "JOB_CALLBACK_URL")]
+    pub job_callback_url: Option<String>,
+    /// Decryptor URLs, comma separated, the i-th holding key share i
+    #[arg(long, env = "DECRYPTOR_URLS", value_delimiter = ',')]
+    pub decryptor_urls: Option<Vec<String>>,
+    /// How many decryptors take part in each decryption
+    #[arg(long, env = "DECRYPTION_THRESHOLD")]
+    pub decryption_threshold: Option<usize>,
+    /// Refuse requests whose new keys come without the origin they were derived from
+    #[arg(long, env = "REQUIRE_HANDLE_ORIGIN")]
+    pub require_handle_origin: Option<bool>,
+    /// Accept plaintext values on /post, for deposits whose amount is public on chain
+    #[arg(long, env = "ALLOW_PLAINTEXT_DEPOSITS")]
+    pub allow_plaintext_deposits: Option<bool>,
+    /// Print the resolved configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, error: String },
+    Parse { path: PathBuf, error: String },
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => write!(f, "cannot read config {}: {}", path.display(), error),
+            ConfigError::Parse { path, error } => write!(f, "invalid config {}: {}", path.display(), error),
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {}", e),
+        }
+    }
+}
+
+/// Server settings. From lowest to highest precedence they come from the
+/// defaults, the TOML file, environment variables and command line flags.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub cache: CacheConfig,
+    pub compute: ComputeConfig,
+    pub jobs: JobsConfig,
+    pub handles: HandlesConfig,
+    pub deposits: DepositsConfig,
+    pub decryption: DecryptionConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    pub keys_dir: PathBuf,
+    /// Unset if the key files are plaintext or encrypted under FHE_KEY_PASSPHRASE.
+    /// Read by `rotate_keys` and `db_test`, the server loads no secret key file
+    pub kms_key_file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    /// The backend's default path if unset
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub capacity_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComputeConfig {
+    /// One per core if 0
+    pub workers: usize,
+    pub queue_limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// No callbacks are made if unset, jobs are only polled
+    pub callback_url: Option<String>,
+    /// How long finished jobs can still be polled
+    pub retention_secs: u64,
+    /// How long after its first submission an idempotency key returns the
+    /// stored outcome
+    pub idempotency_ttl_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandlesConfig {
+    /// Whether requests writing new keys are refused unless the keys can be
+    /// checked against their derivation
+    pub require_origin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DepositsConfig {
+    /// Whether `/post` accepts plaintext values. Off by default, values are
+    /// encrypted client-side and sent to `/post_ciphertext`. Only a relayer
+    /// posting deposits, whose amounts are public on chain anyway, needs it
+    pub allow_plaintext: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecryptionConfig {
+    /// Base URL of every decryptor, in party order
+    pub decryptors: Vec<String>,
+    pub threshold: usize,
+}
+
+impl Default for HandlesConfig {
+    fn default() -> Self {
+        Self { require_origin: true }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: DEFAULT_BIND.parse().expect("default bind address is valid"),
+            keys_dir: PathBuf::from(keys::DEFAULT_KEYS_DIR),
+            kms_key_file: None,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self { backend: Backend::Sqlite, path: None }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { capacity_bytes: DEFAULT_CACHE_CAPACITY_BYTES }
+    }
+}
+
+impl Default for ComputeConfig {
+    fn default() -> Self {
+        Self { workers: 0, queue_limit: DEFAULT_COMPUTE_QUEUE_LIMIT }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            callback_url: None,
+            retention_secs: DEFAULT_JOB_RETENTION_SECS,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+        }
+    }
+}
+
+impl Default for DecryptionConfig {
+    fn default() -> Self {
+        Self {
+            decryptors: DEFAULT_DECRYPTORS.iter().map(|url| url.to_string()).collect(),
+            threshold: DEFAULT_DECRYPTION_THRESHOLD,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file named by `args`, or `fhe.toml` if it
+    /// exists, and applies the overrides in `args` on top.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        if let Some(bind) = args.bind {
+            config.server.bind = bind;
+        }
+        if let Some(keys_dir) = &args.keys_dir {
+            config.server.keys_dir = keys_dir.clone();
+        }
+        if let Some(kms_key_file) = &args.kms_key_file {
+            config.server.kms_key_file = Some(kms_key_file.clone());
+        }
+        if let Some(backend) = args.storage_backend {
+            config.storage.backend = backend;
+        }
+        if let Some(path) = &args.storage_path {
+            config.storage.path = Some(path.clone());
+        }
+        if let Some(capacity) = args.cache_capacity_bytes {
+            config.cache.capacity_bytes = capacity;
+        }
+        if let Some(workers) = args.compute_workers {
+            config.compute.workers = workers;
+        }
+        if let Some(limit) = args.compute_queue_limit {
+            config.compute.queue_limit = limit;
+        }
+        if let Some(url) = &args.job_callback_url {
+            config.jobs.callback_url = Some(url.clone());
+        }
+        if let Some(urls) = &args.decryptor_urls {
+            config.decryption.decryptors = urls.clone();
+        }
+        if let Some(threshold) = args.decryption_threshold {
+            config.decryption.threshold = threshold;
+        }
+        if let Some(require) = args.require_handle_origin {
+            config.handles.require_origin = require;
+        }
+        if let Some(allow) = args.allow_plaintext_deposits {
+            config.deposits.allow_plaintext = allow;
+        }
+        // Resolved here so --print-config shows where the data actually goes
+        if config.storage.path.is_none() {
+            config.storage.path = Some(config.storage.backend.default_path().to_string());
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read { path: path.to_path_buf(), error: e.to_string() })?;
+        toml::from_str(&contents)
+            .map_err(|e| ConfigError::Parse { path: path.to_path_buf(), error: e.to_string() })
+    }
+
+    /// Checks what can be checked before opening anything, so a bad setting
+    /// fails at startup rather than on the first request.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let keys_dir = &self.server.keys_dir;
+        for file in keys::SERVER_FILES {
+            if !keys_dir.join(file).exists() {
+                return Err(ConfigError::Invalid(format!(
+                    "keys directory {} has no {}, run generate_keys first", keys_dir.display(), file
+                )));
+            }
+        }
+        if let Some(path) = self.server.kms_key_file.as_ref().filter(|path| !path.exists()) {
+            return Err(ConfigError::Invalid(format!("KMS key file {} does not exist", path.display())));
+        }
+        if self.storage.path.as_deref().is_some_and(str::is_empty) {
+            return Err(ConfigError::Invalid("storage path is empty".to_string()));
+        }
+        if self.compute.queue_limit == 0 {
+            return Err(ConfigError::Invalid("compute queue limit must be at least 1".to_string()));
+        }
+        if let Some(url) = self.jobs.callback_url.as_ref()
+            .filter(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return Err(ConfigError::Invalid(format!("job callback URL {:?} must start with http:// or https://", url)));
+        }
+        let decryptors = self.decryption.decryptors.len();
+        if self.decryption.threshold == 0 || self.decryption.threshold > decryptors {
+            return Err(ConfigError::Invalid(format!(
+                "decryption threshold {} of {} decryptors", self.decryption.threshold, decryptors
+            )));
+        }
+        if let Some(url) = self.decryption.decryptors.iter()
+            .find(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            return Err(ConfigError::Invalid(format!("decryptor URL {:?} must start with http:// or https://", url)));
+        }
+        if self.storage.backend == Backend::RocksDb && !cfg!(feature = "rocksdb") {
+            return Err(ConfigError::Invalid("the rocksdb backend needs a build with the rocksdb feature".to_string()));
+        }
+        Ok(())
+    }
+
+    /// How the secret key files are decrypted, from the KMS key file or
+    /// the passphrase in the environment. The server holds no secret key
+    /// file, `rotate_keys` and `db_test` read them with its configuration.
+    #[allow(dead_code)]
+    pub fn key_protection(&self) -> Result<keys::KeyProtection, String> {
+        let kms = self.server.kms_key_file.as_deref().map(keys::FileKms::open).transpose()?;
+        keys::KeyProtection::resolve(kms)
+    }
+
+    pub fn storage_path(&self) -> &str {
+        self.storage.path.as_deref().unwrap_or_else(|| self.storage.backend.default_path())
+    }
+
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        toml::to_string_pretty(self).map_err(|e| ConfigError::Invalid(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Built directly rather than parsed, so the environment cannot override anything
+    fn args(config: Option<PathBuf>) -> Args {
+        Args {
+            config,
+            bind: None,
+            keys_dir: None,
+            kms_key_file: None,
+            storage_backend: None,
+            storage_path: None,
+            cache_capacity_bytes: None,
+            compute_workers: None,
+            compute_queue_limit: None,
+            job_callback_url: None,
+            decryptor_urls: None,
+            decryption_threshold: None,
+            require_handle_origin: None,
+            allow_plaintext_deposits: None,
+            print_config: false,
+        }
+    }
+
+    // A directory of its own for each test, removed beforehand
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fhe-config-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = test_dir(name).join("fhe.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn file_settings_override_the_defaults() {
+        let path = write_config("file", "[storage]\nbackend = \"rocksdb\"\n\n[compute]\nqueue_limit = 8\n");
+        let config = Config::load(&args(Some(path))).unwrap();
+        assert_eq!(config.compute.queue_limit, 8);
+        assert_eq!(config.compute.workers, 0);
+        assert_eq!(config.cache.capacity_bytes, DEFAULT_CACHE_CAPACITY_BYTES);
+        assert!(config.handles.require_origin);
+        assert!(!config.deposits.allow_plaintext);
+        // The path follows the backend when the file sets none
+        assert_eq!(config.storage.path.as_deref(), Some(crate::store::DEFAULT_ROCKSDB_PATH));
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = write_config("flags", concat!(
+            "[server]\nbind = \"127.0.0.1:4000\"\n\n",
+            "[storage]\nbackend = \"rocksdb\"\npath = \"file.db\"\n\n",
+            "[handles]\nrequire_origin = true\n\n",
+            "[deposits]\nallow_plaintext = true\n",
+        ));
+        let config = Config::load(&Args {
+            bind: Some("127.0.0.1:5000".parse().unwrap()),
+            storage_backend: Some(Backend::Memory),
+            decryptor_urls: Some(vec!["http://a".to_string(), "http://b".to_string()]),
+            require_handle_origin: Some(false),
+            ..args(Some(path))
+        }).unwrap();
+        assert_eq!(config.server.bind, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(config.storage.backend, Backend::Memory);
+        // Unflagged settings keep the file's value
+        assert_eq!(config.storage.path.as_deref(), Some("file.db"));
+        assert_eq!(config.decryption.decryptors, vec!["http://a", "http://b"]);
+        assert!(!config.handles.require_origin);
+        assert!(config.deposits.allow_plaintext);
+    }
+
+    #[test]
+    fn refuses_unknown_and_missing_files() {
+        let path = write_config("unknown", "[storage]\nbackend = \"sqlite\"\nsize = 3\n");
+        assert!(matches!(Config::load(&args(Some(path))), Err(ConfigError::Parse { .. })));
+        let missing = test_dir("missing").join("fhe.toml");
+        assert!(matches!(Config::load(&args(Some(missing))), Err(ConfigError::Read { .. })));
+    }
+
+    #[test]
+    fn validates_settings_against_each_other() {
+        let keys_dir = test_dir("validate");
+        for file in keys::SERVER_FILES {
+            fs::write(keys_dir.join(file), b"").unwrap();
+        }
+        let valid = || Config {
+            server: ServerConfig { keys_dir: keys_dir.clone(), ..Default::default() },
+            ..Default::default()
+        };
+        assert!(valid().validate().is_ok());
+
+        let mut config = valid();
+        config.decryption.threshold = 4;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let mut config = valid();
+        config.compute.queue_limit = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let mut config = valid();
+        config.jobs.callback_url = Some("ftp://callback".to_string());
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        fs::remove_file(keys_dir.join(keys::SERVER_FILES[0])).unwrap();
+        assert!(matches!(valid().validate(), Err(ConfigError::Invalid(_))));
+    }
+}
@@ -1,61 +1,68 @@
-use tokio_rusqlite::Connection;
-use std::path::Path;
+use clap::Parser;
 use tfhe::prelude::*;
-use tfhe::{CompressedCiphertextList, ClientKey, FheUint64, set_server_key};
+use tfhe::{CompressedCiphertextList, FheUint64, set_server_key};
+#[allow(dead_code)]
 mod keys;
+#[allow(dead_code)]
+mod store;
+#[allow(dead_code)]
+mod config;
 
-const DB_PATH: &str = "data/tfhe.db";
+use config::{Args, Config};
+use store::CiphertextStore;
+
+// Reads the same configuration as the server
+fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
+    Ok(Config::load(&Args::parse()).map_err(|e| e.to_string())?)
+}
+
+async fn open_store(config: &Config) -> Result<std::sync::Arc<dyn CiphertextStore>, Box<dyn std::error::Error>> {
+    Ok(store::open(config.storage.backend, config.storage_path()).await.map_err(|e| e.to_string())?)
+}
 
 pub async fn test_first_value_zero() -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting database test...");
-    let client_key = keys::load_client_key()?;
-    let server_key = keys::load_server_key()?;
-    
+    let config = load_config()?;
+    let client_key = keys::load_client_key(&config.server.keys_dir, &config.key_protection()?)?;
+    let server_key = keys::load_server_key(&config.server.keys_dir)?;
+
     // Set the server key before any operations
     set_server_key(server_key);
-    
-    let conn = Connection::open(DB_PATH).await?;
-    let blob = conn.call(|conn| {
-        let mut stmt = conn.prepare("SELECT ciphertext FROM computations LIMIT 1")?;
-        let blob: Vec<u8> = stmt.query_row([], |row| row.get(0))?;
-        Ok(blob)
-    }).await?;
+
+    // Rows scan in key order, so the first one is the zero value at the all-zero key
+    let store = open_store(&config).await?;
+    let rows = store.scan(None, 1).await.map_err(|e| e.to_string())?;
+    let blob = &rows.first().ok_or("store is empty")?.ciphertext;
     println!("Retrieved blob of size: {} bytes", blob.len());
-    
-    let compressed: CompressedCiphertextList = bincode::deserialize(&blob)?;
+
+    let compressed: CompressedCiphertextList = bincode::deserialize(blob)?;
     let value: FheUint64 = compressed.get(0)?.unwrap();
     let decrypted: u64 = value.decrypt(&client_key);
-    
+
     println!("Decrypted value: {}", decrypted);
     assert_eq!(decrypted, 0);
     Ok(())
 }
 
-pub async fn test_last_values() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting last values test...");
-    let client_key = keys::load_client_key()?;
-    let server_key = keys::load_server_key()?;
-    
+pub async fn test_scan_values() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Starting scan values test...");
+    let config = load_config()?;
+    let client_key = keys::load_client_key(&config.server.keys_dir, &config.key_protection()?)?;
+    let server_key = keys::load_server_key(&config.server.keys_dir)?;
+
     // Set the server key before any operations
     set_server_key(server_key);
-    
-    let conn = Connection::open(DB_PATH).await?;
-    
-    let rows = conn.call(|conn| {
-        let mut stmt = conn.prepare("SELECT key, ciphertext FROM computations ORDER BY ROWID DESC LIMIT 3")?;
-        let rows: Vec<(Vec<u8>, Vec<u8>)> = stmt.query_map([], |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        })?.collect::<Result<Vec<_>, _>>()?;
-        Ok(rows)
-    }).await?;
+
+    let store = open_store(&config).await?;
+    let rows = store.scan(None, 3).await.map_err(|e| e.to_string())?;
 
     println!("Retrieved {} rows", rows.len());
-    
-    for (i, (key, blob)) in rows.iter().enumerate() {
-        let compressed: CompressedCiphertextList = bincode::deserialize(&blob)?;
+
+    for (i, row) in rows.iter().enumerate() {
+        let compressed: CompressedCiphertextList = bincode::deserialize(&row.ciphertext)?;
         let value: FheUint64 = compressed.get(0)?.unwrap();
         let decrypted: u64 = value.decrypt(&client_key);
-        println!("Row {}: Key = {:?}, Value = {}", i + 1, key, decrypted);
+        println!("Row {}: Key = {:?}, Value = {}", i + 1, row.key, decrypted);
     }
 
     Ok(())
@@ -63,6 +70,6 @@ pub async fn test_last_values() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    test_first_value_zero().await;
-    test_last_values().await
-}
\ No newline at end of file
+    test_first_value_zero().await?;
+    test_scan_values().await
+}
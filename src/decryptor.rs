@@ -0,0 +1,102 @@
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::post, Router,
+};
+use clap::Parser;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+#[allow(dead_code)]
+mod ciphertext;
+#[allow(dead_code)]
+mod keys;
+#[allow(dead_code)]
+mod threshold;
+#[allow(dead_code)]
+mod types;
+
+use keys::{FileKms, KeyProtection, KeyShare};
+use threshold::{PartialRequest, PartialResponse};
+
+// Masks of a 256-bit value, 128 blocks of 2048 coefficients, are 2 MiB
+const MAX_REQUEST_BYTES: usize = 8 * 1024 * 1024;
+// Party i listens on this port plus i unless --bind is given
+const BASE_PORT: u16 = 4001;
+
+#[derive(Parser, Debug)]
+#[command(about = "Holds one threshold key share and answers the server's partial decryption requests")]
+struct Args {
+    /// Index of the share to hold, the position of this decryptor's URL in the server's list
+    #[arg(long, env = "DECRYPTOR_PARTY")]
+    party: usize,
+    /// Address to listen on, 127.0.0.1 on port 4001 plus the party index by default
+    #[arg(long, env = "DECRYPTOR_BIND")]
+    bind: Option<SocketAddr>,
+    /// Directory holding `shares/share_<party>.bin` and the key info
+    #[arg(long, env = "FHE_KEYS_DIR", default_value = keys::DEFAULT_KEYS_DIR)]
+    keys_dir: PathBuf,
+    /// Master key file the share is encrypted under, see generate_keys
+    #[arg(long, env = "FHE_KMS_KEY_FILE")]
+    kms_key_file: Option<PathBuf>,
+}
+
+struct Decryptor {
+    share: KeyShare,
+    key_id: String,
+    token_hash: [u8; 32],
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let token = std::env::var(threshold::TOKEN_ENV).ok()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| format!("Set {} to the secret the server authenticates with", threshold::TOKEN_ENV))?;
+    let kms = args.kms_key_file.as_deref().map(FileKms::open).transpose()?;
+    let protection = KeyProtection::resolve(kms)?;
+    // During a rotation the server decrypts under the next key set, like the KeyRing
+    let dir = match keys::has_next(&args.keys_dir) {
+        true => args.keys_dir.join(keys::NEXT_DIR),
+        false => args.keys_dir.clone(),
+    };
+    let info = keys::load_key_info(&dir)?;
+    let share = keys::load_key_share(&dir, args.party, &protection)?;
+    println!("Holding share {} of {} (threshold {}) of key set {}", share.party, share.parties, share.threshold, info);
+
+    let state = Arc::new(Decryptor {
+        share,
+        key_id: info.key_id,
+        token_hash: Sha256::digest(token.as_bytes()).into(),
+    });
+    let app = Router::new()
+        .route("/partial_decrypt", post(partial_decrypt))
+        .layer(DefaultBodyLimit::max(MAX_REQUEST_BYTES))
+        .with_state(state);
+    let bind = args.bind.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], BASE_PORT + args.party as u16)));
+    println!("Decryptor {} listening on http://{}", args.party, bind);
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn partial_decrypt(State(decryptor): State<Arc<Decryptor>>, headers: HeaderMap, body: Bytes) -> Result<Vec<u8>, StatusCode> {
+    // Partials of chosen masks reveal the share, only the server may ask
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    // Comparing digests leaks nothing about the token through timing
+    if <[u8; 32]>::from(Sha256::digest(token.as_bytes())) != decryptor.token_hash {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let request: PartialRequest = bincode::deserialize(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let response = tokio::task::spawn_blocking(move || PartialResponse {
+        party: decryptor.share.party,
+        key_id: decryptor.key_id.clone(),
+        partials: decryptor.share.partial_decrypt(&request.masks, &request.sub_shares),
+    }).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    bincode::serialize(&response).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
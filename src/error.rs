@@ -0,0 +1,208 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use crate::auth::AuthError;
+use crate::ciphertext::CiphertextError;
+use crate::compute::ComputeError;
+use crate::executor::ExecutorError;
+use crate::ingest::IngestError;
+use crate::store::StoreError;
+use crate::threshold::DecryptionError;
+use crate::types::ErrorResponse;
+
+/// Why a request failed, returned by every handler.
+///
+/// Rendered as an `ErrorResponse` JSON body whose `code` names the variant,
+/// so clients and the relayer can branch on it instead of on the message.
+#[derive(Debug)]
+pub enum ApiError {
+    /// A key the request reads has no stored value
+    NotFound(String),
+    /// A stored value has a different type than the request operates on
+    TypeMismatch(String),
+    /// A stored value was encrypted under another key set than the server's
+    KeyMismatch(String),
+    /// The request asks for something invalid, e.g. an undefined operation
+    InvalidRequest(String),
+    /// A client ciphertext's proof of plaintext knowledge did not verify
+    InvalidProof,
+    /// A stored value could not be deserialized or decompressed
+    Deserialization(String),
+    /// A result could not be compressed for storage
+    Compression(String),
+    Storage(String),
+    /// The owner's authorization was missing, invalid, expired or replayed
+    Auth(AuthError),
+    /// A value the request read was written concurrently, retrying can succeed
+    Conflict(String),
+    /// The key the request stores a new value at already has one
+    Exists(String),
+    /// Too few decryptors answered, retrying later can succeed
+    DecryptionUnavailable(String),
+    /// The compute queue is full, retrying later can succeed
+    Busy(String),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::TypeMismatch(_) | ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidProof | ApiError::KeyMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Auth(AuthError::NoOwner | AuthError::OwnerMismatch) => StatusCode::FORBIDDEN,
+            ApiError::Auth(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Conflict(_) | ApiError::Exists(_) => StatusCode::CONFLICT,
+            ApiError::DecryptionUnavailable(_) | ApiError::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Deserialization(_)
+            | ApiError::Compression(_)
+            | ApiError::Storage(_)
+            | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether the failure came from the server's state at the time rather
+    /// than the request, so the same request can succeed later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ApiError::Conflict(_) | ApiError::Busy(_) | ApiError::DecryptionUnavailable(_))
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound(_) => "not_found",
+            ApiError::TypeMismatch(_) => "type_mismatch",
+            ApiError::KeyMismatch(_) => "key_mismatch",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidProof => "invalid_proof",
+            ApiError::Deserialization(_) => "deserialization_error",
+            ApiError::Compression(_) => "compression_error",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Auth(AuthError::NoOwner) => "no_owner",
+            ApiError::Auth(AuthError::InvalidSignature) => "invalid_signature",
+            ApiError::Auth(AuthError::Expired) => "authorization_expired",
+            ApiError::Auth(AuthError::Replayed) => "nonce_replayed",
+            ApiError::Auth(AuthError::OwnerMismatch) => "owner_mismatch",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Exists(_) => "already_exists",
+            ApiError::DecryptionUnavailable(_) => "decryption_unavailable",
+            ApiError::Busy(_) => "server_busy",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Auth(e) => write!(f, "{}", e),
+            ApiError::InvalidProof => write!(f, "proof verification failed"),
+            ApiError::NotFound(e)
+            | ApiError::TypeMismatch(e)
+            | ApiError::KeyMismatch(e)
+            | ApiError::InvalidRequest(e)
+            | ApiError::Deserialization(e)
+            | ApiError::Compression(e)
+            | ApiError::Storage(e)
+            | ApiError::Conflict(e)
+            | ApiError::Exists(e)
+            | ApiError::DecryptionUnavailable(e)
+            | ApiError::Busy(e)
+            | ApiError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        println!("Request failed with {} {}: {}", status.as_u16(), self.code(), self);
+        let body = ErrorResponse { code: self.code().to_string(), message: self.to_string() };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<CiphertextError> for ApiError {
+    fn from(e: CiphertextError) -> Self {
+        match e {
+            CiphertextError::TypeMismatch { .. } => ApiError::TypeMismatch(e.to_string()),
+            CiphertextError::KeyMismatch { .. } => ApiError::KeyMismatch(e.to_string()),
+            CiphertextError::UnsupportedType(_) | CiphertextError::Deserialization(_) => ApiError::Deserialization(e.to_string()),
+            CiphertextError::Compression(_) => ApiError::Compression(e.to_string()),
+        }
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Conflict(_) => ApiError::Conflict(e.to_string()),
+            StoreError::NotFound(_) => ApiError::NotFound(e.to_string()),
+            StoreError::Exists(_) => ApiError::Exists(e.to_string()),
+            StoreError::Storage(_) => ApiError::Storage(e.to_string()),
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        ApiError::Auth(e)
+    }
+}
+
+impl From<ComputeError> for ApiError {
+    fn from(e: ComputeError) -> Self {
+        ApiError::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<IngestError> for ApiError {
+    fn from(e: IngestError) -> Self {
+        match e {
+            IngestError::InvalidProof => ApiError::InvalidProof,
+            _ => ApiError::InvalidRequest(e.to_string()),
+        }
+    }
+}
+
+impl From<DecryptionError> for ApiError {
+    fn from(e: DecryptionError) -> Self {
+        ApiError::DecryptionUnavailable(e.to_string())
+    }
+}
+
+impl From<ExecutorError> for ApiError {
+    fn from(e: ExecutorError) -> Self {
+        match e {
+            ExecutorError::Saturated { .. } => ApiError::Busy(e.to_string()),
+            ExecutorError::Panicked => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+/// An error recorded with a job, answered again as it was the first time.
+impl From<ErrorResponse> for ApiError {
+    fn from(e: ErrorResponse) -> Self {
+        match e.code.as_str() {
+            "not_found" => ApiError::NotFound(e.message),
+            "type_mismatch" => ApiError::TypeMismatch(e.message),
+            "key_mismatch" => ApiError::KeyMismatch(e.message),
+            "invalid_request" => ApiError::InvalidRequest(e.message),
+            "invalid_proof" => ApiError::InvalidProof,
+            "deserialization_error" => ApiError::Deserialization(e.message),
+            "compression_error" => ApiError::Compression(e.message),
+            "storage_error" => ApiError::Storage(e.message),
+            "no_owner" => ApiError::Auth(AuthError::NoOwner),
+            "invalid_signature" => ApiError::Auth(AuthError::InvalidSignature),
+            "authorization_expired" => ApiError::Auth(AuthError::Expired),
+            "nonce_replayed" => ApiError::Auth(AuthError::Replayed),
+            "owner_mismatch" => ApiError::Auth(AuthError::OwnerMismatch),
+            "conflict" => ApiError::Conflict(e.message),
+            "already_exists" => ApiError::Exists(e.message),
+            "decryption_unavailable" => ApiError::DecryptionUnavailable(e.message),
+            "server_busy" => ApiError::Busy(e.message),
+            _ => ApiError::Internal(e.message),
+        }
+    }
+}
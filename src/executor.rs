@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tfhe::ServerKey;
+use tokio::sync::oneshot;
+use crate::keyring::install_server_key;
+use crate::types::ComputeStats;
+
+#[derive(Debug)]
+pub enum ExecutorError {
+    /// `limit` jobs are already queued or running, retrying later can succeed
+    Saturated { limit: usize },
+    /// The job panicked before returning a result
+    Panicked,
+}
+
+impl std::fmt::Display for ExecutorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecutorError::Saturated { limit } =>
+                write!(f, "compute queue is full with {} jobs, retry later", limit),
+            ExecutorError::Panicked => write!(f, "compute job panicked"),
+        }
+    }
+}
+
+// Frees its job's place in the queue when dropped, also if the job panics
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Runs FHE computation on a dedicated rayon pool, so homomorphic operations
+/// never block the async runtime. Handlers submit closures with `run` and
+/// await their results.
+///
+/// At most `queue_limit` jobs are queued or running at once. Beyond that
+/// `run` fails with `Saturated` instead of letting requests pile up, and the
+/// handler answers 503. Parallel iterators inside a job run on the same pool.
+pub struct ComputeExecutor {
+    pool: rayon::ThreadPool,
+    queue_limit: usize,
+    pending: Arc<AtomicUsize>,
+    rejected: AtomicU64,
+}
+
+impl ComputeExecutor {
+    /// Starts `workers` threads, one per core if 0, each installing
+    /// `server_key` as it starts.
+    pub fn new(workers: usize, queue_limit: usize, server_key: Arc<ServerKey>) -> Result<Self, String> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(workers)
+            .thread_name(|index| format!("compute-{}", index))
+            .start_handler(move |_| install_server_key(&server_key))
+            // The job's caller sees `Panicked`, the pool keeps running
+            .panic_handler(|_| println!("Compute job panicked"))
+            .build()
+            .map_err(|e| format!("Failed to start compute pool: {}", e))?;
+        Ok(Self { pool, queue_limit, pending: Arc::new(AtomicUsize::new(0)), rejected: AtomicU64::new(0) })
+    }
+
+    /// Runs `job` on the compute pool and returns its result.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, ExecutorError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.pending
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                (pending < self.queue_limit).then_some(pending + 1)
+            })
+            .map_err(|_| {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                ExecutorError::Saturated { limit: self.queue_limit }
+            })?;
+        let slot = Slot(self.pending.clone());
+        let (reply, result) = oneshot::channel();
+        self.pool.spawn(move || {
+            let _slot = slot;
+            // The caller may have gone away, the result is dropped then
+            let _ = reply.send(job());
+        });
+        result.await.map_err(|_| ExecutorError::Panicked)
+    }
+
+    pub fn stats(&self) -> ComputeStats {
+        ComputeStats {
+            workers: self.pool.current_num_threads(),
+            pending: self.pending.load(Ordering::SeqCst),
+            queue_limit: self.queue_limit,
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,164 +1,581 @@
 use axum::{
-    extract::State,
+    extract::{Path, State},
     Json,
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
+use axum::body::Bytes;
 use tfhe::{
     FheUint64,
-    CompressedCiphertextListBuilder,
-    set_server_key,
+    CompactCiphertextList,
 };
 use tfhe::prelude::*;
 use tokio::try_join;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::future::Future;
+use serde::{de::DeserializeOwned, Serialize};
 use crate::{
     AppState,
     KeyAccess,
-    operations::{self, update_ciphertext, insert_ciphertext},
+    auth::{self, AuthError},
+    ciphertext::{CiphertextError, TypedCiphertext},
+    compute,
+    sealing,
+    error::ApiError,
+    ingest,
+    keys::KeyInfo,
+    operations::{self, CiphertextRow, Loader, WriteBatch},
+    store::{CiphertextStore, StoreError},
     types::{
         Request,
+        EncryptedRequest,
         Transfer,
+        Operation,
+        OperationResponse,
+        Graph,
+        GraphInput,
+        GraphOutput,
+        HandleOrigin,
+        FheType,
+        GraphResponse,
         Decrypt,
+        Reencrypt,
+        ReencryptResponse,
         Withdraw,
         ViewResponse,
-        zero_key,
+        CacheStats,
+        ComputeStats,
+        Job,
+        ZERO_KEY,
     },
 };
 
+// Header a client names a mutating request with, resubmitting it returns the
+// stored outcome instead of running the request again
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
 
-pub async fn handle_post(State(state): State<AppState>, Json(payload): Json<Request>) -> Result<StatusCode, StatusCode> {
-    println!("Received value: {}, key: {:?}", payload.value, payload.key);
-    let client_key = state.get_client_key();
-    let server_key = state.get_server_key();
-    set_server_key((*server_key).clone());
-    let value = FheUint64::encrypt(payload.value, &*client_key);
-    println!("Encrypted value type: {:?}", std::any::type_name_of_val(&value));
-    let compressed = CompressedCiphertextListBuilder::new()
-        .push(value)
-        .build()
-        .map_err(|e| {
-            println!("Compression error: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-    });
-    println!("Serializing compressed value...");
-    let serialized_data = bincode::serialize(&compressed.unwrap())
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    insert_ciphertext(payload.key, serialized_data)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    println!("hit the end of post");
+pub async fn handle_post(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Request>) -> Result<StatusCode, ApiError> {
+    once(state, "post", &headers, payload, post).await?;
     Ok(StatusCode::OK)
 }
 
-pub async fn handle_transfer(State(state): State<AppState>, Json(payload): Json<Transfer>) -> Result<StatusCode, StatusCode> {
-    println!("=== TRANSFER REQUEST RECEIVED ===");
+async fn post(state: AppState, payload: Request) -> Result<(), ApiError> {
+    // A plaintext sent over HTTP defeats client-side encryption, except for
+    // deposits whose amount is public on chain anyway
+    if !state.allows_plaintext_deposits() {
+        return Err(ApiError::InvalidRequest(
+            "plaintext values are not accepted, encrypt client-side and use /post_ciphertext".to_string()
+        ));
+    }
+    if let Some(origin) = handle_origin(&state, payload.origin)? {
+        let value = payload.value.to_le_bytes();
+        let scalar = fhe_handles::Scalar { fhe_type: FheType::Uint64.tag(), bytes: &value };
+        check_handle(&payload.key, fhe_handles::derive(&origin, fhe_handles::opcode::ENCRYPT, &[], Some(scalar), FheType::Uint64.tag()))?;
+    }
+    encrypt_and_store(&state, payload.key, payload.value, payload.owner).await
+}
+
+/// Stores the zero transfers and withdrawals start from at `ZERO_KEY`, unless
+/// it is there already. No program derives it, so it is written without an origin.
+pub async fn seed_zero(state: &AppState) -> Result<(), ApiError> {
+    if !state.get_store().get_info(vec![ZERO_KEY]).await?.is_empty() {
+        return Ok(());
+    }
+    encrypt_and_store(state, ZERO_KEY, 0, None).await
+}
+
+async fn encrypt_and_store(state: &AppState, key: [u8; 32], value: u64, owner: Option<[u8; 32]>) -> Result<(), ApiError> {
+    let public_key = state.get_public_key();
     let server_key = state.get_server_key();
-    set_server_key((*server_key).clone());
-    println!("handle_transfer hit!!!!!!!!");
-
-    println!("Attempting to fetch sender ciphertext...");
-    println!("Sender key: {:?}", payload.sender_key);
-    println!("Reciver key: {:?}", payload.recipient_key);
-    println!("transfer key: {:?}", payload.transfer_value);
-    println!("Fetching all required values...");
-    let (sender_value, recipient_value, transfer_value, zero_value) = try_join!(
-        operations::get_prepared_ciphertext(payload.sender_key),
-        operations::get_prepared_ciphertext(payload.recipient_key),
-        operations::get_prepared_ciphertext(payload.transfer_value),
-        operations::get_prepared_ciphertext(zero_key)
-    ).map_err(|e| {
-        println!("Error fetching values: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    println!("Successfully fetched all values");
-
-    println!("about to start operations");
-    let condition = sender_value.ge(&transfer_value);
-    let real_amount = condition.if_then_else(&transfer_value, &zero_value);
-    let new_sender_value = &sender_value - &real_amount;
-    let new_recipient_value = &recipient_value + &real_amount;
-    println!("ending operations");
-
-    let compressed_sender = CompressedCiphertextListBuilder::new()
-        .push(new_sender_value.clone())
-        .build()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        
-    let serialized_sender = bincode::serialize(&compressed_sender)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    update_ciphertext(payload.sender_key, serialized_sender.clone()).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    println!("Successfully updated sender key: {:?}", payload.sender_key);
-
-    let compressed_recipient = CompressedCiphertextListBuilder::new()
-        .push(new_recipient_value.clone())
-        .build()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        
-    let serialized_recipient = bincode::serialize(&compressed_recipient)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    update_ciphertext(payload.recipient_key, serialized_recipient.clone()).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    println!("Successfully updated recipient key: {:?}", payload.recipient_key);
+    let keys = state.get_keys();
+    let (value, serialized_data) = state.get_executor().run(move || -> Result<_, ApiError> {
+        // Encrypt with the public key so the plaintext path does not need the client key
+        let value: FheUint64 = CompactCiphertextList::builder(&public_key)
+            .push(value)
+            .build_packed()
+            .expand_with_key(&server_key)
+            .and_then(|expander| expander.get(0))
+            .map_err(|e| ApiError::Internal(format!("encryption failed: {}", e)))?
+            .ok_or_else(|| ApiError::Internal("encryption produced no value".to_string()))?;
+        let value = TypedCiphertext::from(value);
+        let serialized_data = value.compress()?;
+        Ok((value, serialized_data))
+    }).await??;
+    let mut batch = WriteBatch::new(keys.key_id());
+    batch.insert(key, value, serialized_data, owner);
+    operations::commit_batch(&*state.get_store(), &state.get_cache(), batch).await?;
+    Ok(())
+}
+
+pub async fn handle_post_ciphertext(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<EncryptedRequest>) -> Result<StatusCode, ApiError> {
+    once(state, "post_ciphertext", &headers, payload, post_ciphertext).await?;
     Ok(StatusCode::OK)
 }
 
-pub async fn handle_view(
-    State(state): State<AppState>, 
-    Json(payload): Json<Decrypt>
-) -> Result<Json<ViewResponse>, StatusCode> {
-    println!("Received key bytes: {:?}", payload.key);  // Debug incoming data
-    
-    let client_key = state.get_client_key();
-    let server_key = state.get_server_key();
-    set_server_key((*server_key).clone());
-
-    // Add error logging
-    let value = operations::get_prepared_ciphertext(payload.key)
-        .await
-        .map_err(|e| {
-            println!("Error preparing ciphertext: {:?}", e);  // Log the actual error
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    println!("Successfully prepared ciphertext");  // Confirm success
-    
-    let decrypted: u64 = value.decrypt(&client_key);
-    println!("Decrypted value: {}", decrypted);  // Log decrypted value
-    
-    Ok(Json(ViewResponse { result: decrypted }))
-}
-
-pub async fn handle_withdraw(State(state): State<AppState>, 
-Json(payload): Json<Withdraw>
-) -> Result<Json<ViewResponse>, StatusCode> {
-    let client_key = state.get_client_key();
-    let server_key = state.get_server_key();
-    set_server_key((*server_key).clone());
-    
-    let balance = operations::get_prepared_ciphertext(payload.key)
-        .await?;
-    let transfer = operations::get_prepared_ciphertext(payload.value)
+async fn post_ciphertext(state: AppState, payload: EncryptedRequest) -> Result<(), ApiError> {
+    println!("Received client ciphertext for key: {:?}", payload.key);
+    let keys = state.get_keys();
+
+    let (list, fhe_type) = ingest::parse(&payload.ciphertext)?;
+    if let Some(origin) = handle_origin(&state, payload.origin)? {
+        check_handle(&payload.key, fhe_handles::derive(&origin, fhe_handles::opcode::ENCRYPT, &[], None, fhe_type.tag()))?;
+    }
+
+    let crs = state.get_crs();
+    let public_key = state.get_public_key();
+    let (key, owner) = (payload.key, payload.owner);
+    let (value, serialized_data) = state.get_executor().run(move || -> Result<_, ApiError> {
+        // Nothing is stored unless the proof of plaintext knowledge verifies
+        let value = ingest::verify_and_expand(&list, &crs, &public_key, &key, owner.as_ref())?;
+        let serialized_data = value.compress()?;
+        Ok((value, serialized_data))
+    }).await??;
+    let mut batch = WriteBatch::new(keys.key_id());
+    batch.insert(payload.key, value, serialized_data, payload.owner);
+    operations::commit_batch(&*state.get_store(), &state.get_cache(), batch).await?;
+    Ok(())
+}
+
+pub async fn handle_public_key(State(state): State<AppState>) -> Result<Vec<u8>, ApiError> {
+    let public_key = state.get_public_key();
+    bincode::serialize(&*public_key)
+        .map_err(|e| ApiError::Internal(format!("cannot serialize public key: {}", e)))
+}
+
+pub async fn handle_crs(State(state): State<AppState>) -> Result<Vec<u8>, ApiError> {
+    let crs = state.get_crs();
+    bincode::serialize(&*crs)
+        .map_err(|e| ApiError::Internal(format!("cannot serialize CRS: {}", e)))
+}
+
+/// The server key as a bincode `CompressedServerKey`, for compute workers
+/// outside this process. Call `decompress` on it before use.
+pub async fn handle_server_key(State(state): State<AppState>) -> Bytes {
+    state.get_compressed_server_key()
+}
+
+/// Identity and parameters of the key set the public key and CRS belong to.
+pub async fn handle_key_info(State(state): State<AppState>) -> Json<KeyInfo> {
+    Json(state.get_keys().info().clone())
+}
+
+pub async fn handle_transfer(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Transfer>) -> Result<StatusCode, ApiError> {
+    once(state, "transfer", &headers, payload, transfer).await?;
+    Ok(StatusCode::OK)
+}
+
+async fn transfer(state: AppState, payload: Transfer) -> Result<(), ApiError> {
+    let keys = state.get_keys();
+    if payload.sender_key == payload.recipient_key {
+        return Err(ApiError::InvalidRequest("sender and recipient are the same account".to_string()));
+    }
+    let store = state.get_store();
+    let cache = state.get_cache();
+    let executor = state.get_executor();
+    let loader = Loader { store: &*store, cache: &cache, keys: &keys, executor: &executor };
+    let ((sender_value, sender_version), (recipient_value, recipient_version), transfer_value, zero_value) = try_join!(
+        operations::get_typed_ciphertext::<FheUint64>(&loader, payload.sender_key),
+        operations::get_typed_ciphertext::<FheUint64>(&loader, payload.recipient_key),
+        operations::get_prepared_ciphertext(&loader, payload.transfer_value),
+        operations::get_prepared_ciphertext(&loader, ZERO_KEY)
+    )?;
+
+    let ((new_sender_value, serialized_sender), (new_recipient_value, serialized_recipient)) = executor.run(move || {
+        let condition = sender_value.ge(&transfer_value);
+        let real_amount = condition.if_then_else(&transfer_value, &zero_value);
+        let new_sender_value = TypedCiphertext::from(&sender_value - &real_amount);
+        let new_recipient_value = TypedCiphertext::from(&recipient_value + &real_amount);
+        let serialized_sender = new_sender_value.compress()?;
+        let serialized_recipient = new_recipient_value.compress()?;
+        Ok::<_, CiphertextError>(((new_sender_value, serialized_sender), (new_recipient_value, serialized_recipient)))
+    }).await??;
+
+    // Debit and credit commit together, a failure leaves both balances untouched.
+    // A concurrent write to either balance since it was read fails with 409 Conflict.
+    let mut batch = WriteBatch::new(keys.key_id());
+    batch
+        .update(payload.sender_key, sender_version, new_sender_value, serialized_sender)
+        .update(payload.recipient_key, recipient_version, new_recipient_value, serialized_recipient);
+    operations::commit_batch(&*store, &cache, batch).await?;
+    Ok(())
+}
+
+pub async fn handle_op(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Operation>) -> Result<Json<OperationResponse>, ApiError> {
+    once(state, "op", &headers, payload, op).await.map(Json)
+}
+
+async fn op(state: AppState, payload: Operation) -> Result<OperationResponse, ApiError> {
+    println!("Received {} on {} operands, result key: {:?}", payload.opcode, payload.operands.len(), payload.result);
+    let origin = handle_origin(&state, payload.origin)?;
+    let keys = state.get_keys();
+
+    let store = state.get_store();
+    let cache = state.get_cache();
+    let executor = state.get_executor();
+    let loader = Loader { store: &*store, cache: &cache, keys: &keys, executor: &executor };
+    let stored = operations::authorize_derivation(&*store, payload.owner, &payload.operands, &[payload.result]).await?;
+    if let Some(origin) = origin {
+        let operand_types = payload.operands.iter()
+            .map(|key| stored[key].stored_type())
+            .collect::<Result<Vec<_>, _>>()?;
+        let (handle, _) = operations::derive_handle(&origin, payload.opcode, &payload.operands, &operand_types, payload.scalar.as_ref())?;
+        check_handle(&payload.result, handle)?;
+    }
+    let mut operands = Vec::with_capacity(payload.operands.len());
+    let mut batch = WriteBatch::new(keys.key_id());
+    for key in &payload.operands {
+        let (operand, version) = operations::get_any_ciphertext(&loader, *key).await?;
+        // The result is only valid if no operand changed while it was computed
+        batch.check(*key, version);
+        operands.push(operand);
+    }
+    let (opcode, scalar) = (payload.opcode, payload.scalar);
+    let (result, serialized_data) = executor.run(move || -> Result<_, ApiError> {
+        let result = match &scalar {
+            Some(scalar) => compute::execute_scalar(opcode, &operands, scalar)?,
+            None => compute::execute(opcode, &operands)?,
+        };
+        let serialized_data = result.compress()?;
+        Ok((result, serialized_data))
+    }).await??;
+    let result_type = result.fhe_type();
+    let response = OperationResponse { result_type };
+    batch.insert(payload.result, result, serialized_data, payload.owner).respond(&response);
+    operations::commit_batch(&*store, &cache, batch).await?;
+    println!("Stored {} result at key: {:?}", result_type, payload.result);
+    Ok(response)
+}
+
+pub async fn handle_graph(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Graph>) -> Result<Json<GraphResponse>, ApiError> {
+    once(state, "graph", &headers, payload, graph).await.map(Json)
+}
+
+async fn graph(state: AppState, payload: Graph) -> Result<GraphResponse, ApiError> {
+    println!("Received graph of {} nodes", payload.nodes.len());
+    let keys = state.get_keys();
+
+    let mut inputs: Vec<[u8; 32]> = payload.nodes.iter()
+        .flat_map(|node| node.inputs.iter())
+        .filter_map(|input| match input {
+            GraphInput::Handle(key) => Some(*key),
+            GraphInput::Node(_) => None,
+        })
+        .collect();
+    inputs.sort_unstable();
+    inputs.dedup();
+    let outputs: Vec<[u8; 32]> = payload.nodes.iter().filter_map(|node| node.output).collect();
+    let store = state.get_store();
+    let stored = operations::authorize_derivation(&*store, payload.owner, &inputs, &outputs).await?;
+    if let Some(origin) = handle_origin(&state, payload.origin)? {
+        let handles = operations::derive_graph_handles(&origin, &payload.nodes, &stored)?;
+        for (node, handle) in payload.nodes.iter().zip(handles) {
+            if let Some(output) = node.output {
+                check_handle(&output, handle)?;
+            }
+        }
+    }
+    // Outputs are only committed if no input changed while the graph ran
+    let cache = state.get_cache();
+    let mut batch = WriteBatch::new(keys.key_id());
+    let mut cached = HashMap::with_capacity(inputs.len());
+    let mut missing = Vec::new();
+    for key in inputs {
+        match cache.get(&key).await {
+            Some((value, version)) => {
+                batch.check(key, version);
+                cached.insert(key, value);
+            }
+            None => missing.push(key),
+        }
+    }
+    let rows = store.get_many(missing).await?;
+    let versions: Vec<([u8; 32], i64)> = rows.iter().map(|row| (row.key, row.version)).collect();
+    for (key, version) in &versions {
+        batch.check(*key, *version);
+    }
+
+    // Decompression, the graph and compression all run as one job on the compute pool
+    let nodes = payload.nodes;
+    let (loaded, outputs) = state.get_executor().run(move || -> Result<(Vec<TypedCiphertext>, Vec<CiphertextRow>), ApiError> {
+        let loaded = rows.par_iter()
+            .map(|row| keys.decompress(row))
+            .collect::<Result<Vec<_>, CiphertextError>>()?;
+        let mut handles = cached;
+        handles.extend(rows.iter().map(|row| row.key).zip(loaded.iter().cloned()));
+        let results = compute::execute_graph(&nodes, &handles)?;
+        let outputs = nodes.par_iter()
+            .zip(results)
+            .filter_map(|(node, result)| node.output.map(|key| (key, result)))
+            .map(|(key, result)| {
+                let data = result.compress()?;
+                Ok((key, result, data))
+            })
+            .collect::<Result<Vec<_>, CiphertextError>>()?;
+        Ok((loaded, outputs))
+    }).await??;
+    for ((key, version), value) in versions.into_iter().zip(loaded) {
+        cache.insert(key, value, version).await;
+    }
+
+    let response = GraphResponse {
+        outputs: outputs.iter()
+            .map(|(key, result, _)| GraphOutput { key: *key, result_type: result.fhe_type() })
+            .collect(),
+    };
+    for (key, result, data) in outputs {
+        batch.insert(key, result, data, payload.owner);
+    }
+    batch.respond(&response);
+    operations::commit_batch(&*store, &cache, batch).await?;
+    println!("Stored {} graph outputs", response.outputs.len());
+    Ok(response)
+}
+
+pub async fn handle_view(State(state): State<AppState>, Json(payload): Json<Decrypt>) -> Result<Json<ViewResponse>, ApiError> {
+    let message = auth::decrypt_message(&payload.key, payload.nonce, payload.expiry);
+    authorize_owner(&*state.get_store(), payload.key, &message, payload.nonce, payload.expiry, &payload.signature).await?;
+
+    let decryptor = state.get_decryptor();
+    let keys = state.get_keys();
+    let (store, cache, executor) = (state.get_store(), state.get_cache(), state.get_executor());
+    let loader = Loader { store: &*store, cache: &cache, keys: &keys, executor: &executor };
+
+    let (value, _) = operations::get_any_ciphertext(&loader, payload.key).await?;
+    let plaintext = decryptor.decrypt(value).await?;
+    Ok(Json(ViewResponse { result: plaintext.to_scalar(), result_type: plaintext.fhe_type }))
+}
+
+pub async fn handle_reencrypt(
+    State(state): State<AppState>,
+    Json(payload): Json<Reencrypt>
+) -> Result<Json<ReencryptResponse>, ApiError> {
+    println!("Received reencrypt request for key: {:?}", payload.key);
+    let message = auth::reencrypt_message(&payload.key, &payload.recipient, payload.nonce, payload.expiry);
+    authorize_owner(&*state.get_store(), payload.key, &message, payload.nonce, payload.expiry, &payload.signature).await?;
+
+    let decryptor = state.get_decryptor();
+    let keys = state.get_keys();
+    let (store, cache, executor) = (state.get_store(), state.get_cache(), state.get_executor());
+    let loader = Loader { store: &*store, cache: &cache, keys: &keys, executor: &executor };
+
+    let (value, _) = operations::get_any_ciphertext(&loader, payload.key).await?;
+    let plaintext = decryptor.decrypt(value).await?;
+    // The plaintext only leaves this function sealed to the recipient
+    let sealed = sealing::seal(&payload.recipient, &payload.key, &plaintext.bytes)
+        .map_err(|e| ApiError::InvalidRequest(format!("cannot seal to recipient: {}", e)))?;
+
+    Ok(Json(ReencryptResponse {
+        result_type: plaintext.fhe_type,
+        ephemeral_public_key: sealed.ephemeral_public_key,
+        nonce: sealed.nonce,
+        ciphertext: sealed.ciphertext,
+    }))
+}
+
+pub async fn handle_cache_stats(State(state): State<AppState>) -> Json<CacheStats> {
+    Json(state.get_cache().stats().await)
+}
+
+pub async fn handle_compute_stats(State(state): State<AppState>) -> Json<ComputeStats> {
+    Json(state.get_executor().stats())
+}
+
+pub async fn handle_withdraw(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Withdraw>) -> Result<StatusCode, ApiError> {
+    once(state, "withdraw", &headers, payload, authorized_withdraw).await?;
+    Ok(StatusCode::OK)
+}
+
+// Checks the owner's signature before the withdrawal runs as a job, so a job
+// resumed after a restart is not refused for its nonce having been used
+async fn authorized_withdraw(state: AppState, payload: Withdraw) -> Result<(), ApiError> {
+    let message = auth::withdraw_message(&payload.key, payload.value.as_ref(), payload.amount, payload.nonce, payload.expiry);
+    authorize_owner(&*state.get_store(), payload.key, &message, payload.nonce, payload.expiry, &payload.signature).await?;
+    withdraw(state, payload).await
+}
+
+// The new balance is not decrypted here, where it would be stored with the
+// job, the owner reads it through `/decrypt`
+async fn withdraw(state: AppState, payload: Withdraw) -> Result<(), ApiError> {
+    let keys = state.get_keys();
+
+    let store = state.get_store();
+    let cache = state.get_cache();
+    let executor = state.get_executor();
+    let loader = Loader { store: &*store, cache: &cache, keys: &keys, executor: &executor };
+    let (balance, version) = operations::get_typed_ciphertext::<FheUint64>(&loader, payload.key)
         .await?;
-    let zero_value = operations::get_prepared_ciphertext(zero_key).await?;
-        
-    let condition = balance.ge(&transfer);
-    let real_amount = condition.if_then_else(&transfer, &zero_value);
-    let new_balance = balance - real_amount;
-
-    let compressed = CompressedCiphertextListBuilder::new()
-        .push(new_balance.clone())
-        .build()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        
-    let serialized_data = bincode::serialize(&compressed)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    update_ciphertext(payload.key, serialized_data)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let decrypted: u64 = new_balance.decrypt(&client_key);
-    Ok(Json(ViewResponse { result: decrypted }))
-}
\ No newline at end of file
+    let encrypted_amount = match (payload.value, payload.amount) {
+        (Some(value), None) => Some(try_join!(
+            operations::get_prepared_ciphertext(&loader, value),
+            operations::get_prepared_ciphertext(&loader, ZERO_KEY)
+        )?),
+        (None, Some(_)) => None,
+        _ => {
+            return Err(ApiError::InvalidRequest("withdraw needs exactly one of value and amount".to_string()));
+        }
+    };
+    let amount = payload.amount.unwrap_or_default();
+
+    let (new_value, serialized_data) = executor.run(move || {
+        let new_balance = match encrypted_amount {
+            Some((transfer, zero_value)) => {
+                let condition = balance.ge(&transfer);
+                let real_amount = condition.if_then_else(&transfer, &zero_value);
+                balance - real_amount
+            }
+            // A public amount only needs scalar operations, no encrypted amount or zero
+            None => {
+                let condition = balance.ge(amount);
+                condition.if_then_else(&(&balance - amount), &balance)
+            }
+        };
+        let new_value = TypedCiphertext::from(new_balance);
+        let serialized_data = new_value.compress()?;
+        Ok::<_, CiphertextError>((new_value, serialized_data))
+    }).await??;
+
+    let mut batch = WriteBatch::new(keys.key_id());
+    batch.update(payload.key, version, new_value, serialized_data);
+    operations::commit_batch(&*store, &cache, batch).await?;
+    Ok(())
+}
+
+/// Status of a job accepted through `/jobs`, with its result once finished.
+pub async fn handle_job(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Job>, ApiError> {
+    state.get_jobs().get(&id).await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("no job {}", id)))
+}
+
+pub async fn handle_post_job(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Request>) -> Result<Response, ApiError> {
+    accept(state, "post", &headers, payload, post).await
+}
+
+pub async fn handle_post_ciphertext_job(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<EncryptedRequest>) -> Result<Response, ApiError> {
+    accept(state, "post_ciphertext", &headers, payload, post_ciphertext).await
+}
+
+pub async fn handle_transfer_job(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Transfer>) -> Result<Response, ApiError> {
+    accept(state, "transfer", &headers, payload, transfer).await
+}
+
+pub async fn handle_op_job(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Operation>) -> Result<Response, ApiError> {
+    accept(state, "op", &headers, payload, op).await
+}
+
+pub async fn handle_graph_job(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Graph>) -> Result<Response, ApiError> {
+    accept(state, "graph", &headers, payload, graph).await
+}
+
+pub async fn handle_withdraw_job(State(state): State<AppState>, headers: HeaderMap, Json(payload): Json<Withdraw>) -> Result<Response, ApiError> {
+    accept(state, "withdraw", &headers, payload, authorized_withdraw).await
+}
+
+/// Runs a job's request through the endpoint of its `kind`, for jobs resumed
+/// after a restart. Their authorization was checked when they were accepted.
+pub async fn run_job(state: AppState, kind: String, request: serde_json::Value) -> Result<serde_json::Value, ApiError> {
+    match kind.as_str() {
+        "post" => to_value(post(state, parse(request)?).await?),
+        "post_ciphertext" => to_value(post_ciphertext(state, parse(request)?).await?),
+        "transfer" => to_value(transfer(state, parse(request)?).await?),
+        "op" => to_value(op(state, parse(request)?).await?),
+        "graph" => to_value(graph(state, parse(request)?).await?),
+        "withdraw" => to_value(withdraw(state, parse(request)?).await?),
+        other => Err(ApiError::InvalidRequest(format!("unknown job kind {}", other))),
+    }
+}
+
+// Stores the request as a job of `kind`, runs `operation` on it in the
+// background and answers 202 Accepted with where to poll it. A resubmission
+// under the same `Idempotency-Key` gets the earlier job with 200 OK instead.
+async fn accept<P, T, F, Fut>(state: AppState, kind: &str, headers: &HeaderMap, payload: P, operation: F) -> Result<Response, ApiError>
+where
+    P: Serialize,
+    T: Serialize,
+    F: FnOnce(AppState, P) -> Fut,
+    Fut: Future<Output = Result<T, ApiError>> + Send + 'static,
+{
+    let idempotency_key = idempotency_key(headers)?;
+    let request = to_value(&payload)?;
+    let jobs = state.get_jobs();
+    let operation = operation(state, payload);
+    let (job, created) = jobs.submit(kind, idempotency_key, request, async move { to_value(operation.await?) }).await?;
+    let status = if created { StatusCode::ACCEPTED } else { StatusCode::OK };
+    let location = format!("/jobs/{}", job.id);
+    Ok((status, [(header::LOCATION, location)], Json(job)).into_response())
+}
+
+// Runs `operation` on the request, or once per key if it has an
+// `Idempotency-Key`. The first submission under a key runs as a job of `kind`
+// and waits for it, later ones get its stored outcome without recomputing
+async fn once<P, T, F, Fut>(state: AppState, kind: &str, headers: &HeaderMap, payload: P, operation: F) -> Result<T, ApiError>
+where
+    P: Serialize,
+    T: Serialize + DeserializeOwned,
+    F: FnOnce(AppState, P) -> Fut,
+    Fut: Future<Output = Result<T, ApiError>> + Send + 'static,
+{
+    let Some(idempotency_key) = idempotency_key(headers)? else {
+        return operation(state, payload).await;
+    };
+    let request = to_value(&payload)?;
+    let jobs = state.get_jobs();
+    let operation = operation(state, payload);
+    let result = jobs.run(kind, idempotency_key.clone(), request, async move { to_value(operation.await?) }).await?;
+    // Jobs applied before a restart by versions that did not store results with
+    // the writes only have one for endpoints that return none
+    serde_json::from_value(result.unwrap_or_default()).map_err(|_| ApiError::Internal(format!(
+        "request {} was applied, but its result was lost in a restart", idempotency_key
+    )))
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    headers.get(IDEMPOTENCY_KEY)
+        .map(|value| value.to_str().map(str::to_string))
+        .transpose()
+        .map_err(|_| ApiError::InvalidRequest(format!("{} must be visible ASCII", IDEMPOTENCY_KEY)))
+}
+
+fn parse<T: DeserializeOwned>(request: serde_json::Value) -> Result<T, ApiError> {
+    serde_json::from_value(request).map_err(|e| ApiError::InvalidRequest(format!("invalid job request: {}", e)))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<serde_json::Value, ApiError> {
+    serde_json::to_value(value).map_err(|e| ApiError::Internal(format!("cannot serialize job: {}", e)))
+}
+
+// The origin the result keys of a request must be derived from. Requests
+// without one are refused if origins are required, otherwise their keys are
+// taken as they are
+fn handle_origin(state: &AppState, origin: Option<HandleOrigin>) -> Result<Option<fhe_handles::Origin>, ApiError> {
+    match origin {
+        Some(origin) => Ok(Some(origin.into())),
+        None if state.requires_handle_origin() => Err(ApiError::InvalidRequest(
+            "the result handle comes without the origin it was derived from".to_string()
+        )),
+        None => Ok(None),
+    }
+}
+
+// Only the key the requesting program derived for a value may be written
+fn check_handle(key: &[u8; 32], derived: [u8; 32]) -> Result<(), ApiError> {
+    if *key != derived {
+        return Err(ApiError::InvalidRequest(format!("result key {:?} does not match its derivation", key)));
+    }
+    Ok(())
+}
+
+// Checks the owner's signature over `message` and burns its nonce
+async fn authorize_owner(store: &dyn CiphertextStore, key: [u8; 32], message: &[u8], nonce: u64, expiry: i64, signature: &[u8]) -> Result<(), ApiError> {
+    let owner = store.get(key)
+        .await?
+        .ok_or(StoreError::NotFound(key))?
+        .owner;
+    let owner = auth::verify_owner_signature(owner, message, expiry, signature)?;
+    let fresh = store.consume_nonce(owner, nonce, expiry, auth::now()).await?;
+    if !fresh {
+        return Err(AuthError::Replayed.into());
+    }
+    Ok(())
+}
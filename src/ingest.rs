@@ -0,0 +1,72 @@
+use tfhe::{CompactPublicKey, ProvenCompactCiphertextList};
+use tfhe::zk::CompactPkeCrs;
+use crate::ciphertext::TypedCiphertext;
+use crate::types::FheType;
+
+/// Reasons a client-submitted ciphertext is rejected.
+#[derive(Debug)]
+pub enum IngestError {
+    /// The payload is not a bincode-serialized `ProvenCompactCiphertextList`
+    Deserialization(String),
+    /// The proof of plaintext knowledge did not verify against the CRS
+    InvalidProof,
+    /// The verified list does not hold a supported type at index 0
+    Expansion(String),
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::Deserialization(e) => write!(f, "invalid ciphertext list: {}", e),
+            IngestError::InvalidProof => write!(f, "proof verification failed"),
+            IngestError::Expansion(e) => write!(f, "invalid ciphertext: {}", e),
+        }
+    }
+}
+
+/// What a client proves its ciphertext with: the 32-byte key followed by the
+/// 32-byte owner, or the key alone for an unowned value.
+pub fn proof_metadata(key: &[u8; 32], owner: Option<&[u8; 32]>) -> Vec<u8> {
+    let mut metadata = key.to_vec();
+    if let Some(owner) = owner {
+        metadata.extend_from_slice(owner);
+    }
+    metadata
+}
+
+/// Deserializes a client ciphertext and reads the type of its first element,
+/// the type stored if its proof verifies. Nothing is verified yet, so the key
+/// the value is for can be checked before the proof.
+pub fn parse(ciphertext: &[u8]) -> Result<(ProvenCompactCiphertextList, FheType), IngestError> {
+    let proven_list: ProvenCompactCiphertextList = bincode::deserialize(ciphertext)
+        .map_err(|e| IngestError::Deserialization(e.to_string()))?;
+    let kind = proven_list.get_kind_of(0)
+        .ok_or_else(|| IngestError::Expansion("no element at index 0".to_string()))?;
+    let fhe_type = FheType::try_from(kind).map_err(|e| IngestError::Expansion(e.to_string()))?;
+    Ok((proven_list, fhe_type))
+}
+
+/// Verifies the proof attached to a client ciphertext and expands its first element,
+/// keeping whichever `FheType` the client encrypted.
+///
+/// The handle the value is stored under and its owner are the proof metadata,
+/// so a proof cannot be replayed to populate a different handle or to give the
+/// value to another owner. Requires the server key to be set on the calling
+/// thread for the key-switch to compute parameters.
+pub fn verify_and_expand(
+    proven_list: &ProvenCompactCiphertextList,
+    crs: &CompactPkeCrs,
+    public_key: &CompactPublicKey,
+    key: &[u8; 32],
+    owner: Option<&[u8; 32]>,
+) -> Result<TypedCiphertext, IngestError> {
+    if proven_list.verify(crs, public_key, &proof_metadata(key, owner)).is_invalid() {
+        return Err(IngestError::InvalidProof);
+    }
+    // The proof was checked above, expanding through verify_and_expand would verify it twice
+    let expander = proven_list
+        .expand_without_verification()
+        .map_err(|e| IngestError::Expansion(e.to_string()))?;
+    TypedCiphertext::from_list(&expander, 0)
+        .map_err(|e| IngestError::Expansion(e.to_string()))
+}
@@ -0,0 +1,429 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::Rng;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use crate::auth;
+use crate::error::ApiError;
+use crate::store::{CiphertextStore, StoreError, StoredJob};
+use crate::types::{ErrorResponse, Job, JobStatus};
+
+const CALLBACK_ATTEMPTS: u32 = 3;
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+tokio::task_local! {
+    // The job the current task runs, so its batch marks the job applied
+    static CURRENT: String;
+}
+
+/// ID of the job the calling task runs, if it runs one.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// Operations accepted through `/jobs`. Each runs in the background while
+/// the client polls `GET /jobs/{id}`, and on completion the job is posted
+/// to the configured callback URL. Synchronous requests with an idempotency
+/// key also run as jobs, through `run`.
+///
+/// Jobs are stored with their request before they run, and `resume` runs
+/// those a restart interrupted. The batch committing a job's writes also
+/// marks it applied, so a job that got that far is not run twice. A job
+/// submitted with an idempotency key is stored once under it, and
+/// resubmitting the key returns that job. A job failing with a retryable
+/// error before it was applied gives up its key, so resubmitting runs the
+/// request again.
+///
+/// Finished jobs are kept for `retention_secs`, those holding an idempotency
+/// key at least `idempotency_ttl_secs` after they were submitted. `start_pruning`
+/// drops them afterwards.
+pub struct Jobs {
+    store: Arc<dyn CiphertextStore>,
+    callback_url: Option<String>,
+    retention_secs: i64,
+    idempotency_ttl_secs: i64,
+}
+
+impl Jobs {
+    pub fn new(store: Arc<dyn CiphertextStore>, callback_url: Option<String>, retention_secs: u64, idempotency_ttl_secs: u64) -> Self {
+        Self {
+            store,
+            callback_url,
+            retention_secs: retention_secs as i64,
+            idempotency_ttl_secs: idempotency_ttl_secs as i64,
+        }
+    }
+
+    /// Drops expired jobs every PRUNE_INTERVAL in a background task.
+    pub fn start_pruning(self: &Arc<Self>) {
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = auth::now();
+                match jobs.store.prune_jobs(now - jobs.retention_secs, now - jobs.idempotency_ttl_secs).await {
+                    Ok(0) => {}
+                    Ok(pruned) => println!("Pruned {} finished jobs", pruned),
+                    Err(e) => println!("Failed to prune jobs: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Stores a queued job of `kind` for `request` and runs `operation` in the
+    /// background. If a job was submitted under `idempotency_key` before, it is
+    /// returned instead and `operation` is dropped. The flag tells whether the
+    /// job is new.
+    pub async fn submit<F>(self: &Arc<Self>, kind: &str, idempotency_key: Option<String>, request: Value, operation: F) -> Result<(Job, bool), ApiError>
+    where
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let (job, created) = self.insert(kind, idempotency_key, request, true).await?;
+        if created {
+            println!("Accepted {} job {}", job.kind, job.id);
+            self.spawn(job.clone(), operation, true);
+        }
+        Ok((job, created))
+    }
+
+    /// Runs `operation` as a job of `kind` stored under `idempotency_key` and
+    /// returns its result, for requests answered synchronously. If the key was
+    /// submitted before, the stored outcome is returned and `operation` is
+    /// dropped. The result is `None` for endpoints that return none.
+    pub async fn run<F>(self: &Arc<Self>, kind: &str, idempotency_key: String, request: Value, operation: F) -> Result<Option<Value>, ApiError>
+    where
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let (job, created) = self.insert(kind, Some(idempotency_key), request, false).await?;
+        let job = if created {
+            // A spawned task finishes the job even if the client goes away
+            self.spawn(job, operation, false)
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| ApiError::Internal("job ended without recording its outcome".to_string()))?
+        } else {
+            job
+        };
+        match (job.status, job.error) {
+            (JobStatus::Done, _) => Ok(job.result),
+            (JobStatus::Failed, Some(error)) => Err(error.into()),
+            (JobStatus::Failed, None) => Err(ApiError::Internal(format!("job {} failed without an error", job.id))),
+            (JobStatus::Queued | JobStatus::Running, _) => Err(ApiError::Conflict(format!(
+                "the request is still running as job {}, retry later", job.id
+            ))),
+        }
+    }
+
+    // Stores a new queued job, posted to the callback URL when finished if
+    // `notify`, or returns the one stored under the same idempotency key. The
+    // flag tells whether the job is new
+    async fn insert(&self, kind: &str, idempotency_key: Option<String>, request: Value, notify: bool) -> Result<(Job, bool), ApiError> {
+        let now = auth::now();
+        let request = request.to_string();
+        let id: [u8; 16] = rand::thread_rng().gen();
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        let stored = self.store.insert_job(StoredJob {
+            id: id.clone(),
+            idempotency_key,
+            kind: kind.to_string(),
+            request: request.clone(),
+            status: status_name(JobStatus::Queued),
+            result: None,
+            error: None,
+            finished: false,
+            applied: false,
+            notify,
+            created_at: now,
+            updated_at: now,
+        }).await?;
+        if stored.id == id {
+            return Ok((to_job(stored)?, true));
+        }
+        if stored.kind != kind || stored.request != request {
+            return Err(ApiError::InvalidRequest(format!(
+                "idempotency key of job {} was used for a different request", stored.id
+            )));
+        }
+        let job = to_job(stored)?;
+        println!("Returning {} job {} for a resubmitted request", job.kind, job.id);
+        Ok((job, false))
+    }
+
+    /// Runs the jobs a restart interrupted, each through `run` with its kind and
+    /// request. Jobs whose writes were committed are only marked done, with the
+    /// result stored along with the writes. Returns how many were resumed.
+    pub async fn resume<R, F>(self: &Arc<Self>, run: R) -> Result<usize, StoreError>
+    where
+        R: Fn(String, Value) -> F,
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let pending = self.store.pending_jobs().await?;
+        for stored in &pending {
+            let job = to_job(stored.clone()).map_err(|e| StoreError::Storage(e.to_string()))?;
+            if stored.applied {
+                println!("{} job {} was applied before the restart", job.kind, job.id);
+                let result = stored.result.as_deref()
+                    .map(serde_json::from_str)
+                    .transpose()
+                    .map_err(|e| StoreError::Storage(format!("invalid result of job {}: {}", job.id, e)))?;
+                if let Some(job) = self.finish(&job.id, Ok(result)).await.filter(|_| stored.notify) {
+                    self.notify(job).await;
+                }
+                continue;
+            }
+            let request = serde_json::from_str(&stored.request)
+                .map_err(|e| StoreError::Storage(format!("invalid request of job {}: {}", job.id, e)))?;
+            println!("Resuming {} job {}", job.kind, job.id);
+            let operation = run(job.kind.clone(), request);
+            // Synchronous requests with an idempotency key were never posted
+            self.spawn(job, operation, stored.notify);
+        }
+        Ok(pending.len())
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<Job>, ApiError> {
+        self.store.get_job(id.to_string()).await?.map(to_job).transpose()
+    }
+
+    // Runs `operation` for `job` in a task of its own, returning the finished
+    // job. `notify` posts it to the callback URL as well
+    fn spawn<F>(self: &Arc<Self>, job: Job, operation: F, notify: bool) -> JoinHandle<Option<Job>>
+    where
+        F: Future<Output = Result<Value, ApiError>> + Send + 'static,
+    {
+        let jobs = self.clone();
+        tokio::spawn(CURRENT.scope(job.id.clone(), async move {
+            jobs.update(&job.id, |job| job.status = JobStatus::Running).await;
+            let outcome = operation.await.map(Some);
+            let job = jobs.finish(&job.id, outcome).await?;
+            println!("{} job {} is {:?}", job.kind, job.id, job.status);
+            if notify {
+                jobs.notify(job.clone()).await;
+            }
+            Some(job)
+        }))
+    }
+
+    // Records the outcome
+    async fn finish(&self, id: &str, outcome: Result<Option<Value>, ApiError>) -> Option<Job> {
+        if outcome.as_ref().is_err_and(ApiError::is_retryable) {
+            // Not the request's final outcome, released first so no resubmission gets it
+            match self.store.release_job_key(id.to_string()).await {
+                Ok(true) => println!("Released the idempotency key of job {} to be retried", id),
+                Ok(false) => {}
+                Err(e) => println!("Failed to release the idempotency key of job {}: {}", id, e),
+            }
+        }
+        self.update(id, |job| match outcome {
+            Ok(result) => {
+                job.status = JobStatus::Done;
+                job.result = result;
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(ErrorResponse { code: e.code().to_string(), message: e.to_string() });
+            }
+        }).await
+    }
+
+    // Applies `change` to the stored job. A job that cannot be saved is still
+    // returned, so it is reported, and runs again after a restart
+    async fn update(&self, id: &str, change: impl FnOnce(&mut Job)) -> Option<Job> {
+        let mut job = match self.get(id).await {
+            Ok(job) => job?,
+            Err(e) => {
+                println!("Failed to load job {}: {}", id, e);
+                return None;
+            }
+        };
+        change(&mut job);
+        job.updated_at = auth::now();
+        if let Err(e) = self.store.update_job(to_stored(&job)).await {
+            println!("Failed to save job {}: {}", id, e);
+        }
+        Some(job)
+    }
+
+    // Posts the finished job to the callback URL, retrying with backoff
+    async fn notify(&self, job: Job) {
+        let Some(url) = self.callback_url.clone() else {
+            return;
+        };
+        let delivered = tokio::task::spawn_blocking(move || {
+            for attempt in 1..=CALLBACK_ATTEMPTS {
+                match ureq::post(&url).timeout(CALLBACK_TIMEOUT).send_json(&job) {
+                    Ok(_) => return true,
+                    Err(e) => {
+                        println!("Callback for job {} failed ({}/{}): {}", job.id, attempt, CALLBACK_ATTEMPTS, e);
+                        if attempt < CALLBACK_ATTEMPTS {
+                            std::thread::sleep(Duration::from_secs(1 << attempt));
+                        }
+                    }
+                }
+            }
+            false
+        }).await;
+        if !matches!(delivered, Ok(true)) {
+            println!("Gave up on the completion callback, the job can still be polled");
+        }
+    }
+}
+
+impl Job {
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+fn status_name(status: JobStatus) -> String {
+    match serde_json::to_value(status) {
+        Ok(Value::String(name)) => name,
+        _ => format!("{:?}", status),
+    }
+}
+
+fn to_job(stored: StoredJob) -> Result<Job, ApiError> {
+    let invalid = |e: serde_json::Error| ApiError::Internal(format!("invalid stored job {}: {}", stored.id, e));
+    Ok(Job {
+        status: serde_json::from_value(Value::String(stored.status.clone())).map_err(invalid)?,
+        // Stored with the writes already, but only reported once the job is done
+        result: stored.result.as_deref().filter(|_| stored.finished).map(serde_json::from_str).transpose().map_err(invalid)?,
+        error: stored.error.as_deref().map(serde_json::from_str).transpose().map_err(invalid)?,
+        id: stored.id,
+        kind: stored.kind,
+        created_at: stored.created_at,
+        updated_at: stored.updated_at,
+    })
+}
+
+// Only the fields `update_job` writes are filled in
+fn to_stored(job: &Job) -> StoredJob {
+    StoredJob {
+        id: job.id.clone(),
+        idempotency_key: None,
+        kind: job.kind.clone(),
+        request: String::new(),
+        status: status_name(job.status),
+        result: job.result.as_ref().map(Value::to_string),
+        error: job.error.as_ref().and_then(|error| serde_json::to_string(error).ok()),
+        finished: job.is_finished(),
+        applied: false,
+        notify: false,
+        created_at: job.created_at,
+        updated_at: job.updated_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use serde_json::json;
+    use crate::store::{MemoryStore, Write};
+
+    fn jobs() -> (Arc<Jobs>, Arc<dyn CiphertextStore>) {
+        let store: Arc<dyn CiphertextStore> = Arc::new(MemoryStore::new());
+        (Arc::new(Jobs::new(store.clone(), None, 3600, 86400)), store)
+    }
+
+    // An operation counting its runs and committing a batch as jobs do
+    fn counted(store: &Arc<dyn CiphertextStore>, runs: &Arc<AtomicUsize>, outcome: Result<Value, ApiError>) -> impl Future<Output = Result<Value, ApiError>> + Send + 'static {
+        let (store, runs) = (store.clone(), runs.clone());
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            let result = outcome?;
+            let applied = Write::Applied { id: current().expect("runs as a job"), result: Some(result.to_string()) };
+            store.batch(vec![applied]).await?;
+            Ok(result)
+        }
+    }
+
+    async fn finished(jobs: &Jobs, id: &str) -> Job {
+        for _ in 0..100 {
+            if let Some(job) = jobs.get(id).await.unwrap().filter(Job::is_finished) {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("job {} did not finish", id);
+    }
+
+    #[tokio::test]
+    async fn resubmitted_keys_return_the_stored_outcome() {
+        let (jobs, store) = jobs();
+        let runs = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let result = jobs.run("op", "key".to_string(), json!({"a": 1}), counted(&store, &runs, Ok(json!(7)))).await;
+            assert_eq!(result.unwrap(), Some(json!(7)));
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        let other = jobs.run("op", "key".to_string(), json!({"a": 2}), counted(&store, &runs, Ok(json!(8)))).await;
+        assert!(matches!(other, Err(ApiError::InvalidRequest(_))));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn final_failures_keep_their_key() {
+        let (jobs, store) = jobs();
+        let runs = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let failure = Err(ApiError::InvalidRequest("bad".to_string()));
+            let result = jobs.run("op", "key".to_string(), json!({}), counted(&store, &runs, failure)).await;
+            assert!(matches!(result, Err(ApiError::InvalidRequest(_))));
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retryable_failures_release_their_key() {
+        let (jobs, store) = jobs();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let busy = Err(ApiError::Busy("full".to_string()));
+        let result = jobs.run("op", "key".to_string(), json!({}), counted(&store, &runs, busy)).await;
+        assert!(matches!(result, Err(ApiError::Busy(_))));
+        let result = jobs.run("op", "key".to_string(), json!({}), counted(&store, &runs, Ok(json!(7)))).await;
+        assert_eq!(result.unwrap(), Some(json!(7)));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn resume_reruns_only_unapplied_jobs() {
+        let (jobs, store) = jobs();
+        let (applied, _) = jobs.insert("op", None, json!({"n": 1}), true).await.unwrap();
+        let (interrupted, _) = jobs.insert("op", None, json!({"n": 2}), false).await.unwrap();
+        // The first got as far as committing its writes before the restart
+        store.batch(vec![Write::Applied { id: applied.id.clone(), result: Some("1".to_string()) }]).await.unwrap();
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let resumed = jobs.resume(|kind, request| {
+            assert_eq!((kind.as_str(), &request), ("op", &json!({"n": 2})));
+            counted(&store, &runs, Ok(json!(2)))
+        }).await.unwrap();
+        assert_eq!(resumed, 2);
+
+        assert_eq!(finished(&jobs, &applied.id).await.result, Some(json!(1)));
+        assert_eq!(finished(&jobs, &interrupted.id).await.result, Some(json!(2)));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(store.pending_jobs().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn jobs_record_whether_they_are_posted() {
+        let (jobs, store) = jobs();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (submitted, _) = jobs.submit("op", None, json!({}), counted(&store, &runs, Ok(json!(1)))).await.unwrap();
+        jobs.run("op", "key".to_string(), json!({}), counted(&store, &runs, Ok(json!(2)))).await.unwrap();
+        finished(&jobs, &submitted.id).await;
+
+        // A resumed job is posted only if it was submitted, see `resume`
+        assert!(store.get_job(submitted.id).await.unwrap().unwrap().notify);
+        let (run, created) = jobs.insert("op", Some("key".to_string()), json!({}), true).await.unwrap();
+        assert!(!created);
+        assert!(!store.get_job(run.id).await.unwrap().unwrap().notify);
+    }
+}
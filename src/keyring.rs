@@ -0,0 +1,130 @@
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tfhe::{set_server_key, KeySwitchingKey, ServerKey};
+use crate::ciphertext::{CiphertextError, TypedCiphertext};
+use crate::keys::{self, KeyInfo};
+use crate::store::StoredRow;
+
+thread_local! {
+    // Address of the server key tfhe holds a copy of on this thread
+    static INSTALLED: Cell<*const ServerKey> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Makes `key` the server key of the calling thread. tfhe keeps a copy per
+/// thread, so the key is only cloned when a thread switches to it, not on
+/// every request the thread serves.
+pub fn install_server_key(key: &Arc<ServerKey>) {
+    INSTALLED.with(|installed| {
+        if installed.get() != Arc::as_ptr(key) {
+            set_server_key((**key).clone());
+            installed.set(Arc::as_ptr(key));
+        }
+    });
+}
+
+fn previous_key_pool(key: ServerKey) -> Result<rayon::ThreadPool, String> {
+    let key = Arc::new(key);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(PREVIOUS_KEY_THREADS)
+        .thread_name(|index| format!("previous-key-{}", index))
+        .start_handler(move |_| install_server_key(&key))
+        .build()
+        .map_err(|e| format!("Failed to start previous key pool: {}", e))
+}
+
+/// The key set the server computes under and, while `rotate_keys` migrates
+/// stored rows to it, the key set it rotates away from.
+pub struct KeyRing {
+    current: KeyInfo,
+    // Directory of the current key set's public key and CRS
+    dir: PathBuf,
+    server_key: Arc<ServerKey>,
+    previous: Option<PreviousKeys>,
+}
+
+// Threads decompressing rows under the previous key set, only needed during a
+// rotation. Each holds its own copy of that server key.
+const PREVIOUS_KEY_THREADS: usize = 2;
+
+struct PreviousKeys {
+    info: KeyInfo,
+    // Has the previous server key installed, so threads computing under the
+    // current one never switch keys
+    pool: rayon::ThreadPool,
+    // Switches values from this key set to the current one
+    rekey: KeySwitchingKey,
+}
+
+impl KeyRing {
+    /// Loads the key set in `dir`, or during a rotation the one in its `next`
+    /// directory with the key set in `dir` as the previous one.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        if !keys::has_next(dir) {
+            return Ok(Self {
+                current: keys::load_key_info(dir)?,
+                dir: dir.to_path_buf(),
+                server_key: Arc::new(keys::load_server_key(dir)?),
+                previous: None,
+            });
+        }
+        let next = dir.join(keys::NEXT_DIR);
+        // Without it rows under the previous key set cannot be read, and the
+        // migration must run with the server stopped
+        let rekey = keys::load_rekey(&next)?.ok_or_else(|| format!(
+            "{} has no key switching key, keep the server stopped until rotate_keys finishes", next.display()
+        ))?;
+        Ok(Self {
+            current: keys::load_key_info(&next)?,
+            server_key: Arc::new(keys::load_server_key(&next)?),
+            dir: next,
+            previous: Some(PreviousKeys {
+                info: keys::load_key_info(dir)?,
+                pool: previous_key_pool(keys::load_server_key(dir)?)?,
+                rekey,
+            }),
+        })
+    }
+
+    pub fn info(&self) -> &KeyInfo {
+        &self.current
+    }
+
+    /// Key set new values are stored under.
+    pub fn key_id(&self) -> &str {
+        &self.current.key_id
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn server_key(&self) -> &Arc<ServerKey> {
+        &self.server_key
+    }
+
+    pub fn previous(&self) -> Option<&KeyInfo> {
+        self.previous.as_ref().map(|previous| &previous.info)
+    }
+
+    /// Decompresses `row` into a value under the current key set, switching
+    /// values under the previous key set over. Rows from before key IDs are
+    /// under the oldest key set loaded.
+    /// Requires the current server key to be installed on the calling thread.
+    pub fn decompress(&self, row: &StoredRow) -> Result<TypedCiphertext, CiphertextError> {
+        let previous = self.previous.as_ref()
+            .filter(|previous| row.key_id.as_ref().is_none_or(|id| *id == previous.info.key_id));
+        match (previous, row.key_id.as_deref()) {
+            (Some(previous), _) => {
+                // Decompression needs the key set the row was compressed under
+                let value = previous.pool.install(|| row.decompress())?;
+                Ok(value.keyswitch(&previous.rekey))
+            }
+            (None, Some(found)) if found != self.key_id() => Err(CiphertextError::KeyMismatch {
+                expected: self.key_id().to_string(),
+                found: found.to_string(),
+            }),
+            (None, _) => row.decompress(),
+        }
+    }
+}
@@ -1,78 +1,895 @@
 use std::fs;
-use std::path::Path;
-use tfhe::{ConfigBuilder, generate_keys, ClientKey, ServerKey, CompactPublicKey};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use clap::Parser;
+use hmac::Hmac;
+use rand::rngs::OsRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+use tfhe::{Config, ConfigBuilder, ClientKey, CompressedServerKey, ServerKey, CompactPublicKey, KeySwitchingKey};
+use tfhe::zk::CompactPkeCrs;
 use tfhe::shortint::prelude::PARAM_MESSAGE_2_CARRY_2;
-use tfhe::shortint::parameters::COMP_PARAM_MESSAGE_2_CARRY_2;
+use tfhe::shortint::parameters::{COMP_PARAM_MESSAGE_2_CARRY_2, EncryptionKeyChoice, PBSParameters};
+use tfhe::shortint::parameters::classic::gaussian::p_fail_2_minus_64::ks_pbs::V0_11_PARAM_MESSAGE_2_CARRY_2_KS_PBS_GAUSSIAN_2M64;
+use tfhe::shortint::parameters::compact_public_key_only::p_fail_2_minus_64::ks_pbs::V0_11_PARAM_PKE_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M64;
+use tfhe::shortint::parameters::key_switching::p_fail_2_minus_64::ks_pbs::V0_11_PARAM_KEYSWITCH_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M64;
 
-const KEYS_DIR: &str = "keys";
-const CLIENT_KEY_PATH: &str = "keys/client_key.bin";
-const SERVER_KEY_PATH: &str = "keys/server_key.bin";
-//const PUBLIC_KEY_PATH: &str = "keys/public_key.bin";
+pub const DEFAULT_KEYS_DIR: &str = "keys";
+// File names within the keys directory
+const KEY_INFO_FILE: &str = "key_info.toml";
+const CLIENT_KEY_FILE: &str = "client_key.bin";
+const SERVER_KEY_FILE: &str = "server_key.bin";
+// The same key in tfhe's compressed form, what external compute workers download
+const COMPRESSED_SERVER_KEY_FILE: &str = "server_key_compressed.bin";
+const PUBLIC_KEY_FILE: &str = "public_key.bin";
+const CRS_FILE: &str = "crs.bin";
+const SHARES_DIR: &str = "shares";
+/// Key set being rotated to, generated by `rotate_keys`
+pub const NEXT_DIR: &str = "next";
+/// Key sets rotated away from, one directory per key ID
+pub const RETIRED_DIR: &str = "retired";
+// Switches ciphertexts from the current key set to the one in NEXT_DIR
+const REKEY_FILE: &str = "rekey.bin";
+// Generated keys, the client key aside, without the key info stamped on them
+const KEY_FILES: [&str; 4] = [SERVER_KEY_FILE, PUBLIC_KEY_FILE, CRS_FILE, SHARES_DIR];
+/// What the server loads from the keys directory. The client key stays
+/// offline and each key share with the decryptor holding it
+pub const SERVER_FILES: [&str; 5] = [
+    KEY_INFO_FILE, SERVER_KEY_FILE, COMPRESSED_SERVER_KEY_FILE, PUBLIC_KEY_FILE, CRS_FILE,
+];
+// Any DECRYPTION_THRESHOLD of the DECRYPTION_PARTIES decryptors can decrypt
+const DECRYPTION_PARTIES: usize = 3;
+const DECRYPTION_THRESHOLD: usize = 2;
+// Width of the uniform noise each party adds to its partial decryption so the
+// combiner does not learn exact inner products. Far below the 2^58 rounding
+// margin of the 2_2 parameters, so decoding is unaffected.
+const FLOODING_NOISE_BITS: u32 = 40;
+// Largest plaintext a single proven list may carry, one FheUint256
+const CRS_MAX_BITS: usize = 256;
+/// Passphrase the secret key files are encrypted under. Only read from the
+/// environment, so it never shows in a process listing or config file.
+pub const PASSPHRASE_ENV: &str = "FHE_KEY_PASSPHRASE";
+/// Set to 1 to write secret key files in plaintext, and to read them so
+/// while protection is configured. Both are refused otherwise.
+pub const ALLOW_PLAINTEXT_ENV: &str = "FHE_ALLOW_PLAINTEXT_KEYS";
+// Starts every encrypted key file, bincode keys never begin with it
+const SEALED_MAGIC: &[u8; 8] = b"FHESEAL1";
+// OWASP's recommendation for PBKDF2-HMAC-SHA256
+#[cfg(not(test))]
+const PBKDF2_ROUNDS: u32 = 600_000;
+// Files record their rounds, so tests can seal with fewer
+#[cfg(test)]
+const PBKDF2_ROUNDS: u32 = 1_000;
+
+#[derive(Parser, Debug)]
+#[command(about = "Generates the FHE key set, threshold key shares and CRS")]
+struct Args {
+    /// Directory the keys are written to, the same variable the server reads
+    #[arg(long, env = "FHE_KEYS_DIR", default_value = DEFAULT_KEYS_DIR)]
+    keys_dir: PathBuf,
+    /// FHE parameters of a new key set: message-2-carry-2 or message-2-carry-2-gaussian
+    #[arg(long, env = "FHE_PARAMETER_SET", default_value_t = ParameterSet::default())]
+    parameter_set: ParameterSet,
+    /// Master key file wrapping the key files' data keys, created if missing.
+    /// Set FHE_KEY_PASSPHRASE instead to encrypt them under a passphrase
+    #[arg(long, env = "FHE_KMS_KEY_FILE")]
+    kms_key_file: Option<PathBuf>,
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let dir = args.keys_dir.as_path();
 
-    println!("checking keys...");
-    if !Path::new(KEYS_DIR).exists() {
-        fs::create_dir(KEYS_DIR)?;
+    println!("checking keys in {}...", dir.display());
+    if !dir.exists() {
+        fs::create_dir_all(dir)?;
     }
 
-    if Path::new(CLIENT_KEY_PATH).exists() && 
-       Path::new(SERVER_KEY_PATH).exists() {
-        println!("Keys already exist. Skipping key generation.");
+    let kms = match &args.kms_key_file {
+        Some(path) if !path.exists() => {
+            let kms = FileKms::create(path)?;
+            println!("Created KMS key {} in {}", kms.key_id(), path.display());
+            Some(kms)
+        }
+        Some(path) => Some(FileKms::open(path)?),
+        None => None,
+    };
+    let protection = KeyProtection::resolve(kms)?;
+
+    let keys_exist = dir.join(CLIENT_KEY_FILE).exists() &&
+        KEY_FILES.iter().all(|file| dir.join(file).exists());
+    if keys_exist {
+        if dir.join(KEY_INFO_FILE).exists() {
+            let info = load_key_info(dir)?;
+            println!("Keys {} already exist. Skipping key generation.", info);
+            if info.parameter_set != args.parameter_set {
+                println!("Remove {} to generate {} keys instead", dir.display(), args.parameter_set);
+            }
+        } else {
+            // Keys generated before key sets were stamped all used the default parameters
+            let info = KeyInfo::new(ParameterSet::default());
+            save_key_info(dir, &info)?;
+            println!("Stamped existing keys as {}", info);
+        }
+        // Sealed first, plaintext files no longer load once protection is configured
+        let sealed = seal_existing(dir, &protection)?;
+        if sealed > 0 {
+            println!("Encrypted {} existing key files with {}", sealed, protection);
+        }
+        if !dir.join(COMPRESSED_SERVER_KEY_FILE).exists() {
+            // Keys from before it was kept. Freshly generated, it is a different
+            // but equivalent server key for the same client key
+            save_compressed_server_key(dir, &CompressedServerKey::new(&load_client_key(dir, &protection)?))?;
+            println!("Generated the compressed server key of the existing keys");
+        }
         Ok(())
     } else {
-        println!("Generating new keys...");
-        let config = tfhe::ConfigBuilder::with_custom_parameters(PARAM_MESSAGE_2_CARRY_2)
-        .enable_compression(COMP_PARAM_MESSAGE_2_CARRY_2).build();
-        let client_key = tfhe::ClientKey::generate(config);
-        let server_key = tfhe::ServerKey::new(&client_key);
-        // add public key
-        save_client_key(&client_key)?;
-        save_server_key(&server_key)?;
-        println!("Keys generated successfully.");
+        println!("Generating new {} keys...", args.parameter_set);
+        let info = generate_key_set(dir, args.parameter_set, &protection)?;
+        println!("Keys {} generated successfully, secret key files {}.", info, match protection {
+            KeyProtection::None => "unencrypted".to_string(),
+            _ => format!("encrypted with {}", protection),
+        });
         Ok(())
     }
 }
 
-fn save_client_key(key: &ClientKey) -> Result<(), String> {
-    let buffer = bincode::serialize(key)
-        .map_err(|e| format!("Failed to serialize client key: {}", e))?;
-    fs::write(CLIENT_KEY_PATH, buffer)
+/// Generates a complete key set with threshold shares into `dir`.
+pub fn generate_key_set(dir: &Path, parameter_set: ParameterSet, protection: &KeyProtection) -> Result<KeyInfo, String> {
+    fs::create_dir_all(dir)
+        .map_err(|e| format!("Failed to create keys directory: {}", e))?;
+    let config = parameter_set.config();
+    let client_key = ClientKey::generate(config);
+    let compressed_server_key = CompressedServerKey::new(&client_key);
+    let server_key = compressed_server_key.decompress();
+    let public_key = CompactPublicKey::try_new(&client_key)
+        .map_err(|e| format!("Failed to generate public key: {}", e))?;
+    let crs = CompactPkeCrs::from_config(config, CRS_MAX_BITS)
+        .map_err(|e| format!("Failed to generate CRS: {}", e))?;
+    save_client_key(dir, &client_key, protection)?;
+    save_server_key(dir, &server_key)?;
+    save_compressed_server_key(dir, &compressed_server_key)?;
+    save_public_key(dir, &public_key)?;
+    save_crs(dir, &crs)?;
+    let shares = split_client_key(&client_key, DECRYPTION_PARTIES, DECRYPTION_THRESHOLD)?;
+    save_key_shares(dir, &shares, protection)?;
+    // Written last, a key set without it is incomplete
+    let info = KeyInfo::new(parameter_set);
+    save_key_info(dir, &info)?;
+    Ok(info)
+}
+
+/// Generates the key switching key from the key set in `from` to the one in
+/// `to` and saves it in `to`. tfhe only switches between key sets of the
+/// same parameters, so returns false without one otherwise.
+pub fn generate_rekey(from: &Path, to: &Path, protection: &KeyProtection) -> Result<bool, String> {
+    if load_key_info(from)?.parameter_set != load_key_info(to)?.parameter_set {
+        return Ok(false);
+    }
+    let rekey = KeySwitchingKey::new(
+        (&load_client_key(from, protection)?, &load_server_key(from)?),
+        (&load_client_key(to, protection)?, &load_server_key(to)?),
+    ).map_err(|e| format!("Failed to generate key switching key: {}", e))?;
+    let buffer = bincode::serialize(&rekey)
+        .map_err(|e| format!("Failed to serialize key switching key: {}", e))?;
+    fs::write(to.join(REKEY_FILE), buffer)
+        .map_err(|e| format!("Failed to save key switching key: {}", e))?;
+    Ok(true)
+}
+
+/// The key switching key into the key set in `dir`, if it has one.
+pub fn load_rekey(dir: &Path) -> Result<Option<KeySwitchingKey>, String> {
+    let path = dir.join(REKEY_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(path)
+        .map_err(|e| format!("Failed to read key switching key: {}", e))?;
+    bincode::deserialize(&data)
+        .map(Some)
+        .map_err(|e| format!("Failed to deserialize key switching key: {}", e))
+}
+
+/// Whether the key set in `dir` has a key switching key into it.
+pub fn has_rekey(dir: &Path) -> bool {
+    dir.join(REKEY_FILE).exists()
+}
+
+/// Whether a complete key set is waiting in `dir`'s `next` directory.
+pub fn has_next(dir: &Path) -> bool {
+    dir.join(NEXT_DIR).join(KEY_INFO_FILE).exists()
+}
+
+/// Moves the key set in `dir` to `retired/<key ID>` and the one in `next`
+/// in its place. Files already moved are skipped, so an interrupted run
+/// can be repeated.
+pub fn promote_next(dir: &Path) -> Result<(), String> {
+    let next = dir.join(NEXT_DIR);
+    if !has_next(dir) {
+        return Err(format!("{} holds no complete key set", next.display()));
+    }
+    let files = [
+        CLIENT_KEY_FILE, SERVER_KEY_FILE, COMPRESSED_SERVER_KEY_FILE, PUBLIC_KEY_FILE, CRS_FILE, SHARES_DIR, KEY_INFO_FILE,
+    ];
+    // The key info moves last either way, it marks where a set is complete
+    if dir.join(KEY_INFO_FILE).exists() {
+        let retired = dir.join(RETIRED_DIR).join(load_key_info(dir)?.key_id);
+        fs::create_dir_all(&retired)
+            .map_err(|e| format!("Failed to create retired keys directory: {}", e))?;
+        move_files(dir, &retired, &files)?;
+    }
+    move_files(&next, dir, &files)?;
+    fs::remove_dir_all(&next)
+        .map_err(|e| format!("Failed to remove {}: {}", next.display(), e))
+}
+
+fn move_files(from: &Path, to: &Path, files: &[&str]) -> Result<(), String> {
+    for file in files {
+        let source = from.join(file);
+        if source.exists() {
+            fs::rename(&source, to.join(file))
+                .map_err(|e| format!("Failed to move {}: {}", source.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// The FHE parameters a key set can be generated with.
+///
+/// All use KS-PBS on 2-bit messages, which threshold decryption and the
+/// compact public key parameters require, with a 2^-64 failure probability,
+/// the lowest tfhe 0.11 offers for them. Multi-bit PBS is not offered since
+/// tfhe 0.11 cannot compress its ciphertexts for storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParameterSet {
+    /// TUniform noise, tfhe's default
+    #[default]
+    #[serde(rename = "message-2-carry-2")]
+    Message2Carry2,
+    /// Gaussian noise, the distribution earlier tfhe defaults used
+    #[serde(rename = "message-2-carry-2-gaussian")]
+    Message2Carry2Gaussian,
+}
+
+impl FromStr for ParameterSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "message-2-carry-2" => Ok(ParameterSet::Message2Carry2),
+            "message-2-carry-2-gaussian" => Ok(ParameterSet::Message2Carry2Gaussian),
+            other if other.starts_with("multi-bit") => Err(
+                "multi-bit parameter sets are unsupported, tfhe 0.11 cannot compress their ciphertexts".to_string()
+            ),
+            other => Err(format!(
+                "unknown parameter set {:?}, expected message-2-carry-2 or message-2-carry-2-gaussian", other
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ParameterSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterSet::Message2Carry2 => write!(f, "message-2-carry-2"),
+            ParameterSet::Message2Carry2Gaussian => write!(f, "message-2-carry-2-gaussian"),
+        }
+    }
+}
+
+impl ParameterSet {
+    fn compute_parameters(&self) -> PBSParameters {
+        match self {
+            ParameterSet::Message2Carry2 => PARAM_MESSAGE_2_CARRY_2.into(),
+            ParameterSet::Message2Carry2Gaussian => V0_11_PARAM_MESSAGE_2_CARRY_2_KS_PBS_GAUSSIAN_2M64.into(),
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        // The compact public key uses dedicated encryption parameters, client
+        // ciphertexts are key-switched to the compute parameters on expansion.
+        // Every set shares the 2048-coefficient GLWE these switch and compress into.
+        ConfigBuilder::with_custom_parameters(self.compute_parameters())
+        .use_dedicated_compact_public_key_parameters((
+            V0_11_PARAM_PKE_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M64,
+            V0_11_PARAM_KEYSWITCH_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M64,
+        ))
+        .enable_compression(COMP_PARAM_MESSAGE_2_CARRY_2).build()
+    }
+
+    /// Hex SHA-256 of the serialized parameters, changes if a tfhe upgrade
+    /// alters the values behind the same name.
+    pub fn fingerprint(&self) -> String {
+        let encoded = bincode::serialize(&self.config()).expect("parameters serialize");
+        to_hex(&Sha256::digest(encoded))
+    }
+}
+
+/// Identity of a key set, written next to its keys. Every stored ciphertext
+/// records the `key_id` it was encrypted under.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyInfo {
+    pub key_id: String,
+    pub parameter_set: ParameterSet,
+    pub fingerprint: String,
+}
+
+impl KeyInfo {
+    fn new(parameter_set: ParameterSet) -> Self {
+        let id: [u8; 8] = rand::thread_rng().gen();
+        Self { key_id: to_hex(&id), parameter_set, fingerprint: parameter_set.fingerprint() }
+    }
+}
+
+impl std::fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.key_id, self.parameter_set)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn save_key_info(dir: &Path, info: &KeyInfo) -> Result<(), String> {
+    let contents = toml::to_string_pretty(info)
+        .map_err(|e| format!("Failed to serialize key info: {}", e))?;
+    fs::write(dir.join(KEY_INFO_FILE), contents)
+        .map_err(|e| format!("Failed to save key info: {}", e))
+}
+
+/// Loads the key info and checks its fingerprint against this build's
+/// parameters, so keys are never used with parameters they were not made for.
+pub fn load_key_info(dir: &Path) -> Result<KeyInfo, String> {
+    let contents = fs::read_to_string(dir.join(KEY_INFO_FILE))
+        .map_err(|e| format!("Failed to read key info: {}", e))?;
+    let info: KeyInfo = toml::from_str(&contents)
+        .map_err(|e| format!("Failed to parse key info: {}", e))?;
+    if info.fingerprint != info.parameter_set.fingerprint() {
+        return Err(format!(
+            "Key set {} was generated with different {} parameters than this build uses", info.key_id, info.parameter_set
+        ));
+    }
+    Ok(info)
+}
+
+/// One decryptor's portion of the secret LWE key.
+///
+/// The key is split with replicated additive sharing: there is one additive
+/// sub-share per set of `threshold - 1` parties, held by every party outside
+/// that set. Any `threshold` parties jointly hold all sub-shares while fewer
+/// always miss at least one.
+#[derive(Serialize, Deserialize)]
+pub struct KeyShare {
+    pub party: usize,
+    pub parties: usize,
+    pub threshold: usize,
+    /// `(index, coefficients)` of every sub-share this party holds
+    pub sub_shares: Vec<(usize, Vec<u64>)>,
+}
+
+// Shares live as long as their decryptor, their coefficients are wiped when dropped
+impl Drop for KeyShare {
+    fn drop(&mut self) {
+        for (_, coefficients) in &mut self.sub_shares {
+            coefficients.zeroize();
+        }
+    }
+}
+
+impl KeyShare {
+    /// Sum of `<mask, sub_share>` over the requested sub-shares, one value per
+    /// block, flooded with noise.
+    ///
+    /// The caller subtracts the partials of all parties from the ciphertext
+    /// body to recover the noisy plaintext.
+    pub fn partial_decrypt(&self, masks: &[Vec<u64>], sub_shares: &[usize]) -> Vec<u64> {
+        let mut rng = rand::thread_rng();
+        masks.iter().map(|mask| {
+            let partial = self.sub_shares.iter()
+                .filter(|(index, _)| sub_shares.contains(index))
+                .fold(0u64, |acc, (_, coefficients)| {
+                    mask.iter().zip(coefficients).fold(acc, |acc, (a, s)| acc.wrapping_add(a.wrapping_mul(*s)))
+                });
+            partial.wrapping_add(rng.gen::<u64>() >> (64 - FLOODING_NOISE_BITS))
+        }).collect()
+    }
+}
+
+fn split_client_key(client_key: &ClientKey, parties: usize, threshold: usize) -> Result<Vec<KeyShare>, String> {
+    if threshold == 0 || threshold > parties {
+        return Err(format!("Invalid decryption threshold {} of {}", threshold, parties));
+    }
+    let (integer_key, _, _, _) = client_key.clone().into_raw_parts();
+    let (glwe_secret_key, _, parameters) = integer_key.into_raw_parts().into_raw_parts();
+    if parameters.encryption_key_choice() != EncryptionKeyChoice::Big {
+        return Err("Threshold decryption requires KS-PBS parameters".to_string());
+    }
+    // KS-PBS ciphertexts decrypt under the GLWE key viewed as an LWE key
+    let secret = glwe_secret_key.as_lwe_secret_key().as_ref().to_vec();
+
+    let unqualified_sets = subsets(0, parties, threshold - 1);
+    let mut rng = rand::thread_rng();
+    let mut remainder = secret;
+    let mut sub_shares: Vec<Vec<u64>> = Vec::with_capacity(unqualified_sets.len());
+    for _ in 1..unqualified_sets.len() {
+        let sub_share: Vec<u64> = remainder.iter().map(|_| rng.gen()).collect();
+        remainder.iter_mut().zip(&sub_share).for_each(|(r, s)| *r = r.wrapping_sub(*s));
+        sub_shares.push(sub_share);
+    }
+    sub_shares.push(remainder);
+
+    let shares = (0..parties).map(|party| KeyShare {
+        party,
+        parties,
+        threshold,
+        sub_shares: unqualified_sets.iter().zip(&sub_shares).enumerate()
+            .filter(|(_, (set, _))| !set.contains(&party))
+            .map(|(index, (_, sub_share))| (index, sub_share.clone()))
+            .collect(),
+    }).collect();
+    sub_shares.iter_mut().for_each(|sub_share| sub_share.zeroize());
+    Ok(shares)
+}
+
+/// Indices of the sub-shares `party` holds when the key is split between
+/// `parties` with `threshold`, the same for every key set.
+pub fn held_sub_shares(party: usize, parties: usize, threshold: usize) -> Vec<usize> {
+    subsets(0, parties, threshold.saturating_sub(1)).iter().enumerate()
+        .filter(|(_, set)| !set.contains(&party))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// All subsets of `size` parties drawn from `start..parties`, in lexicographic order
+fn subsets(start: usize, parties: usize, size: usize) -> Vec<Vec<usize>> {
+    if size == 0 {
+        return vec![Vec::new()];
+    }
+    (start..parties).flat_map(|first| {
+        subsets(first + 1, parties, size - 1).into_iter().map(move |mut rest| {
+            rest.insert(0, first);
+            rest
+        })
+    }).collect()
+}
+
+fn share_file(party: usize) -> String {
+    format!("share_{}.bin", party)
+}
+
+fn save_key_shares(dir: &Path, shares: &[KeyShare], protection: &KeyProtection) -> Result<(), String> {
+    let shares_dir = dir.join(SHARES_DIR);
+    fs::create_dir_all(&shares_dir)
+        .map_err(|e| format!("Failed to create shares directory: {}", e))?;
+    for share in shares {
+        let buffer = Zeroizing::new(bincode::serialize(share)
+            .map_err(|e| format!("Failed to serialize key share: {}", e))?);
+        let name = share_file(share.party);
+        write_private(&shares_dir.join(&name), &seal(protection, &name, &buffer)?)
+            .map_err(|e| format!("Failed to save key share: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Loads the share of `party`, what the decryptor of that party holds.
+pub fn load_key_share(dir: &Path, party: usize, protection: &KeyProtection) -> Result<KeyShare, String> {
+    let name = share_file(party);
+    let data = fs::read(dir.join(SHARES_DIR).join(&name))
+        .map_err(|e| format!("Failed to read key share {}: {}", party, e))?;
+    let data = open(protection, &name, data)?;
+    let share: KeyShare = bincode::deserialize(&data)
+        .map_err(|e| format!("Failed to deserialize key share: {}", e))?;
+    if share.party != party {
+        return Err(format!("{} holds the share of party {}", name, share.party));
+    }
+    Ok(share)
+}
+
+fn save_client_key(dir: &Path, key: &ClientKey, protection: &KeyProtection) -> Result<(), String> {
+    let buffer = Zeroizing::new(bincode::serialize(key)
+        .map_err(|e| format!("Failed to serialize client key: {}", e))?);
+    write_private(&dir.join(CLIENT_KEY_FILE), &seal(protection, CLIENT_KEY_FILE, &buffer)?)
         .map_err(|e| format!("Failed to save client key: {}", e))?;
     Ok(())
 }
 
-fn save_server_key(key: &ServerKey) -> Result<(), String> {
+fn save_server_key(dir: &Path, key: &ServerKey) -> Result<(), String> {
     let buffer = bincode::serialize(key)
         .map_err(|e| format!("Failed to serialize server key: {}", e))?;
-    fs::write(SERVER_KEY_PATH, buffer)
+    fs::write(dir.join(SERVER_KEY_FILE), buffer)
         .map_err(|e| format!("Failed to save server key: {}", e))?;
     Ok(())
 }
 
-// fn save_public_key(key: &CompactPublicKey) -> Result<(), String> {
-//     let buffer = bincode::serialize(key)
-//         .map_err(|e| format!("Failed to serialize public key: {}", e))?;
-//     fs::write(PUBLIC_KEY_PATH, buffer)
-//         .map_err(|e| format!("Failed to save public key: {}", e))?;
-//     Ok(())
-// }
+fn save_compressed_server_key(dir: &Path, key: &CompressedServerKey) -> Result<(), String> {
+    let buffer = bincode::serialize(key)
+        .map_err(|e| format!("Failed to serialize compressed server key: {}", e))?;
+    fs::write(dir.join(COMPRESSED_SERVER_KEY_FILE), buffer)
+        .map_err(|e| format!("Failed to save compressed server key: {}", e))?;
+    Ok(())
+}
 
-// pub fn load_public_key() -> Result<CompactPublicKey, String> {
-//     let data = fs::read(PUBLIC_KEY_PATH).map_err(|e| e.to_string())?;
-//     bincode::deserialize(&data).map_err(|e| e.to_string())
-// }
+fn save_public_key(dir: &Path, key: &CompactPublicKey) -> Result<(), String> {
+    let buffer = bincode::serialize(key)
+        .map_err(|e| format!("Failed to serialize public key: {}", e))?;
+    fs::write(dir.join(PUBLIC_KEY_FILE), buffer)
+        .map_err(|e| format!("Failed to save public key: {}", e))?;
+    Ok(())
+}
+
+fn save_crs(dir: &Path, crs: &CompactPkeCrs) -> Result<(), String> {
+    let buffer = bincode::serialize(crs)
+        .map_err(|e| format!("Failed to serialize CRS: {}", e))?;
+    fs::write(dir.join(CRS_FILE), buffer)
+        .map_err(|e| format!("Failed to save CRS: {}", e))?;
+    Ok(())
+}
 
-pub fn load_client_key() -> Result<ClientKey, String> {
-    let data = fs::read(CLIENT_KEY_PATH)
+pub fn load_crs(dir: &Path) -> Result<CompactPkeCrs, String> {
+    let data = fs::read(dir.join(CRS_FILE))
+        .map_err(|e| format!("Failed to read CRS: {}", e))?;
+    bincode::deserialize(&data)
+        .map_err(|e| format!("Failed to deserialize CRS: {}", e))
+}
+
+pub fn load_public_key(dir: &Path) -> Result<CompactPublicKey, String> {
+    let data = fs::read(dir.join(PUBLIC_KEY_FILE))
+        .map_err(|e| format!("Failed to read public key: {}", e))?;
+    bincode::deserialize(&data)
+        .map_err(|e| format!("Failed to deserialize public key: {}", e))
+}
+
+pub fn load_client_key(dir: &Path, protection: &KeyProtection) -> Result<ClientKey, String> {
+    let data = fs::read(dir.join(CLIENT_KEY_FILE))
         .map_err(|e| format!("Failed to read client key: {}", e))?;
-    
+    let data = open(protection, CLIENT_KEY_FILE, data)?;
     bincode::deserialize(&data)
         .map_err(|e| format!("Failed to deserialize client key: {}", e))
 }
 
-pub fn load_server_key() -> Result<ServerKey, String> {
-    let data = fs::read(SERVER_KEY_PATH)
+/// The bincode `CompressedServerKey` as stored, to be served without
+/// deserializing it first.
+pub fn read_compressed_server_key(dir: &Path) -> Result<Vec<u8>, String> {
+    fs::read(dir.join(COMPRESSED_SERVER_KEY_FILE))
+        .map_err(|e| format!("Failed to read compressed server key: {}", e))
+}
+
+pub fn load_server_key(dir: &Path) -> Result<ServerKey, String> {
+    let data = fs::read(dir.join(SERVER_KEY_FILE))
         .map_err(|e| format!("Failed to read server key: {}", e))?;
     bincode::deserialize(&data).map_err(|e| e.to_string())
-}
\ No newline at end of file
+}
+
+/// How the secret key files, the client key and the key shares, are
+/// encrypted at rest. The server key, public key and CRS stay plaintext.
+pub enum KeyProtection {
+    /// Written as is, only with ALLOW_PLAINTEXT_ENV set, how keys were
+    /// stored before they could be encrypted
+    None,
+    /// XChaCha20-Poly1305 under a key derived from the passphrase with PBKDF2
+    Passphrase(Zeroizing<String>),
+    /// XChaCha20-Poly1305 under a random data key per file, wrapped by a KMS key
+    Kms(FileKms),
+}
+
+impl KeyProtection {
+    /// The KMS key if given, otherwise the passphrase in FHE_KEY_PASSPHRASE
+    /// if set. Using both is refused, it would be unclear which one applies.
+    pub fn resolve(kms: Option<FileKms>) -> Result<Self, String> {
+        let passphrase = std::env::var(PASSPHRASE_ENV).ok()
+            .filter(|passphrase| !passphrase.is_empty())
+            .map(Zeroizing::new);
+        match (kms, passphrase) {
+            (Some(_), Some(_)) => Err(format!("Set either a KMS key file or {}, not both", PASSPHRASE_ENV)),
+            (Some(kms), None) => Ok(KeyProtection::Kms(kms)),
+            (None, Some(passphrase)) => Ok(KeyProtection::Passphrase(passphrase)),
+            (None, None) => Ok(KeyProtection::None),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyProtection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyProtection::None => write!(f, "no encryption"),
+            KeyProtection::Passphrase(_) => write!(f, "the passphrase"),
+            KeyProtection::Kms(kms) => write!(f, "KMS key {}", kms.key_id),
+        }
+    }
+}
+
+/// Local stand-in for a key management service. Its master key, kept in a
+/// file, only ever wraps and unwraps data keys, which is the part a real
+/// KMS would perform without releasing the master key.
+pub struct FileKms {
+    key_id: String,
+    master_key: Zeroizing<[u8; 32]>,
+}
+
+impl FileKms {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let data = Zeroizing::new(fs::read(path)
+            .map_err(|e| format!("Failed to read KMS key {}: {}", path.display(), e))?);
+        if data.len() != 32 {
+            return Err(format!("KMS key {} is not a 32-byte key", path.display()));
+        }
+        let mut master_key = Zeroizing::new([0u8; 32]);
+        master_key.copy_from_slice(&data);
+        Ok(Self::from_key(master_key))
+    }
+
+    /// Generates a new master key into `path`.
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let mut master_key = Zeroizing::new([0u8; 32]);
+        OsRng.fill(&mut master_key[..]);
+        write_private(path, master_key.as_slice())
+            .map_err(|e| format!("Failed to save KMS key {}: {}", path.display(), e))?;
+        Ok(Self::from_key(master_key))
+    }
+
+    fn from_key(master_key: Zeroizing<[u8; 32]>) -> Self {
+        // Names the master key in sealed files without revealing it
+        let key_id = to_hex(&Sha256::digest(master_key.as_slice())[..8]);
+        Self { key_id, master_key }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn wrap(&self, data_key: &[u8; 32], name: &str) -> Result<([u8; 24], Vec<u8>), String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped_key = XChaCha20Poly1305::new(Key::from_slice(self.master_key.as_slice()))
+            .encrypt(&nonce, Payload { msg: data_key, aad: name.as_bytes() })
+            .map_err(|_| format!("Failed to wrap the data key of {}", name))?;
+        Ok((nonce.into(), wrapped_key))
+    }
+
+    fn unwrap(&self, nonce: &[u8; 24], wrapped_key: &[u8], name: &str) -> Result<Zeroizing<[u8; 32]>, String> {
+        let data_key = Zeroizing::new(XChaCha20Poly1305::new(Key::from_slice(self.master_key.as_slice()))
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: wrapped_key, aad: name.as_bytes() })
+            .map_err(|_| format!("Failed to unwrap the data key of {}, the file is corrupted", name))?);
+        let mut key = Zeroizing::new([0u8; 32]);
+        if data_key.len() != key.len() {
+            return Err(format!("The data key of {} has the wrong length", name));
+        }
+        key.copy_from_slice(&data_key);
+        Ok(key)
+    }
+}
+
+/// An encrypted key file after SEALED_MAGIC. The file name is the
+/// associated data, so files cannot be swapped for one another.
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    key: SealingKey,
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+/// Where the key a file is sealed under comes from.
+#[derive(Serialize, Deserialize)]
+enum SealingKey {
+    Passphrase { salt: [u8; 16], rounds: u32 },
+    Kms { key_id: String, nonce: [u8; 24], wrapped_key: Vec<u8> },
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, key.as_mut_slice());
+    key
+}
+
+fn plaintext_allowed() -> bool {
+    std::env::var(ALLOW_PLAINTEXT_ENV).is_ok_and(|value| value == "1" || value == "true")
+}
+
+/// Encrypts `plaintext`, the contents of the key file `name`. Without
+/// protection the file is only written as is if ALLOW_PLAINTEXT_ENV is set.
+fn seal(protection: &KeyProtection, name: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let (key, sealing_key) = match protection {
+        KeyProtection::None if plaintext_allowed() => return Ok(plaintext.to_vec()),
+        KeyProtection::None => {
+            return Err(format!(
+                "Refusing to write {} in plaintext, set {} or a KMS key file, or {}=1 to allow it",
+                name, PASSPHRASE_ENV, ALLOW_PLAINTEXT_ENV
+            ));
+        }
+        KeyProtection::Passphrase(passphrase) => {
+            let salt: [u8; 16] = OsRng.gen();
+            let key = derive_key(passphrase, &salt, PBKDF2_ROUNDS);
+            (key, SealingKey::Passphrase { salt, rounds: PBKDF2_ROUNDS })
+        }
+        KeyProtection::Kms(kms) => {
+            let mut key = Zeroizing::new([0u8; 32]);
+            OsRng.fill(&mut key[..]);
+            let (nonce, wrapped_key) = kms.wrap(&key, name)?;
+            (key, SealingKey::Kms { key_id: kms.key_id.clone(), nonce, wrapped_key })
+        }
+    };
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(key.as_slice()))
+        .encrypt(&nonce, Payload { msg: plaintext, aad: name.as_bytes() })
+        .map_err(|_| format!("Failed to encrypt {}", name))?;
+    let sealed = bincode::serialize(&SealedFile { key: sealing_key, nonce: nonce.into(), ciphertext })
+        .map_err(|e| format!("Failed to serialize {}: {}", name, e))?;
+    Ok([SEALED_MAGIC.as_slice(), &sealed].concat())
+}
+
+/// Decrypts `data`, the contents of the key file `name`. With protection
+/// configured, a plaintext file is refused unless ALLOW_PLAINTEXT_ENV is set,
+/// it may have been swapped in for the encrypted one.
+fn open(protection: &KeyProtection, name: &str, data: Vec<u8>) -> Result<Zeroizing<Vec<u8>>, String> {
+    let data = Zeroizing::new(data);
+    let Some(sealed) = data.strip_prefix(SEALED_MAGIC.as_slice()) else {
+        match protection {
+            KeyProtection::None => {}
+            _ if plaintext_allowed() => println!("Warning: {} is not encrypted, run generate_keys to encrypt it", name),
+            _ => return Err(format!(
+                "{} is not encrypted although {} is configured, run generate_keys to encrypt it or set {}=1",
+                name, protection, ALLOW_PLAINTEXT_ENV
+            )),
+        }
+        return Ok(data);
+    };
+    let sealed: SealedFile = bincode::deserialize(sealed)
+        .map_err(|e| format!("Failed to deserialize {}: {}", name, e))?;
+    let key = match (&sealed.key, protection) {
+        (SealingKey::Passphrase { salt, rounds }, KeyProtection::Passphrase(passphrase)) => {
+            derive_key(passphrase, salt, *rounds)
+        }
+        (SealingKey::Kms { key_id, nonce, wrapped_key }, KeyProtection::Kms(kms)) if *key_id == kms.key_id => {
+            kms.unwrap(nonce, wrapped_key, name)?
+        }
+        (SealingKey::Passphrase { .. }, _) => {
+            return Err(format!("{} is encrypted under a passphrase, set {}", name, PASSPHRASE_ENV));
+        }
+        (SealingKey::Kms { key_id, .. }, _) => {
+            return Err(format!("{} is encrypted under KMS key {}, configure its key file", name, key_id));
+        }
+    };
+    XChaCha20Poly1305::new(Key::from_slice(key.as_slice()))
+        .decrypt(XNonce::from_slice(&sealed.nonce), Payload { msg: &sealed.ciphertext, aad: name.as_bytes() })
+        .map(Zeroizing::new)
+        .map_err(|_| format!("Failed to decrypt {}, wrong passphrase or corrupted file", name))
+}
+
+/// Encrypts the plaintext secret key files in `dir` with `protection`,
+/// returning how many there were.
+fn seal_existing(dir: &Path, protection: &KeyProtection) -> Result<usize, String> {
+    if matches!(protection, KeyProtection::None) {
+        return Ok(0);
+    }
+    let shares = (0..).map(|party| (dir.join(SHARES_DIR), share_file(party)))
+        .take_while(|(shares_dir, name)| shares_dir.join(name).exists());
+    let mut sealed = 0;
+    for (file_dir, name) in std::iter::once((dir.to_path_buf(), CLIENT_KEY_FILE.to_string())).chain(shares) {
+        let path = file_dir.join(&name);
+        let data = Zeroizing::new(fs::read(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?);
+        if data.starts_with(SEALED_MAGIC) {
+            continue;
+        }
+        // Replaced in one rename, so an interruption never leaves half a key
+        let staged = path.with_extension("sealing");
+        write_private(&staged, &seal(protection, &name, &data)?)
+            .and_then(|_| fs::rename(&staged, &path))
+            .map_err(|e| format!("Failed to encrypt {}: {}", path.display(), e))?;
+        sealed += 1;
+    }
+    Ok(sealed)
+}
+
+// Secret key files are created readable by their owner only
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(client_key: &ClientKey) -> Vec<u64> {
+        let (integer_key, _, _, _) = client_key.clone().into_raw_parts();
+        let (glwe_secret_key, _, _) = integer_key.into_raw_parts().into_raw_parts();
+        glwe_secret_key.as_lwe_secret_key().as_ref().to_vec()
+    }
+
+    // The sub-shares `shares` jointly hold, by index
+    fn pooled(shares: &[&KeyShare]) -> std::collections::BTreeMap<usize, Vec<u64>> {
+        shares.iter().flat_map(|share| share.sub_shares.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn any_threshold_of_shares_rebuilds_the_key() {
+        let client_key = ClientKey::generate(ParameterSet::default().config());
+        let secret = secret(&client_key);
+        let shares = split_client_key(&client_key, 3, 2).unwrap();
+
+        // One sub-share per single party, each held by the two others
+        for share in &shares {
+            let held: Vec<usize> = share.sub_shares.iter().map(|(index, _)| *index).collect();
+            assert_eq!(held, held_sub_shares(share.party, 3, 2));
+            assert_eq!(held.len(), 2);
+        }
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            let sub_shares = pooled(&[&shares[a], &shares[b]]);
+            assert_eq!(sub_shares.len(), 3);
+            let sum = sub_shares.values().fold(vec![0u64; secret.len()], |sum, sub_share| {
+                sum.iter().zip(sub_share).map(|(x, y)| x.wrapping_add(*y)).collect()
+            });
+            assert_eq!(sum, secret);
+        }
+        assert!(pooled(&[&shares[0]]).len() < 3);
+    }
+
+    #[test]
+    fn partials_sum_to_the_masked_key() {
+        let client_key = ClientKey::generate(ParameterSet::default().config());
+        let secret = secret(&client_key);
+        let shares = split_client_key(&client_key, 3, 2).unwrap();
+        let mask: Vec<u64> = (0..secret.len()).map(|_| OsRng.gen()).collect();
+        let expected = mask.iter().zip(&secret).fold(0u64, |acc, (a, s)| acc.wrapping_add(a.wrapping_mul(*s)));
+
+        // Parties 0 and 2 split the sub-shares between them, each used once
+        let partials = [
+            shares[0].partial_decrypt(std::slice::from_ref(&mask), &[1, 2]),
+            shares[2].partial_decrypt(std::slice::from_ref(&mask), &[0]),
+        ];
+        let sum = partials.iter().fold(0u64, |acc, partial| acc.wrapping_add(partial[0]));
+        let noise = sum.wrapping_sub(expected);
+        assert!(noise < 2 << FLOODING_NOISE_BITS, "noise {} exceeds the flooding bound", noise);
+    }
+
+    #[test]
+    fn rejects_invalid_thresholds() {
+        let client_key = ClientKey::generate(ParameterSet::default().config());
+        assert!(split_client_key(&client_key, 3, 0).is_err());
+        assert!(split_client_key(&client_key, 3, 4).is_err());
+        assert_eq!(split_client_key(&client_key, 3, 1).unwrap()[0].sub_shares.len(), 1);
+    }
+
+    #[test]
+    fn passphrase_sealed_files_open_with_the_passphrase_only() {
+        let protection = KeyProtection::Passphrase(Zeroizing::new("correct".to_string()));
+        let sealed = seal(&protection, CLIENT_KEY_FILE, b"secret").unwrap();
+        assert!(sealed.starts_with(SEALED_MAGIC));
+
+        assert_eq!(open(&protection, CLIENT_KEY_FILE, sealed.clone()).unwrap().as_slice(), b"secret");
+        assert!(open(&protection, &share_file(0), sealed.clone()).is_err());
+        let wrong = KeyProtection::Passphrase(Zeroizing::new("wrong".to_string()));
+        assert!(open(&wrong, CLIENT_KEY_FILE, sealed.clone()).is_err());
+        assert!(open(&KeyProtection::None, CLIENT_KEY_FILE, sealed).is_err());
+    }
+
+    #[test]
+    fn kms_sealed_files_open_with_the_same_master_key_only() {
+        let kms = KeyProtection::Kms(FileKms::from_key(Zeroizing::new([1; 32])));
+        let sealed = seal(&kms, CLIENT_KEY_FILE, b"secret").unwrap();
+
+        assert_eq!(open(&kms, CLIENT_KEY_FILE, sealed.clone()).unwrap().as_slice(), b"secret");
+        assert!(open(&kms, &share_file(0), sealed.clone()).is_err());
+        let other = KeyProtection::Kms(FileKms::from_key(Zeroizing::new([2; 32])));
+        assert!(open(&other, CLIENT_KEY_FILE, sealed.clone()).is_err());
+        let passphrase = KeyProtection::Passphrase(Zeroizing::new("correct".to_string()));
+        assert!(open(&passphrase, CLIENT_KEY_FILE, sealed).is_err());
+    }
+
+    #[test]
+    fn plaintext_files_open_without_protection() {
+        assert_eq!(open(&KeyProtection::None, CLIENT_KEY_FILE, b"secret".to_vec()).unwrap().as_slice(), b"secret");
+    }
+}
@@ -1,110 +1,201 @@
-use tfhe::prelude::*;
-use tfhe::{ set_server_key, ServerKey, ClientKey, CompressedCiphertextList, CompressedCiphertextListBuilder};
-use std::io::Cursor;
+use tfhe::{ServerKey, CompactPublicKey};
+use tfhe::zk::CompactPkeCrs;
 use axum::{
-    routing::{get, post}, Router, Json, extract::State,
-    http::StatusCode,
+    body::Bytes,
+    routing::{get, post}, Router,
 };
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio_rusqlite::Connection;
-use std::path::Path;
-use std::fs;
 use async_trait::async_trait;
-use tokio::try_join;
+use clap::Parser;
+#[allow(dead_code)]
 mod keys;
 mod types;
+mod auth;
+mod ciphertext;
+mod compute;
+mod sealing;
 mod operations;
 mod handlers;
+mod ingest;
+mod threshold;
 mod cache;
-use handlers::{handle_post, handle_transfer, handle_view, handle_withdraw};
-use crate::operations::{init_db, update_ciphertext, get_ciphertext, insert_ciphertext};
-
-const DB_PATH: &str = "data/tfhe.db";
-const zero_key: [u8; 32] = [0u8; 32];
+mod store;
+mod config;
+mod error;
+mod keyring;
+mod executor;
+mod jobs;
+use handlers::{handle_post, handle_post_ciphertext, handle_public_key, handle_server_key, handle_crs, handle_key_info, handle_transfer, handle_op, handle_graph, handle_view, handle_reencrypt, handle_withdraw, handle_cache_stats, handle_compute_stats};
+use handlers::{seed_zero, run_job, handle_job, handle_post_job, handle_post_ciphertext_job, handle_transfer_job, handle_op_job, handle_graph_job, handle_withdraw_job};
+use crate::cache::Cache;
+use crate::config::{Args, Config};
+use crate::executor::ComputeExecutor;
+use crate::jobs::Jobs;
+use crate::keyring::KeyRing;
+use crate::store::CiphertextStore;
+use crate::threshold::ThresholdDecryptor;
 
 #[derive(Clone)]
 struct AppState {
-    db: Arc<Connection>,
+    store: Arc<dyn CiphertextStore>,
     server_key: Arc<ServerKey>,
-    client_key: Arc<ClientKey>,
+    compressed_server_key: Bytes,
+    decryptor: Arc<ThresholdDecryptor>,
+    public_key: Arc<CompactPublicKey>,
+    crs: Arc<CompactPkeCrs>,
+    cache: Arc<Cache>,
+    executor: Arc<ComputeExecutor>,
+    jobs: Arc<Jobs>,
+    keys: Arc<KeyRing>,
+    require_handle_origin: bool,
+    allow_plaintext_deposits: bool,
 }
 
 #[async_trait]
 pub trait KeyAccess {
     fn get_server_key(&self) -> Arc<ServerKey>;
-    fn get_client_key(&self) -> Arc<ClientKey>;
+    fn get_compressed_server_key(&self) -> Bytes;
+    fn get_decryptor(&self) -> Arc<ThresholdDecryptor>;
+    fn get_public_key(&self) -> Arc<CompactPublicKey>;
+    fn get_crs(&self) -> Arc<CompactPkeCrs>;
+    fn get_cache(&self) -> Arc<Cache>;
+    fn get_store(&self) -> Arc<dyn CiphertextStore>;
+    fn get_keys(&self) -> Arc<KeyRing>;
+    fn get_executor(&self) -> Arc<ComputeExecutor>;
+    fn get_jobs(&self) -> Arc<Jobs>;
+    fn requires_handle_origin(&self) -> bool;
+    fn allows_plaintext_deposits(&self) -> bool;
 }
 
 impl KeyAccess for AppState {
     fn get_server_key(&self) -> Arc<ServerKey> {
         self.server_key.clone()
     }
-    fn get_client_key(&self) -> Arc<ClientKey> {
-        self.client_key.clone()
+    fn get_compressed_server_key(&self) -> Bytes {
+        self.compressed_server_key.clone()
+    }
+    fn get_decryptor(&self) -> Arc<ThresholdDecryptor> {
+        self.decryptor.clone()
+    }
+    fn get_public_key(&self) -> Arc<CompactPublicKey> {
+        self.public_key.clone()
+    }
+    fn get_crs(&self) -> Arc<CompactPkeCrs> {
+        self.crs.clone()
+    }
+    fn get_cache(&self) -> Arc<Cache> {
+        self.cache.clone()
+    }
+    fn get_store(&self) -> Arc<dyn CiphertextStore> {
+        self.store.clone()
+    }
+    fn get_keys(&self) -> Arc<KeyRing> {
+        self.keys.clone()
+    }
+    fn get_executor(&self) -> Arc<ComputeExecutor> {
+        self.executor.clone()
+    }
+    fn get_jobs(&self) -> Arc<Jobs> {
+        self.jobs.clone()
+    }
+    fn requires_handle_origin(&self) -> bool {
+        self.require_handle_origin
+    }
+    fn allows_plaintext_deposits(&self) -> bool {
+        self.allow_plaintext_deposits
     }
-}
-
-////////////////// Request structs //////////////////
-
-#[derive(Deserialize)]
-struct Request {
-    key: [u8; 32],
-    value: u64,
-}
-
-#[derive(Deserialize)]
-struct Transfer {
-    sender_key: [u8; 32],
-    recipient_key: [u8; 32],
-    transfer_value: [u8; 32],
-}
-
-#[derive(Deserialize)]
-struct Withdraw {
-    key: [u8; 32],
-    value: [u8;32]
-}
-
-#[derive(Deserialize)]
-struct Decrypt {
-    key: [u8; 32],
-}
-
-//////////////////////// Response Structs ///////////////////////////
-
-#[derive(Serialize)]
-struct ViewResponse {
-    result: u64,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if !Path::new("data").exists() {
-        fs::create_dir("data").expect("Failed to create data directory");
+    let args = Args::parse();
+    let config = Config::load(&args).map_err(|e| e.to_string())?;
+    // Printed before validation, so an invalid configuration can be inspected
+    if args.print_config {
+        print!("{}", config.to_toml().map_err(|e| e.to_string())?);
+        config.validate().map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+    config.validate().map_err(|e| e.to_string())?;
+
+    let store = store::open(config.storage.backend, config.storage_path())
+        .await
+        .map_err(|e| e.to_string())?;
+    let keys = Arc::new(KeyRing::load(&config.server.keys_dir)?);
+    let token = std::env::var(threshold::TOKEN_ENV).ok()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| format!("Set {} to the secret the decryptors accept requests with", threshold::TOKEN_ENV))?;
+    let decryptor = ThresholdDecryptor::connect(
+        &config.decryption.decryptors, config.decryption.threshold, token, keys.key_id().to_string(),
+    )?;
+    println!("Decrypting with {} of the decryptors at {}", config.decryption.threshold, config.decryption.decryptors.join(", "));
+    match keys.previous() {
+        Some(previous) => println!(
+            "Rotating from key set {} to {}, rows under {} are switched over as they are read",
+            previous, keys.info(), previous.key_id
+        ),
+        None => println!("Using key set {}", keys.info()),
     }
+    // tfhe holds the server key per thread, each compute thread installs it
+    // once as it starts. Handlers never compute on the async runtime
+    let executor = ComputeExecutor::new(config.compute.workers, config.compute.queue_limit, keys.server_key().clone())?;
+    println!("Compute pool of {} threads, at most {} jobs queued", executor.stats().workers, config.compute.queue_limit);
+    // Public key and CRS of the current key set, the new one during a rotation
+    let keys_dir = keys.dir();
     let state = AppState {
-        db: Arc::new(Connection::open(DB_PATH).await?),
-        server_key: Arc::new(keys::load_server_key()?),
-        client_key: Arc::new(keys::load_client_key()?),
+        store: store.clone(),
+        server_key: keys.server_key().clone(),
+        compressed_server_key: Bytes::from(keys::read_compressed_server_key(keys_dir)?),
+        decryptor: Arc::new(decryptor),
+        public_key: Arc::new(keys::load_public_key(keys_dir)?),
+        crs: Arc::new(keys::load_crs(keys_dir)?),
+        cache: Arc::new(Cache::new(config.cache.capacity_bytes)),
+        executor: Arc::new(executor),
+        jobs: Arc::new(Jobs::new(
+            store,
+            config.jobs.callback_url.clone(),
+            config.jobs.retention_secs,
+            config.jobs.idempotency_ttl_secs,
+        )),
+        keys: keys.clone(),
+        require_handle_origin: config.handles.require_origin,
+        allow_plaintext_deposits: config.deposits.allow_plaintext,
     };
-    init_db(&state.db).await?;
+    seed_zero(&state).await.map_err(|e| e.to_string())?;
+    // Jobs a crash or restart interrupted run again before new requests arrive
+    let resumed = state.jobs.resume(|kind, request| run_job(state.clone(), kind, request))
+        .await
+        .map_err(|e| e.to_string())?;
+    if resumed > 0 {
+        println!("Resumed {} jobs", resumed);
+    }
+    state.jobs.start_pruning();
     let app = Router::new()
         .route("/post", post(handle_post))
+        .route("/post_ciphertext", post(handle_post_ciphertext))
+        .route("/public_key", get(handle_public_key))
+        .route("/server_key", get(handle_server_key))
+        .route("/crs", get(handle_crs))
+        .route("/key_info", get(handle_key_info))
         .route("/transfer", post(handle_transfer))
+        .route("/op", post(handle_op))
+        .route("/graph", post(handle_graph))
         .route("/decrypt", post(handle_view))
+        .route("/reencrypt", post(handle_reencrypt))
         .route("/withdraw", post(handle_withdraw))
+        .route("/jobs/post", post(handle_post_job))
+        .route("/jobs/post_ciphertext", post(handle_post_ciphertext_job))
+        .route("/jobs/transfer", post(handle_transfer_job))
+        .route("/jobs/op", post(handle_op_job))
+        .route("/jobs/graph", post(handle_graph_job))
+        .route("/jobs/withdraw", post(handle_withdraw_job))
+        .route("/jobs/:id", get(handle_job))
+        .route("/metrics/cache", get(handle_cache_stats))
+        .route("/metrics/compute", get(handle_compute_stats))
         .with_state(state);
 
-    println!("Server starting on http://localhost:3000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("Server starting on http://{}", config.server.bind);
+    let listener = tokio::net::TcpListener::bind(config.server.bind).await?;
     axum::serve(listener, app).await.unwrap();
     Ok(())
 }
-
-
-
-
-
-
-
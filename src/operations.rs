@@ -1,88 +1,300 @@
-use axum::http::StatusCode;
-use tfhe::{FheUint64, CompressedCiphertextList};
-use tfhe::prelude::*;
-use std::path::Path;
-use std::fs;
-use tokio_rusqlite::Connection;
-
-const DB_PATH: &str = "data/tfhe.db";
-
-pub async fn get_prepared_ciphertext(key: [u8; 32]) -> Result<FheUint64, StatusCode> {
-    let serialized_data = get_ciphertext(key)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let deserialized_compressed: CompressedCiphertextList = bincode::deserialize(&serialized_data)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    deserialized_compressed.get(0)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tfhe::FheUint64;
+use crate::cache::Cache;
+use crate::ciphertext::{CiphertextError, StoredType, TypedCiphertext};
+use crate::compute::{self, ComputeError};
+use crate::auth::AuthError;
+use crate::error::ApiError;
+use crate::executor::ComputeExecutor;
+use crate::jobs;
+use crate::keyring::KeyRing;
+use crate::store::{CiphertextStore, RowInfo, StoreError, StoredRow, Write};
+use crate::types::{FheType, GraphInput, GraphNode, Opcode, Scalar};
+
+/// A value to store: its key, the value and its compressed ciphertext.
+pub type CiphertextRow = ([u8; 32], TypedCiphertext, Vec<u8>);
+
+impl RowInfo {
+    /// The type the row's value is stored as.
+    pub fn stored_type(&self) -> Result<FheType, CiphertextError> {
+        FheType::from_tag(self.fhe_type)
+            .ok_or_else(|| CiphertextError::UnsupportedType(format!("tag {}", self.fhe_type)))
+    }
+}
+
+impl StoredRow {
+    /// Decompresses the row under the server key set on the calling thread,
+    /// `KeyRing::decompress` picks the key set the row is under.
+    pub fn decompress(&self) -> Result<TypedCiphertext, CiphertextError> {
+        let fhe_type = FheType::from_tag(self.fhe_type)
+            .ok_or_else(|| CiphertextError::UnsupportedType(format!("tag {}", self.fhe_type)))?;
+        TypedCiphertext::decompress(fhe_type, &self.ciphertext)
+    }
+}
+
+/// Where values are loaded from: the store, the cache in front of it, and
+/// the key ring and compute pool that decompress store rows.
+pub struct Loader<'a> {
+    pub store: &'a dyn CiphertextStore,
+    pub cache: &'a Cache,
+    pub keys: &'a Arc<KeyRing>,
+    pub executor: &'a ComputeExecutor,
+}
+
+pub async fn get_prepared_ciphertext(loader: &Loader<'_>, key: [u8; 32]) -> Result<FheUint64, ApiError> {
+    Ok(get_typed_ciphertext::<FheUint64>(loader, key).await?.0)
+}
+
+/// Loads the value at `key` as `T` with its version, failing with
+/// `TypeMismatch` if the row holds a different type.
+pub async fn get_typed_ciphertext<T: StoredType>(loader: &Loader<'_>, key: [u8; 32]) -> Result<(T, i64), ApiError> {
+    let (value, version) = get_any_ciphertext(loader, key).await?;
+    Ok((T::from_typed(value)?, version))
+}
+
+/// Loads the value at `key` from the cache, or from the store on a miss,
+/// decompressing it on the compute pool. Only values under the current key
+/// set are ever cached.
+pub async fn get_any_ciphertext(loader: &Loader<'_>, key: [u8; 32]) -> Result<(TypedCiphertext, i64), ApiError> {
+    if let Some(cached) = loader.cache.get(&key).await {
+        return Ok(cached);
+    }
+    let row = loader.store.get(key)
+        .await?
+        .ok_or(StoreError::NotFound(key))?;
+    let version = row.version;
+    let keys = loader.keys.clone();
+    let value = loader.executor.run(move || keys.decompress(&row)).await??;
+    loader.cache.insert(key, value.clone(), version).await;
+    Ok((value, version))
 }
 
-pub async fn get_ciphertext(key: [u8; 32]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let conn = Connection::open(DB_PATH).await?;
-    
-    conn.call(move |conn| {
-        conn.query_row(
-            "SELECT ciphertext FROM computations WHERE key = ?",
-            [key],
-            |row| row.get(0)
-        )
-    }).await.map_err(Into::into)
+/// Checks, before anything is computed, that values owned by `owner` may be
+/// derived from `inputs` and stored at `outputs`. Every input must be stored
+/// with exactly that owner, so nobody can take over or give away values
+/// computed from another's, and no output may have a value yet. Returns what
+/// is stored of the inputs.
+pub async fn authorize_derivation(
+    store: &dyn CiphertextStore,
+    owner: Option<[u8; 32]>,
+    inputs: &[[u8; 32]],
+    outputs: &[[u8; 32]],
+) -> Result<HashMap<[u8; 32], RowInfo>, ApiError> {
+    let keys = inputs.iter().chain(outputs).copied().collect();
+    let stored: HashMap<[u8; 32], RowInfo> = store.get_info(keys).await?
+        .into_iter()
+        .map(|info| (info.key, info))
+        .collect();
+    if let Some(key) = outputs.iter().find(|key| stored.contains_key(*key)) {
+        return Err(StoreError::Exists(*key).into());
+    }
+    for key in inputs {
+        let info = stored.get(key).ok_or(StoreError::NotFound(*key))?;
+        if info.owner != owner {
+            return Err(AuthError::OwnerMismatch.into());
+        }
+    }
+    Ok(stored)
 }
 
-pub async fn init_db(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(parent) = Path::new(DB_PATH).parent() {
-        println!("Creating directory at: {:?}", parent);
-        fs::create_dir_all(parent)?;
-    }
-    conn.call(|conn| {
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS computations (
-                key CHAR(32) NOT NULL PRIMARY KEY,
-                ciphertext BLOB NOT NULL
-            )",
-            (),
-        ).map_err(|e| {
-            println!("Database error: {}", e);
-            e
-        })?;
-        Ok(())
-    })
-    .await?;
-    Ok(())
+/// Handle `origin` derives for `opcode` on `operands` of `operand_types` and
+/// the optional `scalar`, with the type of the result. Only the types are
+/// needed, so handles are checked before anything is loaded or computed.
+pub fn derive_handle(
+    origin: &fhe_handles::Origin,
+    opcode: Opcode,
+    operands: &[[u8; 32]],
+    operand_types: &[FheType],
+    scalar: Option<&Scalar>,
+) -> Result<([u8; 32], FheType), ComputeError> {
+    let result_type = compute::result_type(opcode, operand_types, scalar.is_some())?;
+    let scalar = scalar
+        .map(|scalar| compute::scalar_operand(opcode, operand_types[0], scalar))
+        .transpose()?;
+    let scalar = scalar.as_ref().map(|(fhe_type, bytes)| fhe_handles::Scalar { fhe_type: fhe_type.tag(), bytes });
+    let handle = fhe_handles::derive(origin, opcode.code(), operands, scalar, result_type.tag());
+    Ok((handle, result_type))
 }
 
-pub async fn update_ciphertext(key: [u8; 32], new_ciphertext: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
-    let conn = Connection::open(DB_PATH).await?;
-    conn.call(move |conn| {
-        let mut stmt = conn.prepare(
-            "UPDATE computations SET ciphertext = ? WHERE key = ?"
-        )?;
-        let rows_affected = stmt.execute((&new_ciphertext, &key))?;
-        if rows_affected == 0 {
-            println!("No row found with the given key");
-        } else {
-            println!("Updated ciphertext for key: {:?}", key);
+/// Handles `origin` derives for every node of a graph, whether it is stored
+/// or not. Node `i` is derived with nonce `origin.nonce + i`, and a node that
+/// reads an earlier node has that node's handle as the operand. `stored`
+/// holds the stored inputs, as `authorize_derivation` returns them.
+pub fn derive_graph_handles(
+    origin: &fhe_handles::Origin,
+    nodes: &[GraphNode],
+    stored: &HashMap<[u8; 32], RowInfo>,
+) -> Result<Vec<[u8; 32]>, ApiError> {
+    let mut derived: Vec<([u8; 32], FheType)> = Vec::with_capacity(nodes.len());
+    for (index, node) in nodes.iter().enumerate() {
+        let mut operands = Vec::with_capacity(node.inputs.len());
+        let mut operand_types = Vec::with_capacity(node.inputs.len());
+        for input in &node.inputs {
+            let (handle, fhe_type) = match *input {
+                GraphInput::Handle(key) => {
+                    let info = stored.get(&key).ok_or(StoreError::NotFound(key))?;
+                    (key, info.stored_type()?)
+                }
+                GraphInput::Node(dependency) => *derived.get(dependency).ok_or_else(|| ComputeError::InvalidGraph(
+                    format!("node {} reads node {}, only earlier nodes can be inputs", index, dependency)
+                ))?,
+            };
+            operands.push(handle);
+            operand_types.push(fhe_type);
         }
-        Ok(())
-    }).await?;
-    Ok(())
+        let origin = fhe_handles::Origin { nonce: origin.nonce.wrapping_add(index as u64), ..*origin };
+        let node_handle = derive_handle(&origin, node.opcode, &operands, &operand_types, node.scalar.as_ref())
+            .map_err(|error| ComputeError::Node { index, error: Box::new(error) })?;
+        derived.push(node_handle);
+    }
+    Ok(derived.into_iter().map(|(handle, _)| handle).collect())
 }
 
-pub async fn insert_ciphertext(key: [u8; 32], ciphertext: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
-    let conn = Connection::open(DB_PATH).await?;
-    println!("inserting ciphertext via helper");
-    conn.call(move |conn| {
-        conn.execute(
-            "INSERT OR REPLACE INTO computations (key, ciphertext) VALUES (?1, ?2)",
-            (key, ciphertext),
-        ).map_err(|e| {
-            println!("Insert error: {}", e);
-            e
-        })?;
-        Ok(())
-    }).await?;
-    Ok(())
-}
\ No newline at end of file
+/// Ciphertext writes that `commit_batch` applies atomically, so a multi-row
+/// change such as a transfer is stored entirely or not at all.
+///
+/// Updates and checks carry the version the caller read. If any row has been
+/// written since, the whole batch fails with `StoreError::Conflict`.
+pub struct WriteBatch {
+    // Key set the written values are encrypted under
+    key_id: String,
+    writes: Vec<Write>,
+    // Values of the puts and updates in order, written through to the cache
+    values: Vec<([u8; 32], TypedCiphertext)>,
+    // Response of the job the batch commits for, in JSON
+    result: Option<String>,
+}
+
+impl WriteBatch {
+    pub fn new(key_id: &str) -> Self {
+        Self { key_id: key_id.to_string(), writes: Vec::new(), values: Vec::new(), result: None }
+    }
+
+    /// Inserts `value` at `key` as a new row, the batch fails if `key` has one.
+    /// `ciphertext` is `value` as returned by `TypedCiphertext::compress`.
+    pub fn insert(&mut self, key: [u8; 32], value: TypedCiphertext, ciphertext: Vec<u8>, owner: Option<[u8; 32]>) -> &mut Self {
+        self.writes.push(Write::Put { key, fhe_type: value.fhe_type().tag(), ciphertext, owner, key_id: self.key_id.clone() });
+        self.values.push((key, value));
+        self
+    }
+
+    /// Replaces the value at `key`, keeping its owner, if it is still at `version`.
+    pub fn update(&mut self, key: [u8; 32], version: i64, value: TypedCiphertext, ciphertext: Vec<u8>) -> &mut Self {
+        self.writes.push(Write::Update { key, version, fhe_type: value.fhe_type().tag(), ciphertext, key_id: self.key_id.clone() });
+        self.values.push((key, value));
+        self
+    }
+
+    /// Records `response` as the result of the job the batch runs in, stored
+    /// with the batch so it is known after a restart that left the job applied.
+    pub fn respond<T: Serialize>(&mut self, response: &T) -> &mut Self {
+        self.result = serde_json::to_string(response).ok();
+        self
+    }
+
+    /// Requires the value at `key`, read but not written, to still be at `version`.
+    /// Checks should precede writes to the same key in the batch.
+    pub fn check(&mut self, key: [u8; 32], version: i64) -> &mut Self {
+        self.writes.push(Write::Check { key, version });
+        self
+    }
+}
+
+/// Applies `batch` atomically, then writes the new values through to `cache`
+/// with their versions. Inside a job, the batch also marks the job applied
+/// and stores its result.
+pub async fn commit_batch(store: &dyn CiphertextStore, cache: &Cache, batch: WriteBatch) -> Result<(), StoreError> {
+    let count = batch.writes.len();
+    let mut writes = batch.writes;
+    if let Some(id) = jobs::current() {
+        writes.push(Write::Applied { id, result: batch.result });
+    }
+    match store.batch(writes).await {
+        Ok(versions) => {
+            for ((key, value), version) in batch.values.into_iter().zip(versions) {
+                cache.insert(key, value, version).await;
+            }
+            println!("Committed {} ciphertext writes", count);
+            Ok(())
+        }
+        Err(StoreError::Conflict(key)) => {
+            // The cached copy may be the stale one, a retry must read the store
+            cache.remove(&key).await;
+            Err(StoreError::Conflict(key))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: fhe_handles::Origin = fhe_handles::Origin { program_id: [1; 32], account: [2; 32], nonce: 0 };
+
+    fn at(nonce: u64) -> fhe_handles::Origin {
+        fhe_handles::Origin { nonce, ..ORIGIN }
+    }
+
+    fn handle(hex: &str) -> [u8; 32] {
+        let mut handle = [0; 32];
+        for (i, byte) in handle.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        handle
+    }
+
+    fn node(opcode: Opcode, inputs: Vec<GraphInput>) -> GraphNode {
+        GraphNode { opcode, inputs, scalar: None, output: None }
+    }
+
+    fn info(key: [u8; 32], fhe_type: FheType) -> ([u8; 32], RowInfo) {
+        (key, RowInfo { key, fhe_type: fhe_type.tag(), owner: None, version: 1 })
+    }
+
+    // The vectors of the fhe-handles tests, from the requests a server gets for them
+    #[test]
+    fn derives_the_shared_vectors() {
+        let (add, add_type) = derive_handle(&at(7), Opcode::Add, &[[3; 32], [4; 32]], &[FheType::Uint64; 2], None).unwrap();
+        assert_eq!(add, handle("0a3d2f6c351ce92d37c6590a84db2172fb0e6d5b17bd8001905347bd0a8352fb"));
+        assert_eq!(add_type, FheType::Uint64);
+
+        let (lt, lt_type) = derive_handle(&at(2), Opcode::Lt, &[[3; 32], [4; 32]], &[FheType::Uint64; 2], None).unwrap();
+        assert_eq!(lt, handle("bc444911973b4f97dc293f4485f7c3810f31d3c9bdfc5e7e7589421856e102e0"));
+        assert_eq!(lt_type, FheType::Bool);
+
+        let (sub, _) = derive_handle(&at(1), Opcode::Sub, &[[3; 32]], &[FheType::Int8], Some(&Scalar::Signed(-1))).unwrap();
+        assert_eq!(sub, handle("143fe3931431bccf654135b8aa78d3214865c52b07e737c26ca8dd93f045612d"));
+    }
+
+    #[test]
+    fn scalars_are_hashed_as_the_parsed_plaintext() {
+        let derive = |scalar: Scalar| derive_handle(&ORIGIN, Opcode::Add, &[[3; 32]], &[FheType::Uint8], Some(&scalar)).unwrap();
+        assert_eq!(derive(Scalar::Unsigned(5)), derive(Scalar::Decimal("5".to_string())));
+        assert_ne!(derive(Scalar::Unsigned(5)), derive(Scalar::Unsigned(6)));
+        assert!(derive_handle(&ORIGIN, Opcode::Add, &[[3; 32]], &[FheType::Uint8], Some(&Scalar::Unsigned(256))).is_err());
+    }
+
+    #[test]
+    fn graph_nodes_chain_their_handles() {
+        let stored = HashMap::from([info([3; 32], FheType::Uint64), info([4; 32], FheType::Uint64)]);
+        let nodes = [
+            node(Opcode::Ge, vec![GraphInput::Handle([3; 32]), GraphInput::Handle([4; 32])]),
+            node(Opcode::Select, vec![GraphInput::Node(0), GraphInput::Handle([3; 32]), GraphInput::Handle([4; 32])]),
+        ];
+        let handles = derive_graph_handles(&at(10), &nodes, &stored).unwrap();
+        let (ge, _) = derive_handle(&at(10), Opcode::Ge, &[[3; 32], [4; 32]], &[FheType::Uint64; 2], None).unwrap();
+        let types = [FheType::Bool, FheType::Uint64, FheType::Uint64];
+        let (select, _) = derive_handle(&at(11), Opcode::Select, &[ge, [3; 32], [4; 32]], &types, None).unwrap();
+        assert_eq!(handles, vec![ge, select]);
+    }
+
+    #[test]
+    fn graph_nodes_only_read_earlier_nodes() {
+        let stored = HashMap::from([info([3; 32], FheType::Uint64)]);
+        let nodes = [node(Opcode::Add, vec![GraphInput::Handle([3; 32]), GraphInput::Node(0)])];
+        assert!(derive_graph_handles(&ORIGIN, &nodes, &stored).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use clap::{Parser, Subcommand};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tfhe::{set_server_key, ClientKey, KeySwitchingKey, ServerKey};
+#[allow(dead_code)]
+mod keys;
+#[allow(dead_code)]
+mod store;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod ciphertext;
+#[allow(dead_code)]
+mod types;
+
+use ciphertext::{CiphertextError, TypedCiphertext};
+use config::Config;
+use keys::{KeyInfo, ParameterSet};
+use store::{CiphertextStore, StoreError, StoredRow};
+use types::FheType;
+
+// Progress of an interrupted migration, kept next to the keys it migrates to
+const PROGRESS_FILE: &str = "rotation.toml";
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+#[derive(Parser, Debug)]
+#[command(about = "Rotates the FHE key set and migrates stored ciphertexts to it")]
+struct Args {
+    #[command(flatten)]
+    server: config::Args,
+    #[command(subcommand)]
+    step: Step,
+}
+
+#[derive(Subcommand, Debug)]
+enum Step {
+    /// Generates the next key set, and a key switching key into it when the parameters match
+    Prepare {
+        /// Parameters of the next key set, those of the current one if not given
+        #[arg(long)]
+        parameter_set: Option<ParameterSet>,
+    },
+    /// Moves stored ciphertexts to the next key set in batches, resuming where an
+    /// interrupted run stopped
+    Migrate {
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
+    },
+    /// Makes the next key set current once no stored ciphertext is under another one
+    Finish,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Progress {
+    /// Last key of the last migrated batch
+    after: Option<[u8; 32]>,
+    migrated: u64,
+    skipped: u64,
+}
+
+impl Progress {
+    fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(PROGRESS_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read migration progress: {}", e))?;
+        toml::from_str(&contents).map_err(|e| format!("Failed to parse migration progress: {}", e))
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), String> {
+        let contents = toml::to_string(self)
+            .map_err(|e| format!("Failed to serialize migration progress: {}", e))?;
+        fs::write(dir.join(PROGRESS_FILE), contents)
+            .map_err(|e| format!("Failed to save migration progress: {}", e))
+    }
+}
+
+/// How values move from the current key set to the next one.
+enum Switch {
+    /// Homomorphically, nothing is decrypted
+    Rekey(Box<KeySwitchingKey>),
+    /// Decrypted and encrypted again, only possible here where both client keys are at hand
+    Reencrypt { from: Box<ClientKey>, to: Box<ClientKey> },
+}
+
+/// Decompresses rows on threads holding the current server key, then
+/// switches and compresses them on threads holding the next one. Every thread
+/// sets its key once as it starts, not once per row.
+struct Migration {
+    from_pool: rayon::ThreadPool,
+    to_pool: rayon::ThreadPool,
+    switch: Switch,
+}
+
+// A pool of `workers` threads, one per core if 0, with `key` installed on each
+fn key_pool(name: &'static str, workers: usize, key: ServerKey) -> Result<rayon::ThreadPool, String> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(workers)
+        .thread_name(move |index| format!("{}-{}", name, index))
+        .start_handler(move |_| set_server_key(key.clone()))
+        .build()
+        .map_err(|e| format!("Failed to start {} pool: {}", name, e))
+}
+
+impl Migration {
+    /// Compressed values of `rows` under the next key set, in order.
+    fn migrate(&self, rows: &[StoredRow]) -> Result<Vec<Vec<u8>>, CiphertextError> {
+        let values = self.from_pool.install(|| {
+            rows.par_iter()
+                .map(|row| {
+                    let fhe_type = FheType::from_tag(row.fhe_type)
+                        .ok_or_else(|| CiphertextError::UnsupportedType(format!("tag {}", row.fhe_type)))?;
+                    TypedCiphertext::decompress(fhe_type, &row.ciphertext)
+                })
+                .collect::<Result<Vec<_>, CiphertextError>>()
+        })?;
+        self.to_pool.install(|| {
+            values.into_par_iter()
+                .map(|value| {
+                    let value = match &self.switch {
+                        Switch::Rekey(rekey) => value.keyswitch(rekey),
+                        Switch::Reencrypt { from, to } => value.reencrypt(from, to),
+                    };
+                    value.compress()
+                })
+                .collect()
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let config = Config::load(&args.server).map_err(|e| e.to_string())?;
+    config.validate().map_err(|e| e.to_string())?;
+    match args.step {
+        Step::Prepare { parameter_set } => prepare(&config, parameter_set),
+        Step::Migrate { batch_size } => migrate(&config, batch_size).await,
+        Step::Finish => finish(&config).await,
+    }
+}
+
+fn prepare(config: &Config, parameter_set: Option<ParameterSet>) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = config.server.keys_dir.as_path();
+    let next = dir.join(keys::NEXT_DIR);
+    let current = keys::load_key_info(dir)?;
+    // The next key set's secret files are encrypted like the current one's
+    let protection = config.key_protection()?;
+    let info = if keys::has_next(dir) {
+        let info = keys::load_key_info(&next)?;
+        println!("Key set {} is already prepared", info);
+        info
+    } else {
+        if next.exists() {
+            // Left by an interrupted run, the key info is written last
+            fs::remove_dir_all(&next)?;
+        }
+        let parameter_set = parameter_set.unwrap_or(current.parameter_set);
+        println!("Generating {} keys to rotate to from {}...", parameter_set, current);
+        keys::generate_key_set(&next, parameter_set, &protection)?
+    };
+    if keys::has_rekey(&next) || keys::generate_rekey(dir, &next, &protection)? {
+        println!(
+            "Restart the server to serve key set {} while rows under {} are switched over, then run rotate_keys migrate",
+            info, current.key_id
+        );
+    } else {
+        println!(
+            "Parameter sets differ, so there is no key switching key from {} to {}. Stop the server, then run rotate_keys migrate and finish",
+            current, info
+        );
+    }
+    Ok(())
+}
+
+async fn migrate(config: &Config, batch_size: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = config.server.keys_dir.as_path();
+    let next = dir.join(keys::NEXT_DIR);
+    if !keys::has_next(dir) {
+        return Err("No key set to rotate to, run rotate_keys prepare first".into());
+    }
+    let from = keys::load_key_info(dir)?;
+    let to = keys::load_key_info(&next)?;
+    let switch = match keys::load_rekey(&next)? {
+        Some(rekey) => Switch::Rekey(Box::new(rekey)),
+        None => {
+            println!("No key switching key, decrypting and encrypting again. The server must be stopped");
+            let protection = config.key_protection()?;
+            Switch::Reencrypt {
+                from: Box::new(keys::load_client_key(dir, &protection)?),
+                to: Box::new(keys::load_client_key(&next, &protection)?),
+            }
+        }
+    };
+    let workers = config.compute.workers;
+    let migration = Arc::new(Migration {
+        from_pool: key_pool("migrate-from", workers, keys::load_server_key(dir)?)?,
+        to_pool: key_pool("migrate-to", workers, keys::load_server_key(&next)?)?,
+        switch,
+    });
+
+    let store = store::open(config.storage.backend, config.storage_path()).await.map_err(|e| e.to_string())?;
+    let mut progress = Progress::load(&next)?;
+    if progress.after.is_some() {
+        println!("Resuming migration after {} rows", progress.migrated + progress.skipped);
+    }
+    println!("Migrating rows from key set {} to {}", from, to);
+    loop {
+        let rows = store.scan(progress.after, batch_size).await.map_err(|e| e.to_string())?;
+        let Some(last) = rows.last().map(|row| row.key) else {
+            break;
+        };
+        let (pending, unknown): (Vec<StoredRow>, Vec<StoredRow>) = rows.into_iter()
+            .filter(|row| row.key_id.as_deref() != Some(to.key_id.as_str()))
+            .partition(|row| row.key_id.as_ref().is_none_or(|id| *id == from.key_id));
+        for row in &unknown {
+            println!("Skipping {:?} under unknown key set {:?}", row.key, row.key_id);
+        }
+        progress.skipped += unknown.len() as u64;
+
+        // Switching is CPU bound, keep it off the async workers
+        let batch = migration.clone();
+        let migrated = tokio::task::spawn_blocking(move || {
+            batch.migrate(&pending).map(|ciphertexts| (pending, ciphertexts))
+        }).await?;
+        let (pending, ciphertexts) = migrated.map_err(|e| e.to_string())?;
+        for (row, ciphertext) in pending.into_iter().zip(ciphertexts) {
+            match store.update(row.key, row.version, row.fhe_type, ciphertext, to.key_id.clone()).await {
+                Ok(_) => progress.migrated += 1,
+                // Written or deleted since the scan, finish checks what it is under now
+                Err(StoreError::Conflict(_)) | Err(StoreError::NotFound(_)) => progress.skipped += 1,
+                Err(e) => return Err(e.to_string().into()),
+            }
+        }
+        progress.after = Some(last);
+        progress.save(&next)?;
+        println!("Migrated {} rows, skipped {}", progress.migrated, progress.skipped);
+    }
+    // The next run makes a fresh pass for rows skipped in this one
+    fs::remove_file(next.join(PROGRESS_FILE)).ok();
+    println!("Migration pass complete, run rotate_keys finish");
+    Ok(())
+}
+
+async fn finish(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = config.server.keys_dir.as_path();
+    if !keys::has_next(dir) {
+        return Err("No key set to rotate to, run rotate_keys prepare first".into());
+    }
+    let to = keys::load_key_info(&dir.join(keys::NEXT_DIR))?;
+    let store = store::open(config.storage.backend, config.storage_path()).await.map_err(|e| e.to_string())?;
+    let remaining = count_not_under(&*store, &to).await.map_err(|e| e.to_string())?;
+    if remaining > 0 {
+        return Err(format!("{} rows are not under key set {} yet, run rotate_keys migrate again", remaining, to.key_id).into());
+    }
+    keys::promote_next(dir)?;
+    println!("Key set {} is now current, restart the server to drop the previous one", to);
+    Ok(())
+}
+
+async fn count_not_under(store: &dyn CiphertextStore, info: &KeyInfo) -> Result<usize, StoreError> {
+    let mut count = 0;
+    let mut after = None;
+    loop {
+        let rows = store.scan(after, DEFAULT_BATCH_SIZE).await?;
+        let Some(last) = rows.last() else {
+            return Ok(count);
+        };
+        after = Some(last.key);
+        count += rows.iter().filter(|row| row.key_id.as_deref() != Some(info.key_id.as_str())).count();
+    }
+}
@@ -0,0 +1,92 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const SEAL_INFO: &[u8] = b"svm-fhe:reencrypt";
+
+/// A plaintext sealed to a recipient's X25519 key.
+///
+/// The recipient derives the same key with `x25519(recipient_secret,
+/// ephemeral_public_key)` and HKDF-SHA256, then opens `ciphertext` with
+/// ChaCha20-Poly1305 using the ciphertext handle as associated data.
+pub struct SealedValue {
+    pub ephemeral_public_key: [u8; 32],
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Seals `plaintext` for `recipient` with an ephemeral ECDH key.
+///
+/// The HKDF info binds both public keys and the handle, so a sealed value
+/// cannot be presented as the balance of another handle.
+pub fn seal(recipient: &[u8; 32], handle: &[u8; 32], plaintext: &[u8]) -> Result<SealedValue, String> {
+    let recipient = PublicKey::from(*recipient);
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public_key = PublicKey::from(&ephemeral_secret);
+    let shared_secret = ephemeral_secret.diffie_hellman(&recipient);
+    if !shared_secret.was_contributory() {
+        return Err("recipient key is a low-order point".to_string());
+    }
+
+    let info = [SEAL_INFO, ephemeral_public_key.as_bytes(), recipient.as_bytes(), handle].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+        .expand(&info, &mut key)
+        .map_err(|e| e.to_string())?;
+
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad: handle })
+        .map_err(|e| e.to_string())?;
+
+    Ok(SealedValue {
+        ephemeral_public_key: ephemeral_public_key.to_bytes(),
+        nonce: nonce.into(),
+        ciphertext,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Opens `sealed` the way the recipient does, given its side of the ECDH
+    fn open(shared_secret: &[u8; 32], recipient: &[u8; 32], handle: &[u8; 32], sealed: &SealedValue) -> Option<Vec<u8>> {
+        let info = [SEAL_INFO, &sealed.ephemeral_public_key, recipient, handle].concat();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared_secret).expand(&info, &mut key).ok()?;
+        let cipher = ChaCha20Poly1305::new(&key.into());
+        cipher.decrypt(&sealed.nonce.into(), Payload { msg: &sealed.ciphertext, aad: handle }).ok()
+    }
+
+    #[test]
+    fn recipient_opens_under_the_same_handle_only() {
+        let recipient_secret = EphemeralSecret::random_from_rng(OsRng);
+        let recipient = PublicKey::from(&recipient_secret).to_bytes();
+        let sealed = seal(&recipient, &[1; 32], &42u64.to_le_bytes()).unwrap();
+        let shared_secret = recipient_secret.diffie_hellman(&PublicKey::from(sealed.ephemeral_public_key)).to_bytes();
+
+        assert_eq!(open(&shared_secret, &recipient, &[1; 32], &sealed), Some(42u64.to_le_bytes().to_vec()));
+        assert_eq!(open(&shared_secret, &recipient, &[2; 32], &sealed), None);
+    }
+
+    #[test]
+    fn every_seal_uses_a_fresh_key() {
+        let recipient = PublicKey::from(&EphemeralSecret::random_from_rng(OsRng)).to_bytes();
+        let first = seal(&recipient, &[1; 32], b"value").unwrap();
+        let second = seal(&recipient, &[1; 32], b"value").unwrap();
+        assert_ne!(first.ephemeral_public_key, second.ephemeral_public_key);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn refuses_low_order_recipients() {
+        assert!(seal(&[0; 32], &[1; 32], b"value").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use async_trait::async_trait;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use super::{stage_batch, CiphertextStore, StoreError, StoredJob, StoredRow, Write};
+
+/// Rows kept in process memory only, lost on restart. Meant for tests and
+/// benchmarking the storage layer against the others.
+#[derive(Default)]
+pub struct MemoryStore {
+    rows: Mutex<BTreeMap<[u8; 32], StoredRow>>,
+    nonces: Mutex<HashMap<([u8; 32], u64), i64>>,
+    jobs: Mutex<HashMap<String, StoredJob>>,
+    // Only written while the rows are locked
+    last_version: AtomicI64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn poisoned<T>(_: T) -> StoreError {
+    StoreError::Storage("memory store lock poisoned".to_string())
+}
+
+#[async_trait]
+impl CiphertextStore for MemoryStore {
+    async fn get(&self, key: [u8; 32]) -> Result<Option<StoredRow>, StoreError> {
+        let rows = self.rows.lock().map_err(poisoned)?;
+        Ok(rows.get(&key).cloned())
+    }
+
+    async fn batch(&self, writes: Vec<Write>) -> Result<Vec<i64>, StoreError> {
+        // Holding the lock across staging and applying makes the batch atomic
+        let mut rows = self.rows.lock().map_err(poisoned)?;
+        let last_version = self.last_version.load(Ordering::Relaxed);
+        let (staged, versions) = stage_batch(writes, last_version, |key| Ok(rows.get(key).cloned()))?;
+        let mut jobs = self.jobs.lock().map_err(poisoned)?;
+        rows.extend(staged.rows);
+        self.last_version.store(staged.last_version, Ordering::Relaxed);
+        for (id, result) in staged.applied {
+            if let Some(job) = jobs.get_mut(&id) {
+                job.applied = true;
+                job.result = result;
+            }
+        }
+        Ok(versions)
+    }
+
+    async fn delete(&self, key: [u8; 32]) -> Result<bool, StoreError> {
+        let mut rows = self.rows.lock().map_err(poisoned)?;
+        Ok(rows.remove(&key).is_some())
+    }
+
+    async fn scan(&self, after: Option<[u8; 32]>, limit: usize) -> Result<Vec<StoredRow>, StoreError> {
+        let rows = self.rows.lock().map_err(poisoned)?;
+        let start = match after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        Ok(rows.range((start, Bound::Unbounded)).take(limit).map(|(_, row)| row.clone()).collect())
+    }
+
+    async fn consume_nonce(&self, owner: [u8; 32], nonce: u64, expiry: i64, now: i64) -> Result<bool, StoreError> {
+        let mut nonces = self.nonces.lock().map_err(poisoned)?;
+        nonces.retain(|_, nonce_expiry| *nonce_expiry >= now);
+        match nonces.entry((owner, nonce)) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(expiry);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn insert_job(&self, job: StoredJob) -> Result<StoredJob, StoreError> {
+        let mut jobs = self.jobs.lock().map_err(poisoned)?;
+        let earlier = job.idempotency_key.as_ref().and_then(|key| {
+            jobs.values().find(|stored| stored.idempotency_key.as_ref() == Some(key))
+        });
+        if let Some(earlier) = earlier {
+            return Ok(earlier.clone());
+        }
+        jobs.insert(job.id.clone(), job.clone());
+        Ok(job)
+    }
+
+    async fn update_job(&self, job: StoredJob) -> Result<(), StoreError> {
+        let mut jobs = self.jobs.lock().map_err(poisoned)?;
+        if let Some(stored) = jobs.get_mut(&job.id) {
+            stored.status = job.status;
+            stored.result = job.result;
+            stored.error = job.error;
+            stored.finished = job.finished;
+            stored.updated_at = job.updated_at;
+        }
+        Ok(())
+    }
+
+    async fn get_job(&self, id: String) -> Result<Option<StoredJob>, StoreError> {
+        let jobs = self.jobs.lock().map_err(poisoned)?;
+        Ok(jobs.get(&id).cloned())
+    }
+
+    async fn pending_jobs(&self) -> Result<Vec<StoredJob>, StoreError> {
+        let jobs = self.jobs.lock().map_err(poisoned)?;
+        let mut pending: Vec<StoredJob> = jobs.values().filter(|job| !job.finished).cloned().collect();
+        pending.sort_by_key(|job| job.created_at);
+        Ok(pending)
+    }
+
+    async fn prune_jobs(&self, before: i64, keyed_before: i64) -> Result<usize, StoreError> {
+        let mut jobs = self.jobs.lock().map_err(poisoned)?;
+        let count = jobs.len();
+        jobs.retain(|_, job| !job.expired(before, keyed_before));
+        Ok(count - jobs.len())
+    }
+
+    async fn release_job_key(&self, id: String) -> Result<bool, StoreError> {
+        let mut jobs = self.jobs.lock().map_err(poisoned)?;
+        match jobs.get_mut(&id) {
+            Some(job) if !job.applied => Ok(job.idempotency_key.take().is_some()),
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: u8) -> Write {
+        Write::Put { key: [key; 32], fhe_type: 4, ciphertext: vec![key], owner: Some([key; 32]), key_id: "k".to_string() }
+    }
+
+    fn job(id: &str, idempotency_key: Option<&str>, created_at: i64) -> StoredJob {
+        StoredJob {
+            id: id.to_string(),
+            idempotency_key: idempotency_key.map(str::to_string),
+            kind: "op".to_string(),
+            request: "{}".to_string(),
+            status: "pending".to_string(),
+            result: None,
+            error: None,
+            finished: false,
+            applied: false,
+            notify: false,
+            created_at,
+            updated_at: created_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_batches_write_nothing() {
+        let store = MemoryStore::new();
+        store.batch(vec![put(1)]).await.unwrap();
+        let writes = vec![put(2), Write::Check { key: [1; 32], version: 7 }];
+        assert!(matches!(store.batch(writes).await, Err(StoreError::Conflict(_))));
+        assert!(store.get([2; 32]).await.unwrap().is_none());
+        assert_eq!(store.batch(vec![put(3)]).await.unwrap(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn stored_again_rows_get_new_versions() {
+        let store = MemoryStore::new();
+        assert_eq!(store.batch(vec![put(1)]).await.unwrap(), vec![1]);
+        assert!(store.delete([1; 32]).await.unwrap());
+        assert!(!store.delete([1; 32]).await.unwrap());
+        assert_eq!(store.batch(vec![put(1)]).await.unwrap(), vec![2]);
+        // A reader still holding version 1 must not match the new row
+        let stale = vec![Write::Check { key: [1; 32], version: 1 }];
+        assert!(matches!(store.batch(stale).await, Err(StoreError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn scans_in_key_order() {
+        let store = MemoryStore::new();
+        store.batch(vec![put(3), put(1), put(2)]).await.unwrap();
+        let keys: Vec<u8> = store.scan(None, 2).await.unwrap().iter().map(|row| row.key[0]).collect();
+        assert_eq!(keys, vec![1, 2]);
+        let keys: Vec<u8> = store.scan(Some([2; 32]), 10).await.unwrap().iter().map(|row| row.key[0]).collect();
+        assert_eq!(keys, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn nonces_are_used_once_until_expired() {
+        let store = MemoryStore::new();
+        assert!(store.consume_nonce([1; 32], 5, 100, 0).await.unwrap());
+        assert!(!store.consume_nonce([1; 32], 5, 100, 50).await.unwrap());
+        assert!(store.consume_nonce([2; 32], 5, 100, 50).await.unwrap());
+        assert!(store.consume_nonce([1; 32], 5, 300, 200).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_return_the_earlier_job() {
+        let store = MemoryStore::new();
+        assert_eq!(store.insert_job(job("a", Some("key"), 1)).await.unwrap().id, "a");
+        assert_eq!(store.insert_job(job("b", Some("key"), 2)).await.unwrap().id, "a");
+        assert_eq!(store.insert_job(job("c", None, 3)).await.unwrap().id, "c");
+        assert!(store.get_job("b".to_string()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn applied_batches_record_the_result() {
+        let store = MemoryStore::new();
+        store.insert_job(job("a", Some("key"), 1)).await.unwrap();
+        let writes = vec![put(1), Write::Applied { id: "a".to_string(), result: Some("[1]".to_string()) }];
+        store.batch(writes).await.unwrap();
+        let stored = store.get_job("a".to_string()).await.unwrap().unwrap();
+        assert!(stored.applied);
+        assert_eq!(stored.result.as_deref(), Some("[1]"));
+        // Its key stays taken once the writes are in
+        assert!(!store.release_job_key("a".to_string()).await.unwrap());
+        assert_eq!(store.insert_job(job("b", Some("key"), 2)).await.unwrap().id, "a");
+    }
+
+    #[tokio::test]
+    async fn released_keys_can_be_reused() {
+        let store = MemoryStore::new();
+        store.insert_job(job("a", Some("key"), 1)).await.unwrap();
+        assert!(store.release_job_key("a".to_string()).await.unwrap());
+        assert!(!store.release_job_key("a".to_string()).await.unwrap());
+        assert_eq!(store.insert_job(job("b", Some("key"), 2)).await.unwrap().id, "b");
+    }
+
+    #[tokio::test]
+    async fn pending_jobs_are_oldest_first() {
+        let store = MemoryStore::new();
+        store.insert_job(job("b", None, 2)).await.unwrap();
+        store.insert_job(job("a", None, 1)).await.unwrap();
+        let mut done = job("c", None, 0);
+        store.insert_job(done.clone()).await.unwrap();
+        done.finished = true;
+        store.update_job(done).await.unwrap();
+        let ids: Vec<String> = store.pending_jobs().await.unwrap().into_iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn keyed_jobs_are_kept_longer() {
+        let store = MemoryStore::new();
+        for (id, key) in [("keyed", Some("key")), ("unkeyed", None)] {
+            let mut stored = job(id, key, 10);
+            store.insert_job(stored.clone()).await.unwrap();
+            stored.finished = true;
+            store.update_job(stored).await.unwrap();
+        }
+        store.insert_job(job("running", None, 10)).await.unwrap();
+        assert_eq!(store.prune_jobs(20, 5).await.unwrap(), 1);
+        assert!(store.get_job("keyed".to_string()).await.unwrap().is_some());
+        assert!(store.get_job("running".to_string()).await.unwrap().is_some());
+        assert_eq!(store.prune_jobs(20, 20).await.unwrap(), 1);
+        assert!(store.get_job("keyed".to_string()).await.unwrap().is_none());
+    }
+}
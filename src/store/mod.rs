@@ -0,0 +1,379 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod memory;
+#[cfg(feature = "rocksdb")]
+mod rocks;
+mod sqlite;
+
+pub use memory::MemoryStore;
+#[cfg(feature = "rocksdb")]
+pub use rocks::RocksStore;
+pub use sqlite::SqliteStore;
+
+pub const DEFAULT_DB_PATH: &str = "data/tfhe.db";
+pub const DEFAULT_ROCKSDB_PATH: &str = "data/rocksdb";
+
+/// A stored ciphertext as read. Every write to a row gives it the next value
+/// of a counter shared by all rows, so a key deleted and stored again never
+/// comes back at a version it was read at before.
+#[derive(Clone)]
+pub struct StoredRow {
+    pub key: [u8; 32],
+    pub fhe_type: u8,
+    pub ciphertext: Vec<u8>,
+    pub owner: Option<[u8; 32]>,
+    pub version: i64,
+    /// Key set the ciphertext is encrypted under, unset for rows written
+    /// before key sets had IDs
+    pub key_id: Option<String>,
+}
+
+/// What `get_info` reads of a row, everything but the ciphertext.
+#[derive(Clone, Debug)]
+pub struct RowInfo {
+    pub key: [u8; 32],
+    pub fhe_type: u8,
+    pub owner: Option<[u8; 32]>,
+    pub version: i64,
+}
+
+/// One step of a batch, applied in order by `CiphertextStore::batch`.
+pub enum Write {
+    /// Stores a new row at `key`, failing with `Exists` if there is one, so
+    /// neither the value nor the owner of a key can be replaced by a put
+    Put { key: [u8; 32], fhe_type: u8, ciphertext: Vec<u8>, owner: Option<[u8; 32]>, key_id: String },
+    /// Replaces the value at `key`, keeping its owner, if it is still at `version`
+    Update { key: [u8; 32], version: i64, fhe_type: u8, ciphertext: Vec<u8>, key_id: String },
+    /// Requires the row at `key` to still be at `version` without writing it
+    Check { key: [u8; 32], version: i64 },
+    /// Marks the job `id` applied with its result in JSON, so it is not run
+    /// again after a restart and still has its result then
+    Applied { id: String, result: Option<String> },
+}
+
+/// A job accepted through `/jobs`, stored before it runs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredJob {
+    pub id: String,
+    /// Key the client submitted the job under, resubmissions with it get this job
+    pub idempotency_key: Option<String>,
+    pub kind: String,
+    /// The request body in JSON, to run the job again after a restart
+    pub request: String,
+    pub status: String,
+    /// Result or error in JSON, once finished
+    pub result: Option<String>,
+    pub error: Option<String>,
+    pub finished: bool,
+    /// Set by the batch that committed the job's writes
+    pub applied: bool,
+    /// Whether the finished job is posted to the callback URL, only for jobs
+    /// submitted through `/jobs`, also once resumed after a restart
+    pub notify: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl StoredJob {
+    /// Whether `prune_jobs(before, keyed_before)` removes the job.
+    fn expired(&self, before: i64, keyed_before: i64) -> bool {
+        self.finished && self.updated_at < before
+            && (self.idempotency_key.is_none() || self.created_at < keyed_before)
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The row changed after it was read, retrying on fresh values can succeed
+    Conflict([u8; 32]),
+    /// An updated or checked row does not exist
+    NotFound([u8; 32]),
+    /// A put targets a key that already has a row
+    Exists([u8; 32]),
+    Storage(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Conflict(key) => write!(f, "concurrent write to {:?}", key),
+            StoreError::NotFound(key) => write!(f, "no row for {:?}", key),
+            StoreError::Exists(key) => write!(f, "a value is already stored at {:?}", key),
+            StoreError::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+/// Persistence of compressed ciphertexts, decrypt nonces and jobs.
+///
+/// Implementations must apply `batch` atomically: either every write is
+/// stored or, on the first failed update or check, none are. A job marked
+/// applied in a batch is so exactly when the batch's rows are written.
+#[async_trait]
+pub trait CiphertextStore: Send + Sync {
+    async fn get(&self, key: [u8; 32]) -> Result<Option<StoredRow>, StoreError>;
+
+    /// Applies `writes` in order, returning the new version of every put and
+    /// update in the same order.
+    async fn batch(&self, writes: Vec<Write>) -> Result<Vec<i64>, StoreError>;
+
+    /// Removes the row at `key`, returning whether there was one.
+    async fn delete(&self, key: [u8; 32]) -> Result<bool, StoreError>;
+
+    /// Up to `limit` rows in key order, starting after `after` if given.
+    async fn scan(&self, after: Option<[u8; 32]>, limit: usize) -> Result<Vec<StoredRow>, StoreError>;
+
+    /// Records a decrypt nonce for `owner`, returning false if it was already used.
+    /// Nonces past their expiry are pruned since their signatures no longer verify.
+    async fn consume_nonce(&self, owner: [u8; 32], nonce: u64, expiry: i64, now: i64) -> Result<bool, StoreError>;
+
+    /// Stores a new job, unless one was stored under the same idempotency key.
+    /// Returns the stored job, the earlier one in that case.
+    async fn insert_job(&self, job: StoredJob) -> Result<StoredJob, StoreError>;
+
+    /// Replaces the status, result, error and update time of the job `job.id`.
+    async fn update_job(&self, job: StoredJob) -> Result<(), StoreError>;
+
+    async fn get_job(&self, id: String) -> Result<Option<StoredJob>, StoreError>;
+
+    /// Jobs not finished yet, oldest first.
+    async fn pending_jobs(&self) -> Result<Vec<StoredJob>, StoreError>;
+
+    /// Removes finished jobs last updated before `before`, returning how many.
+    /// Jobs holding an idempotency key are kept until they were also created
+    /// before `keyed_before`, so resubmissions find them.
+    async fn prune_jobs(&self, before: i64, keyed_before: i64) -> Result<usize, StoreError>;
+
+    /// Drops the idempotency key of the job `id` unless the job was applied,
+    /// so resubmitting the key runs the request again. Returns whether the
+    /// key was released.
+    async fn release_job_key(&self, id: String) -> Result<bool, StoreError>;
+
+    /// Loads the rows at `keys`, skipping keys with no row.
+    async fn get_many(&self, keys: Vec<[u8; 32]>) -> Result<Vec<StoredRow>, StoreError> {
+        let mut rows = Vec::with_capacity(keys.len());
+        for key in keys {
+            rows.extend(self.get(key).await?);
+        }
+        Ok(rows)
+    }
+
+    /// The type, owner and version of the rows at `keys`, skipping keys with no row.
+    async fn get_info(&self, keys: Vec<[u8; 32]>) -> Result<Vec<RowInfo>, StoreError> {
+        let rows = self.get_many(keys).await?;
+        Ok(rows.into_iter()
+            .map(|row| RowInfo { key: row.key, fhe_type: row.fhe_type, owner: row.owner, version: row.version })
+            .collect())
+    }
+
+    /// Stores a new row at `key`, returning its version.
+    async fn put(&self, key: [u8; 32], fhe_type: u8, ciphertext: Vec<u8>, owner: Option<[u8; 32]>, key_id: String) -> Result<i64, StoreError> {
+        let versions = self.batch(vec![Write::Put { key, fhe_type, ciphertext, owner, key_id }]).await?;
+        Ok(versions[0])
+    }
+
+    /// Replaces the value at `key` if it is still at `version`, returning its new version.
+    async fn update(&self, key: [u8; 32], version: i64, fhe_type: u8, ciphertext: Vec<u8>, key_id: String) -> Result<i64, StoreError> {
+        let versions = self.batch(vec![Write::Update { key, version, fhe_type, ciphertext, key_id }]).await?;
+        Ok(versions[0])
+    }
+}
+
+/// What a batch writes: rows keyed by their key, the jobs it marks applied
+/// and the version counter after its writes.
+#[derive(Default)]
+struct Staged {
+    rows: HashMap<[u8; 32], StoredRow>,
+    applied: Vec<(String, Option<String>)>,
+    last_version: i64,
+}
+
+/// Resolves `writes` in order against the rows `load` returns, for stores
+/// without transactions, numbering the writes on from `last_version`.
+/// Returns what to persist and the batch versions, nothing must be persisted
+/// on error.
+fn stage_batch<F>(writes: Vec<Write>, last_version: i64, mut load: F) -> Result<(Staged, Vec<i64>), StoreError>
+where
+    F: FnMut(&[u8; 32]) -> Result<Option<StoredRow>, StoreError>,
+{
+    let mut staged = Staged { last_version, ..Staged::default() };
+    let mut versions = Vec::with_capacity(writes.len());
+    for write in writes {
+        // Earlier writes in the batch take precedence over the stored row
+        let key = match &write {
+            Write::Put { key, .. } | Write::Update { key, .. } | Write::Check { key, .. } => *key,
+            Write::Applied { id, result } => {
+                staged.applied.push((id.clone(), result.clone()));
+                continue;
+            }
+        };
+        let current = match staged.rows.get(&key) {
+            Some(row) => Some(row.clone()),
+            None => load(&key)?,
+        };
+        match write {
+            Write::Put { key, fhe_type, ciphertext, owner, key_id } => {
+                if current.is_some() {
+                    return Err(StoreError::Exists(key));
+                }
+                staged.last_version += 1;
+                let version = staged.last_version;
+                staged.rows.insert(key, StoredRow { key, fhe_type, ciphertext, owner, version, key_id: Some(key_id) });
+                versions.push(version);
+            }
+            Write::Update { key, version, fhe_type, ciphertext, key_id } => {
+                let row = current.ok_or(StoreError::NotFound(key))?;
+                if row.version != version {
+                    return Err(StoreError::Conflict(key));
+                }
+                staged.last_version += 1;
+                let version = staged.last_version;
+                staged.rows.insert(key, StoredRow {
+                    key, fhe_type, ciphertext, owner: row.owner, version, key_id: Some(key_id),
+                });
+                versions.push(version);
+            }
+            Write::Check { key, version } => {
+                let row = current.ok_or(StoreError::NotFound(key))?;
+                if row.version != version {
+                    return Err(StoreError::Conflict(key));
+                }
+            }
+            Write::Applied { .. } => {}
+        }
+    }
+    Ok((staged, versions))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Sqlite,
+    Memory,
+    RocksDb,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(Backend::Sqlite),
+            "memory" => Ok(Backend::Memory),
+            "rocksdb" => Ok(Backend::RocksDb),
+            other => Err(format!("unknown storage backend {:?}, expected sqlite, memory or rocksdb", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Sqlite => write!(f, "sqlite"),
+            Backend::Memory => write!(f, "memory"),
+            Backend::RocksDb => write!(f, "rocksdb"),
+        }
+    }
+}
+
+impl Backend {
+    pub fn default_path(&self) -> &'static str {
+        match self {
+            Backend::RocksDb => DEFAULT_ROCKSDB_PATH,
+            _ => DEFAULT_DB_PATH,
+        }
+    }
+}
+
+/// Opens the `backend` store at `path`, ignored by the in-memory store.
+pub async fn open(backend: Backend, path: &str) -> Result<Arc<dyn CiphertextStore>, StoreError> {
+    match backend {
+        Backend::Memory => println!("Opening memory store"),
+        _ => println!("Opening {} store at {}", backend, path),
+    }
+    match backend {
+        Backend::Sqlite => Ok(Arc::new(SqliteStore::open(path).await?)),
+        Backend::Memory => Ok(Arc::new(MemoryStore::new())),
+        #[cfg(feature = "rocksdb")]
+        Backend::RocksDb => Ok(Arc::new(RocksStore::open(path)?)),
+        #[cfg(not(feature = "rocksdb"))]
+        Backend::RocksDb => Err(StoreError::Storage("built without the rocksdb feature".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: u8) -> Write {
+        Write::Put { key: [key; 32], fhe_type: 4, ciphertext: vec![key], owner: None, key_id: "k".to_string() }
+    }
+
+    fn update(key: u8, version: i64) -> Write {
+        Write::Update { key: [key; 32], version, fhe_type: 4, ciphertext: vec![key, 1], key_id: "k".to_string() }
+    }
+
+    fn row(key: u8, version: i64) -> StoredRow {
+        StoredRow { key: [key; 32], fhe_type: 4, ciphertext: vec![key], owner: Some([9; 32]), version, key_id: None }
+    }
+
+    // Stages against a store holding `rows`
+    fn stage(writes: Vec<Write>, last_version: i64, rows: &[StoredRow]) -> Result<(Staged, Vec<i64>), StoreError> {
+        stage_batch(writes, last_version, |key| Ok(rows.iter().find(|row| row.key == *key).cloned()))
+    }
+
+    #[test]
+    fn numbers_writes_from_the_last_version() {
+        let (staged, versions) = stage(vec![put(1), update(2, 3), put(3)], 10, &[row(2, 3)]).unwrap();
+        assert_eq!(versions, vec![11, 12, 13]);
+        assert_eq!(staged.last_version, 13);
+        assert_eq!(staged.rows[&[2; 32]].version, 12);
+    }
+
+    #[test]
+    fn updates_keep_the_owner() {
+        let (staged, _) = stage(vec![update(2, 3)], 3, &[row(2, 3)]).unwrap();
+        assert_eq!(staged.rows[&[2; 32]].owner, Some([9; 32]));
+        assert_eq!(staged.rows[&[2; 32]].ciphertext, vec![2, 1]);
+    }
+
+    #[test]
+    fn puts_never_replace_a_row() {
+        assert!(matches!(stage(vec![put(2)], 3, &[row(2, 3)]), Err(StoreError::Exists(_))));
+        assert!(matches!(stage(vec![put(1), put(1)], 0, &[]), Err(StoreError::Exists(_))));
+    }
+
+    #[test]
+    fn stale_versions_conflict() {
+        assert!(matches!(stage(vec![update(2, 2)], 3, &[row(2, 3)]), Err(StoreError::Conflict(_))));
+        assert!(matches!(stage(vec![Write::Check { key: [2; 32], version: 2 }], 3, &[row(2, 3)]), Err(StoreError::Conflict(_))));
+        assert!(stage(vec![Write::Check { key: [2; 32], version: 3 }], 3, &[row(2, 3)]).is_ok());
+    }
+
+    #[test]
+    fn missing_rows_are_not_found() {
+        assert!(matches!(stage(vec![update(2, 1)], 3, &[]), Err(StoreError::NotFound(_))));
+        assert!(matches!(stage(vec![Write::Check { key: [2; 32], version: 1 }], 3, &[]), Err(StoreError::NotFound(_))));
+    }
+
+    #[test]
+    fn later_writes_see_earlier_ones() {
+        // The update reads the version the put gave, not the missing stored row
+        let (staged, versions) = stage(vec![put(1), update(1, 1), Write::Check { key: [1; 32], version: 2 }], 0, &[]).unwrap();
+        assert_eq!(versions, vec![1, 2]);
+        assert_eq!(staged.rows[&[1; 32]].version, 2);
+        assert!(matches!(stage(vec![update(2, 3), update(2, 3)], 3, &[row(2, 3)]), Err(StoreError::Conflict(_))));
+    }
+
+    #[test]
+    fn collects_applied_jobs() {
+        let writes = vec![put(1), Write::Applied { id: "job".to_string(), result: Some("{}".to_string()) }];
+        let (staged, versions) = stage(writes, 0, &[]).unwrap();
+        assert_eq!(versions, vec![1]);
+        assert_eq!(staged.applied, vec![("job".to_string(), Some("{}".to_string()))]);
+    }
+}
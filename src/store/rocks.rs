@@ -0,0 +1,345 @@
+use async_trait::async_trait;
+use rocksdb::{Direction, IteratorMode, Options, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use super::{stage_batch, CiphertextStore, StoreError, StoredJob, StoredRow, Write};
+
+// Key prefixes of the record kinds sharing the default column family
+const ROW_PREFIX: u8 = b'c';
+const NONCE_PREFIX: u8 = b'n';
+const EXPIRY_PREFIX: u8 = b'x';
+const JOB_PREFIX: u8 = b'j';
+const IDEMPOTENCY_PREFIX: u8 = b'i';
+// Last row version handed out, see StoredRow
+const VERSION_KEY: &[u8] = b"v";
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    fhe_type: u8,
+    ciphertext: Vec<u8>,
+    owner: Option<[u8; 32]>,
+    version: i64,
+    key_id: Option<String>,
+}
+
+fn row_key(key: &[u8; 32]) -> Vec<u8> {
+    [&[ROW_PREFIX][..], key].concat()
+}
+
+fn nonce_key(owner: &[u8; 32], nonce: u64) -> Vec<u8> {
+    [&[NONCE_PREFIX][..], owner, &nonce.to_be_bytes()].concat()
+}
+
+// Big endian with the sign bit flipped sorts expiries numerically
+fn expiry_key(expiry: i64, owner: &[u8; 32], nonce: u64) -> Vec<u8> {
+    let expiry = (expiry as u64) ^ (1 << 63);
+    [&[EXPIRY_PREFIX][..], &expiry.to_be_bytes(), owner, &nonce.to_be_bytes()].concat()
+}
+
+fn job_key(id: &str) -> Vec<u8> {
+    [&[JOB_PREFIX][..], id.as_bytes()].concat()
+}
+
+fn idempotency_key(key: &str) -> Vec<u8> {
+    [&[IDEMPOTENCY_PREFIX][..], key.as_bytes()].concat()
+}
+
+fn storage<E: std::fmt::Display>(e: E) -> StoreError {
+    StoreError::Storage(e.to_string())
+}
+
+fn decode(key: [u8; 32], value: &[u8]) -> Result<StoredRow, StoreError> {
+    let record: Record = bincode::deserialize(value).map_err(storage)?;
+    Ok(StoredRow {
+        key,
+        fhe_type: record.fhe_type,
+        ciphertext: record.ciphertext,
+        owner: record.owner,
+        version: record.version,
+        key_id: record.key_id,
+    })
+}
+
+fn encode(row: StoredRow) -> Result<Vec<u8>, StoreError> {
+    let record = Record {
+        fhe_type: row.fhe_type,
+        ciphertext: row.ciphertext,
+        owner: row.owner,
+        version: row.version,
+        key_id: row.key_id,
+    };
+    bincode::serialize(&record).map_err(storage)
+}
+
+struct Inner {
+    db: DB,
+    // RocksDB has no read-modify-write transactions here, so writers take
+    // turns between reading versions and writing the batch
+    write_lock: Mutex<()>,
+}
+
+impl Inner {
+    fn get(&self, key: &[u8; 32]) -> Result<Option<StoredRow>, StoreError> {
+        self.db.get(row_key(key))
+            .map_err(storage)?
+            .map(|value| decode(*key, &value))
+            .transpose()
+    }
+
+    fn last_version(&self) -> Result<i64, StoreError> {
+        match self.db.get(VERSION_KEY).map_err(storage)? {
+            Some(value) => Ok(i64::from_be_bytes(<[u8; 8]>::try_from(&value[..]).map_err(storage)?)),
+            None => Ok(0),
+        }
+    }
+
+    fn get_job(&self, id: &str) -> Result<Option<StoredJob>, StoreError> {
+        self.db.get(job_key(id))
+            .map_err(storage)?
+            .map(|value| bincode::deserialize(&value).map_err(storage))
+            .transpose()
+    }
+
+    fn jobs(&self) -> Result<Vec<StoredJob>, StoreError> {
+        let start = [JOB_PREFIX];
+        let mut jobs = Vec::new();
+        for item in self.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            let (db_key, value) = item.map_err(storage)?;
+            if db_key.first() != Some(&JOB_PREFIX) {
+                break;
+            }
+            jobs.push(bincode::deserialize(&value).map_err(storage)?);
+        }
+        Ok(jobs)
+    }
+}
+
+/// Rows in an embedded RocksDB database, enabled by the `rocksdb` feature.
+pub struct RocksStore {
+    inner: Arc<Inner>,
+}
+
+impl RocksStore {
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        std::fs::create_dir_all(path).map_err(storage)?;
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        let db = DB::open(&options, path).map_err(storage)?;
+        seed_last_version(&db)?;
+        Ok(Self { inner: Arc::new(Inner { db, write_lock: Mutex::new(()) }) })
+    }
+
+    // RocksDB calls block on disk I/O, keep them off the async workers
+    async fn blocking<R, F>(&self, f: F) -> Result<R, StoreError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Inner) -> Result<R, StoreError> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || f(&inner))
+            .await
+            .map_err(storage)?
+    }
+}
+
+// Databases written before the counter existed start it above every stored version
+fn seed_last_version(db: &DB) -> Result<(), StoreError> {
+    if db.get_pinned(VERSION_KEY).map_err(storage)?.is_some() {
+        return Ok(());
+    }
+    let start = [ROW_PREFIX];
+    let mut last = 0;
+    for item in db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+        let (db_key, value) = item.map_err(storage)?;
+        if db_key.first() != Some(&ROW_PREFIX) {
+            break;
+        }
+        let record: Record = bincode::deserialize(&value).map_err(storage)?;
+        last = last.max(record.version);
+    }
+    db.put(VERSION_KEY, last.to_be_bytes()).map_err(storage)
+}
+
+#[async_trait]
+impl CiphertextStore for RocksStore {
+    async fn get(&self, key: [u8; 32]) -> Result<Option<StoredRow>, StoreError> {
+        self.blocking(move |inner| inner.get(&key)).await
+    }
+
+    async fn get_many(&self, keys: Vec<[u8; 32]>) -> Result<Vec<StoredRow>, StoreError> {
+        self.blocking(move |inner| {
+            let values = inner.db.multi_get(keys.iter().map(row_key));
+            let mut rows = Vec::with_capacity(keys.len());
+            for (key, value) in keys.into_iter().zip(values) {
+                if let Some(value) = value.map_err(storage)? {
+                    rows.push(decode(key, &value)?);
+                }
+            }
+            Ok(rows)
+        }).await
+    }
+
+    async fn batch(&self, writes: Vec<Write>) -> Result<Vec<i64>, StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let (staged, versions) = stage_batch(writes, inner.last_version()?, |key| inner.get(key))?;
+            let mut batch = WriteBatch::default();
+            batch.put(VERSION_KEY, staged.last_version.to_be_bytes());
+            for (key, row) in staged.rows {
+                batch.put(row_key(&key), encode(row)?);
+            }
+            for (id, result) in staged.applied {
+                if let Some(mut job) = inner.get_job(&id)? {
+                    job.applied = true;
+                    job.result = result;
+                    batch.put(job_key(&id), bincode::serialize(&job).map_err(storage)?);
+                }
+            }
+            inner.db.write(batch).map_err(storage)?;
+            Ok(versions)
+        }).await
+    }
+
+    async fn delete(&self, key: [u8; 32]) -> Result<bool, StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let existed = inner.db.get_pinned(row_key(&key)).map_err(storage)?.is_some();
+            inner.db.delete(row_key(&key)).map_err(storage)?;
+            Ok(existed)
+        }).await
+    }
+
+    async fn scan(&self, after: Option<[u8; 32]>, limit: usize) -> Result<Vec<StoredRow>, StoreError> {
+        self.blocking(move |inner| {
+            let start = match after {
+                Some(key) => row_key(&key),
+                None => vec![ROW_PREFIX],
+            };
+            let mut rows = Vec::with_capacity(limit);
+            for item in inner.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+                let (db_key, value) = item.map_err(storage)?;
+                if db_key.first() != Some(&ROW_PREFIX) || rows.len() == limit {
+                    break;
+                }
+                let key = <[u8; 32]>::try_from(&db_key[1..]).map_err(storage)?;
+                if Some(key) == after {
+                    continue;
+                }
+                rows.push(decode(key, &value)?);
+            }
+            Ok(rows)
+        }).await
+    }
+
+    async fn consume_nonce(&self, owner: [u8; 32], nonce: u64, expiry: i64, now: i64) -> Result<bool, StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let mut batch = WriteBatch::default();
+            // The expiry index lists nonces oldest first, prune until the first live one
+            let start = [EXPIRY_PREFIX];
+            for item in inner.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+                let (db_key, _) = item.map_err(storage)?;
+                if db_key.len() != 1 + 8 + 32 + 8 || db_key[0] != EXPIRY_PREFIX {
+                    break;
+                }
+                let expired_at = u64::from_be_bytes(<[u8; 8]>::try_from(&db_key[1..9]).map_err(storage)?) ^ (1 << 63);
+                if expired_at as i64 >= now {
+                    break;
+                }
+                let expired_owner = <[u8; 32]>::try_from(&db_key[9..41]).map_err(storage)?;
+                let expired_nonce = u64::from_be_bytes(<[u8; 8]>::try_from(&db_key[41..49]).map_err(storage)?);
+                batch.delete(nonce_key(&expired_owner, expired_nonce));
+                batch.delete(&db_key);
+            }
+            let fresh = inner.db.get_pinned(nonce_key(&owner, nonce)).map_err(storage)?.is_none();
+            if fresh {
+                batch.put(nonce_key(&owner, nonce), expiry.to_be_bytes());
+                batch.put(expiry_key(expiry, &owner, nonce), b"");
+            }
+            inner.db.write(batch).map_err(storage)?;
+            Ok(fresh)
+        }).await
+    }
+
+    async fn insert_job(&self, job: StoredJob) -> Result<StoredJob, StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let mut batch = WriteBatch::default();
+            if let Some(key) = &job.idempotency_key {
+                if let Some(id) = inner.db.get(idempotency_key(key)).map_err(storage)? {
+                    let id = String::from_utf8(id).map_err(storage)?;
+                    if let Some(earlier) = inner.get_job(&id)? {
+                        return Ok(earlier);
+                    }
+                }
+                batch.put(idempotency_key(key), job.id.as_bytes());
+            }
+            batch.put(job_key(&job.id), bincode::serialize(&job).map_err(storage)?);
+            inner.db.write(batch).map_err(storage)?;
+            Ok(job)
+        }).await
+    }
+
+    async fn update_job(&self, job: StoredJob) -> Result<(), StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let Some(mut stored) = inner.get_job(&job.id)? else {
+                return Ok(());
+            };
+            stored.status = job.status;
+            stored.result = job.result;
+            stored.error = job.error;
+            stored.finished = job.finished;
+            stored.updated_at = job.updated_at;
+            inner.db.put(job_key(&stored.id), bincode::serialize(&stored).map_err(storage)?).map_err(storage)
+        }).await
+    }
+
+    async fn get_job(&self, id: String) -> Result<Option<StoredJob>, StoreError> {
+        self.blocking(move |inner| inner.get_job(&id)).await
+    }
+
+    async fn pending_jobs(&self) -> Result<Vec<StoredJob>, StoreError> {
+        self.blocking(|inner| {
+            let mut pending: Vec<StoredJob> = inner.jobs()?.into_iter().filter(|job| !job.finished).collect();
+            pending.sort_by_key(|job| job.created_at);
+            Ok(pending)
+        }).await
+    }
+
+    async fn prune_jobs(&self, before: i64, keyed_before: i64) -> Result<usize, StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let mut batch = WriteBatch::default();
+            let mut pruned = 0;
+            for job in inner.jobs()? {
+                if job.expired(before, keyed_before) {
+                    batch.delete(job_key(&job.id));
+                    if let Some(key) = &job.idempotency_key {
+                        batch.delete(idempotency_key(key));
+                    }
+                    pruned += 1;
+                }
+            }
+            inner.db.write(batch).map_err(storage)?;
+            Ok(pruned)
+        }).await
+    }
+
+    async fn release_job_key(&self, id: String) -> Result<bool, StoreError> {
+        self.blocking(move |inner| {
+            let _guard = inner.write_lock.lock().map_err(storage)?;
+            let Some(mut job) = inner.get_job(&id)? else {
+                return Ok(false);
+            };
+            let Some(key) = job.idempotency_key.take().filter(|_| !job.applied) else {
+                return Ok(false);
+            };
+            let mut batch = WriteBatch::default();
+            batch.delete(idempotency_key(&key));
+            batch.put(job_key(&id), bincode::serialize(&job).map_err(storage)?);
+            inner.db.write(batch).map_err(storage)?;
+            Ok(true)
+        }).await
+    }
+}
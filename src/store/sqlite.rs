@@ -0,0 +1,433 @@
+use async_trait::async_trait;
+use rusqlite::OptionalExtension;
+use std::fs;
+use std::path::Path;
+use tokio_rusqlite::Connection;
+use super::{CiphertextStore, RowInfo, StoreError, StoredJob, StoredRow, Write};
+
+// Inserts nothing when the key is taken, an existing row is never replaced
+const INSERT_CIPHERTEXT: &str =
+    "INSERT INTO computations (key, ciphertext, owner, fhe_type, key_id, version) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+     ON CONFLICT(key) DO NOTHING";
+
+// Every row write takes the next version, see StoredRow
+const NEXT_VERSION: &str = "UPDATE row_versions SET last = last + 1 WHERE id = 0 RETURNING last";
+
+const SELECT_ROW: &str = "SELECT key, fhe_type, ciphertext, owner, version, key_id FROM computations";
+
+const SELECT_JOB: &str =
+    "SELECT id, idempotency_key, kind, request, status, result, error, finished, applied, notify, created_at, updated_at FROM jobs";
+
+fn read_row(row: &rusqlite::Row) -> rusqlite::Result<StoredRow> {
+    Ok(StoredRow {
+        key: row.get(0)?,
+        fhe_type: row.get(1)?,
+        ciphertext: row.get(2)?,
+        owner: row.get(3)?,
+        version: row.get(4)?,
+        key_id: row.get(5)?,
+    })
+}
+
+fn read_job(row: &rusqlite::Row) -> rusqlite::Result<StoredJob> {
+    Ok(StoredJob {
+        id: row.get(0)?,
+        idempotency_key: row.get(1)?,
+        kind: row.get(2)?,
+        request: row.get(3)?,
+        status: row.get(4)?,
+        result: row.get(5)?,
+        error: row.get(6)?,
+        finished: row.get(7)?,
+        applied: row.get(8)?,
+        notify: row.get(9)?,
+        created_at: row.get(10)?,
+        updated_at: row.get(11)?,
+    })
+}
+
+impl From<tokio_rusqlite::Error> for StoreError {
+    fn from(e: tokio_rusqlite::Error) -> Self {
+        StoreError::Storage(e.to_string())
+    }
+}
+
+/// The `computations`, `decrypt_nonces` and `jobs` tables of a SQLite
+/// database, shared through one connection in WAL mode.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub async fn open(path: &str) -> Result<Self, StoreError> {
+        if let Some(parent) = Path::new(path).parent() {
+            println!("Creating directory at: {:?}", parent);
+            fs::create_dir_all(parent).map_err(|e| StoreError::Storage(e.to_string()))?;
+        }
+        let conn = Connection::open(path).await?;
+        init_db(&conn).await?;
+        Ok(Self { conn })
+    }
+}
+
+async fn init_db(conn: &Connection) -> Result<(), StoreError> {
+    conn.call(|conn| {
+        // Readers no longer block behind the single writer, and commits only
+        // fsync the log instead of the whole database
+        let journal_mode: String = conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        println!("SQLite journal mode: {}", journal_mode);
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS computations (
+                key CHAR(32) NOT NULL PRIMARY KEY,
+                ciphertext BLOB NOT NULL,
+                owner BLOB,
+                fhe_type INTEGER NOT NULL DEFAULT 4,
+                version INTEGER NOT NULL DEFAULT 0,
+                key_id TEXT
+            )",
+            (),
+        ).map_err(|e| {
+            println!("Database error: {}", e);
+            e
+        })?;
+        // Databases created by earlier versions lack the newer columns, rows
+        // written before type tags existed are all FheUint64 and rows written
+        // before key IDs have none
+        for (column, definition) in [
+            ("owner", "owner BLOB"),
+            ("fhe_type", "fhe_type INTEGER NOT NULL DEFAULT 4"),
+            ("version", "version INTEGER NOT NULL DEFAULT 0"),
+            ("key_id", "key_id TEXT"),
+        ] {
+            let exists = conn
+                .prepare("SELECT 1 FROM pragma_table_info('computations') WHERE name = ?")?
+                .exists([column])?;
+            if !exists {
+                conn.execute(&format!("ALTER TABLE computations ADD COLUMN {}", definition), ())?;
+            }
+        }
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS row_versions (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 0),
+                last INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        // Databases written before the counter existed start it above every stored version
+        conn.execute(
+            "INSERT OR IGNORE INTO row_versions (id, last) SELECT 0, COALESCE(MAX(version), 0) FROM computations",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS decrypt_nonces (
+                owner BLOB NOT NULL,
+                nonce INTEGER NOT NULL,
+                expiry INTEGER NOT NULL,
+                PRIMARY KEY (owner, nonce)
+            )",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id TEXT NOT NULL PRIMARY KEY,
+                idempotency_key TEXT UNIQUE,
+                kind TEXT NOT NULL,
+                request TEXT NOT NULL,
+                status TEXT NOT NULL,
+                result TEXT,
+                error TEXT,
+                finished INTEGER NOT NULL DEFAULT 0,
+                applied INTEGER NOT NULL DEFAULT 0,
+                notify INTEGER NOT NULL DEFAULT 1,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        // Jobs stored before the flag was kept were posted to the callback when resumed
+        let exists = conn
+            .prepare("SELECT 1 FROM pragma_table_info('jobs') WHERE name = 'notify'")?
+            .exists(())?;
+        if !exists {
+            conn.execute("ALTER TABLE jobs ADD COLUMN notify INTEGER NOT NULL DEFAULT 1", ())?;
+        }
+        // Earlier versions stored the decrypted balance a withdraw returned,
+        // readable by anyone holding the job ID
+        conn.execute("UPDATE jobs SET result = NULL WHERE kind = 'withdraw' AND result IS NOT NULL", ())?;
+        // Pruning only looks at finished jobs, by age
+        conn.execute("CREATE INDEX IF NOT EXISTS jobs_finished ON jobs (finished, updated_at)", ())?;
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl CiphertextStore for SqliteStore {
+    async fn get(&self, key: [u8; 32]) -> Result<Option<StoredRow>, StoreError> {
+        self.conn.call(move |conn| {
+            conn.prepare_cached(&format!("{} WHERE key = ?", SELECT_ROW))?
+                .query_row([key], read_row)
+                .optional()
+        }).await.map_err(Into::into)
+    }
+
+    // One call for all keys instead of a round trip to the connection thread each
+    async fn get_many(&self, keys: Vec<[u8; 32]>) -> Result<Vec<StoredRow>, StoreError> {
+        self.conn.call(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!("{} WHERE key = ?", SELECT_ROW))?;
+            let mut rows = Vec::with_capacity(keys.len());
+            for key in keys {
+                rows.extend(stmt.query_row([key], read_row).optional()?);
+            }
+            Ok(rows)
+        }).await.map_err(Into::into)
+    }
+
+    // Leaves the ciphertexts, by far the largest columns, unread
+    async fn get_info(&self, keys: Vec<[u8; 32]>) -> Result<Vec<RowInfo>, StoreError> {
+        self.conn.call(move |conn| {
+            let mut stmt = conn.prepare_cached("SELECT key, fhe_type, owner, version FROM computations WHERE key = ?")?;
+            let mut rows = Vec::with_capacity(keys.len());
+            for key in keys {
+                rows.extend(stmt.query_row([key], |row| Ok(RowInfo {
+                    key: row.get(0)?,
+                    fhe_type: row.get(1)?,
+                    owner: row.get(2)?,
+                    version: row.get(3)?,
+                })).optional()?);
+            }
+            Ok(rows)
+        }).await.map_err(Into::into)
+    }
+
+    async fn batch(&self, writes: Vec<Write>) -> Result<Vec<i64>, StoreError> {
+        let outcome = self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let mut versions = Vec::with_capacity(writes.len());
+            for write in writes {
+                let (key, version) = match write {
+                    Write::Put { key, fhe_type, ciphertext, owner, key_id } => {
+                        let version: i64 = tx.prepare_cached(NEXT_VERSION)?.query_row((), |row| row.get(0))?;
+                        let inserted = tx.prepare_cached(INSERT_CIPHERTEXT)?
+                            .execute((key, ciphertext, owner, fhe_type, key_id, version))?;
+                        if inserted == 0 {
+                            return Ok(Err(StoreError::Exists(key)));
+                        }
+                        versions.push(version);
+                        continue;
+                    }
+                    Write::Update { key, version, fhe_type, ciphertext, key_id } => {
+                        let next: i64 = tx.prepare_cached(NEXT_VERSION)?.query_row((), |row| row.get(0))?;
+                        let rows_affected = tx.prepare_cached(
+                            "UPDATE computations SET ciphertext = ?, fhe_type = ?, key_id = ?, version = ?
+                             WHERE key = ? AND version = ?"
+                        )?.execute((ciphertext, fhe_type, key_id, next, key, version))?;
+                        if rows_affected == 1 {
+                            versions.push(next);
+                            continue;
+                        }
+                        (key, version)
+                    }
+                    Write::Check { key, version } => (key, version),
+                    Write::Applied { id, result } => {
+                        tx.prepare_cached("UPDATE jobs SET applied = 1, result = ? WHERE id = ?")?.execute((result, id))?;
+                        continue;
+                    }
+                };
+                let current: Option<i64> = tx.prepare_cached("SELECT version FROM computations WHERE key = ?")?
+                    .query_row([key], |row| row.get(0))
+                    .optional()?;
+                // Dropping the transaction rolls back the writes before this one
+                match current {
+                    None => return Ok(Err(StoreError::NotFound(key))),
+                    Some(current) if current != version => return Ok(Err(StoreError::Conflict(key))),
+                    Some(_) => {}
+                }
+            }
+            tx.commit()?;
+            Ok(Ok(versions))
+        }).await?;
+        outcome
+    }
+
+    async fn delete(&self, key: [u8; 32]) -> Result<bool, StoreError> {
+        self.conn.call(move |conn| {
+            let deleted = conn.prepare_cached("DELETE FROM computations WHERE key = ?")?.execute([key])?;
+            Ok(deleted == 1)
+        }).await.map_err(Into::into)
+    }
+
+    async fn scan(&self, after: Option<[u8; 32]>, limit: usize) -> Result<Vec<StoredRow>, StoreError> {
+        self.conn.call(move |conn| {
+            // Keys are fixed length blobs, so byte order is key order
+            let mut stmt = conn.prepare_cached(&format!("{} WHERE key > ? ORDER BY key LIMIT ?", SELECT_ROW))?;
+            let after = after.map(|key| key.to_vec()).unwrap_or_default();
+            let rows = stmt.query_map((after, limit as i64), read_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        }).await.map_err(Into::into)
+    }
+
+    async fn consume_nonce(&self, owner: [u8; 32], nonce: u64, expiry: i64, now: i64) -> Result<bool, StoreError> {
+        self.conn.call(move |conn| {
+            conn.prepare_cached("DELETE FROM decrypt_nonces WHERE expiry < ?")?.execute([now])?;
+            let inserted = conn
+                .prepare_cached("INSERT OR IGNORE INTO decrypt_nonces (owner, nonce, expiry) VALUES (?1, ?2, ?3)")?
+                .execute((owner, nonce as i64, expiry))?;
+            Ok(inserted == 1)
+        }).await.map_err(Into::into)
+    }
+
+    async fn insert_job(&self, job: StoredJob) -> Result<StoredJob, StoreError> {
+        let outcome = self.conn.call(move |conn| {
+            let inserted = conn.prepare_cached(
+                "INSERT OR IGNORE INTO jobs (id, idempotency_key, kind, request, status, result, error, finished, applied, notify, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)"
+            )?.execute(rusqlite::params![
+                job.id, job.idempotency_key, job.kind, job.request, job.status, job.result, job.error,
+                job.finished, job.applied, job.notify, job.created_at, job.updated_at,
+            ])?;
+            if inserted == 1 {
+                return Ok(Ok(job));
+            }
+            // Job IDs are random, so the idempotency key clashed, unless the
+            // same job was inserted twice
+            let earlier = match &job.idempotency_key {
+                Some(key) => conn.prepare_cached(&format!("{} WHERE idempotency_key = ?", SELECT_JOB))?
+                    .query_row([key], read_job)
+                    .optional()?,
+                None => None,
+            };
+            Ok(earlier.ok_or_else(|| StoreError::Storage(format!("job {} is already stored", job.id))))
+        }).await?;
+        outcome
+    }
+
+    async fn update_job(&self, job: StoredJob) -> Result<(), StoreError> {
+        self.conn.call(move |conn| {
+            conn.prepare_cached(
+                "UPDATE jobs SET status = ?, result = ?, error = ?, finished = ?, updated_at = ? WHERE id = ?"
+            )?.execute((job.status, job.result, job.error, job.finished, job.updated_at, job.id))?;
+            Ok(())
+        }).await.map_err(Into::into)
+    }
+
+    async fn get_job(&self, id: String) -> Result<Option<StoredJob>, StoreError> {
+        self.conn.call(move |conn| {
+            conn.prepare_cached(&format!("{} WHERE id = ?", SELECT_JOB))?
+                .query_row([id], read_job)
+                .optional()
+        }).await.map_err(Into::into)
+    }
+
+    async fn pending_jobs(&self) -> Result<Vec<StoredJob>, StoreError> {
+        self.conn.call(|conn| {
+            let mut stmt = conn.prepare_cached(&format!("{} WHERE finished = 0 ORDER BY created_at", SELECT_JOB))?;
+            let jobs = stmt.query_map((), read_job)?.collect::<Result<Vec<_>, _>>()?;
+            Ok(jobs)
+        }).await.map_err(Into::into)
+    }
+
+    async fn prune_jobs(&self, before: i64, keyed_before: i64) -> Result<usize, StoreError> {
+        self.conn.call(move |conn| {
+            let pruned = conn.prepare_cached(
+                "DELETE FROM jobs WHERE finished = 1 AND updated_at < ? AND (idempotency_key IS NULL OR created_at < ?)"
+            )?.execute([before, keyed_before])?;
+            Ok(pruned)
+        }).await.map_err(Into::into)
+    }
+
+    async fn release_job_key(&self, id: String) -> Result<bool, StoreError> {
+        self.conn.call(move |conn| {
+            let released = conn.prepare_cached(
+                "UPDATE jobs SET idempotency_key = NULL WHERE id = ? AND applied = 0 AND idempotency_key IS NOT NULL"
+            )?.execute([id])?;
+            Ok(released == 1)
+        }).await.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: u8) -> Write {
+        Write::Put { key: [key; 32], fhe_type: 4, ciphertext: vec![key], owner: Some([key; 32]), key_id: "k".to_string() }
+    }
+
+    #[tokio::test]
+    async fn batches_are_atomic() {
+        let store = SqliteStore::open(":memory:").await.unwrap();
+        assert_eq!(store.batch(vec![put(1)]).await.unwrap(), vec![1]);
+        let writes = vec![put(2), put(1)];
+        assert!(matches!(store.batch(writes).await, Err(StoreError::Exists(_))));
+        assert!(store.get([2; 32]).await.unwrap().is_none());
+        let row = store.get([1; 32]).await.unwrap().unwrap();
+        assert_eq!((row.version, row.owner, row.key_id.as_deref()), (1, Some([1; 32]), Some("k")));
+    }
+
+    #[tokio::test]
+    async fn stored_again_rows_get_new_versions() {
+        let store = SqliteStore::open(":memory:").await.unwrap();
+        store.batch(vec![put(1)]).await.unwrap();
+        assert!(store.delete([1; 32]).await.unwrap());
+        assert_eq!(store.batch(vec![put(1)]).await.unwrap(), vec![2]);
+        let update = Write::Update { key: [1; 32], version: 2, fhe_type: 4, ciphertext: vec![7], key_id: "k".to_string() };
+        assert_eq!(store.batch(vec![update]).await.unwrap(), vec![3]);
+        let stale = vec![Write::Check { key: [1; 32], version: 2 }];
+        assert!(matches!(store.batch(stale).await, Err(StoreError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn jobs_keep_their_idempotency_key_once_applied() {
+        let store = SqliteStore::open(":memory:").await.unwrap();
+        let job = StoredJob {
+            id: "a".to_string(),
+            idempotency_key: Some("key".to_string()),
+            kind: "op".to_string(),
+            request: "{}".to_string(),
+            status: "pending".to_string(),
+            result: None,
+            error: None,
+            finished: false,
+            applied: false,
+            notify: true,
+            created_at: 1,
+            updated_at: 1,
+        };
+        store.insert_job(job.clone()).await.unwrap();
+        let again = StoredJob { id: "b".to_string(), ..job };
+        assert_eq!(store.insert_job(again).await.unwrap().id, "a");
+        store.batch(vec![put(1), Write::Applied { id: "a".to_string(), result: Some("[1]".to_string()) }]).await.unwrap();
+        let stored = store.get_job("a".to_string()).await.unwrap().unwrap();
+        assert!(stored.applied && stored.notify);
+        assert_eq!(stored.result.as_deref(), Some("[1]"));
+        assert!(!store.release_job_key("a".to_string()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn jobs_without_a_key_are_inserted_once() {
+        let store = SqliteStore::open(":memory:").await.unwrap();
+        let job = StoredJob {
+            id: "a".to_string(),
+            idempotency_key: None,
+            kind: "op".to_string(),
+            request: "{}".to_string(),
+            status: "pending".to_string(),
+            result: None,
+            error: None,
+            finished: false,
+            applied: false,
+            notify: false,
+            created_at: 1,
+            updated_at: 1,
+        };
+        assert_eq!(store.insert_job(job.clone()).await.unwrap().id, "a");
+        match store.insert_job(job).await {
+            Err(StoreError::Storage(e)) => assert_eq!(e, "job a is already stored"),
+            _ => panic!("a second insert of job a must fail"),
+        }
+    }
+}
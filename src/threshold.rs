@@ -0,0 +1,270 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+use crate::ciphertext::TypedCiphertext;
+use crate::keys;
+use crate::types::{FheType, Scalar};
+
+/// Secret the server authenticates to the decryptors with. Only read from
+/// the environment, like the key passphrase.
+pub const TOKEN_ENV: &str = "DECRYPTOR_TOKEN";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum DecryptionError {
+    /// Fewer than `threshold` decryptors answered
+    InsufficientParties { available: usize, threshold: usize },
+}
+
+impl std::fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecryptionError::InsufficientParties { available, threshold } =>
+                write!(f, "{} decryptors available, {} required", available, threshold),
+        }
+    }
+}
+
+/// Body of `POST /partial_decrypt`, in bincode.
+#[derive(Serialize, Deserialize)]
+pub struct PartialRequest {
+    /// LWE mask of every block of the ciphertext
+    pub masks: Vec<Vec<u64>>,
+    /// Sub-shares the decryptor applies, each exactly once across the parties asked
+    pub sub_shares: Vec<usize>,
+}
+
+/// Answer to `POST /partial_decrypt`, in bincode.
+#[derive(Serialize, Deserialize)]
+pub struct PartialResponse {
+    pub party: usize,
+    /// Key set the decryptor's share belongs to
+    pub key_id: String,
+    /// One noise-flooded partial decryption per block
+    pub partials: Vec<u64>,
+}
+
+/// A decrypted value of `fhe_type`, in little-endian two's complement at the
+/// width of the type, one byte for a boolean.
+pub struct Plaintext {
+    pub fhe_type: FheType,
+    pub bytes: Vec<u8>,
+}
+
+impl Plaintext {
+    /// The value as JSON represents it: booleans and 64-bit or narrower
+    /// integers as such, wider integers as decimal strings.
+    pub fn to_scalar(&self) -> Scalar {
+        let mut word = [0u8; 32];
+        word[..self.bytes.len()].copy_from_slice(&self.bytes);
+        let value = U256::from_little_endian(&word);
+        let bits = self.fhe_type.bits();
+        let negative = self.fhe_type.is_signed() && value.bit(bits as usize - 1);
+        match (self.fhe_type, bits) {
+            (FheType::Bool, _) => Scalar::Bool(!value.is_zero()),
+            (_, 128..) if negative => {
+                let magnitude = match bits {
+                    256 => (!value).overflowing_add(U256::one()).0,
+                    _ => (U256::one() << bits) - value,
+                };
+                Scalar::Decimal(format!("-{}", magnitude))
+            }
+            (_, 128..) => Scalar::Decimal(value.to_string()),
+            // Sign-extends the narrower types' bit pattern to 64 bits
+            _ if self.fhe_type.is_signed() => Scalar::Signed((value.low_u64() << (64 - bits)) as i64 >> (64 - bits)),
+            _ => Scalar::Unsigned(value.low_u64()),
+        }
+    }
+}
+
+struct Party {
+    index: usize,
+    url: String,
+    held: Vec<usize>,
+}
+
+/// Gathers t-of-n partial decryptions from `decryptor` processes that each
+/// hold one `KeyShare`, so the coprocessor never holds the secret key or any
+/// share of it.
+///
+/// Party `i` is the decryptor at the `i`-th URL. When a selected party does
+/// not answer, the request is repeated with the next one instead.
+pub struct ThresholdDecryptor {
+    key_id: String,
+    threshold: usize,
+    token: Arc<str>,
+    agent: ureq::Agent,
+    parties: Vec<Arc<Party>>,
+}
+
+impl ThresholdDecryptor {
+    /// Decrypts with `threshold` of the decryptors at `urls`, whose shares must
+    /// belong to the key set `key_id`.
+    pub fn connect(urls: &[String], threshold: usize, token: String, key_id: String) -> Result<Self, String> {
+        if threshold == 0 || threshold > urls.len() {
+            return Err(format!("Decryption threshold {} of {} decryptors", threshold, urls.len()));
+        }
+        let parties = urls.iter().enumerate().map(|(index, url)| Arc::new(Party {
+            index,
+            url: format!("{}/partial_decrypt", url.trim_end_matches('/')),
+            held: keys::held_sub_shares(index, urls.len(), threshold),
+        })).collect();
+        Ok(Self {
+            key_id,
+            threshold,
+            token: token.into(),
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            parties,
+        })
+    }
+
+    /// Decrypts a value of any type.
+    pub async fn decrypt(&self, value: TypedCiphertext) -> Result<Plaintext, DecryptionError> {
+        let fhe_type = value.fhe_type();
+        let blocks = value.into_blocks();
+        let masks: Vec<Vec<u64>> = blocks.iter()
+            .map(|block| block.ct.get_mask().as_ref().to_vec())
+            .collect();
+
+        let mut failed: Vec<usize> = Vec::new();
+        let partials = loop {
+            let selected: Vec<&Arc<Party>> = self.parties.iter()
+                .filter(|party| !failed.contains(&party.index))
+                .take(self.threshold)
+                .collect();
+            if selected.len() < self.threshold {
+                return Err(DecryptionError::InsufficientParties {
+                    available: self.parties.len() - failed.len(),
+                    threshold: self.threshold,
+                });
+            }
+
+            // Every sub-share is applied exactly once, by the first selected party holding it
+            let mut assigned: Vec<usize> = Vec::new();
+            let pending: Vec<_> = selected.iter().map(|party| {
+                let sub_shares: Vec<usize> = party.held.iter()
+                    .filter(|index| !assigned.contains(index))
+                    .copied()
+                    .collect();
+                assigned.extend(&sub_shares);
+                let request = PartialRequest { masks: masks.clone(), sub_shares };
+                let (party, agent, token) = ((*party).clone(), self.agent.clone(), self.token.clone());
+                (party.index, tokio::task::spawn_blocking(move || request_partial(&agent, &party, &token, &request)))
+            }).collect();
+
+            let mut partials = Vec::with_capacity(pending.len());
+            for (index, task) in pending {
+                let outcome = task.await.unwrap_or_else(|e| Err(e.to_string()));
+                match outcome.and_then(|response| self.check(index, blocks.len(), response)) {
+                    Ok(partial) => partials.push(partial),
+                    Err(e) => {
+                        println!("Decryptor {} failed, asking another: {}", index, e);
+                        failed.push(index);
+                    }
+                }
+            }
+            if partials.len() == self.threshold {
+                break partials;
+            }
+        };
+
+        // Blocks are added rather than or-ed in, so leftover carries propagate
+        let mut result = U256::zero();
+        let mut shift = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            let phase = partials.iter()
+                .fold(*block.ct.get_body().data, |acc, partial: &Vec<u64>| acc.wrapping_sub(partial[i]));
+            // Same decoding as shortint's decrypt_message_and_carry
+            let delta = (1u64 << 63) / (block.message_modulus.0 * block.carry_modulus.0);
+            let rounding = (phase & (delta >> 1)) << 1;
+            let decoded = phase.wrapping_add(rounding) / delta;
+            if shift < 256 {
+                result = result.overflowing_add(U256::from(decoded) << shift).0;
+            }
+            shift += block.message_modulus.0.ilog2() as usize;
+        }
+        let mut word = [0u8; 32];
+        result.to_little_endian(&mut word);
+        let width = (fhe_type.bits() as usize).div_ceil(8);
+        Ok(Plaintext { fhe_type, bytes: word[..width].to_vec() })
+    }
+
+    // A misconfigured decryptor answers for the wrong party or key set, which
+    // would decode to garbage instead of failing
+    fn check(&self, index: usize, blocks: usize, response: PartialResponse) -> Result<Vec<u64>, String> {
+        if response.party != index {
+            return Err(format!("answered as party {}", response.party));
+        }
+        if response.key_id != self.key_id {
+            return Err(format!("holds a share of key set {}, the server uses {}", response.key_id, self.key_id));
+        }
+        if response.partials.len() != blocks {
+            return Err(format!("answered {} blocks of {}", response.partials.len(), blocks));
+        }
+        Ok(response.partials)
+    }
+}
+
+fn request_partial(agent: &ureq::Agent, party: &Party, token: &str, request: &PartialRequest) -> Result<PartialResponse, String> {
+    let body = bincode::serialize(request).map_err(|e| e.to_string())?;
+    let response = agent.post(&party.url)
+        .set("Authorization", &format!("Bearer {}", token))
+        .set("Content-Type", "application/octet-stream")
+        .send_bytes(&body)
+        .map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data).map_err(|e| e.to_string())?;
+    bincode::deserialize(&data).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // As JSON, which tells a boolean and a decimal string from numbers
+    fn scalar(fhe_type: FheType, bytes: &[u8]) -> serde_json::Value {
+        serde_json::to_value(Plaintext { fhe_type, bytes: bytes.to_vec() }.to_scalar()).unwrap()
+    }
+
+    #[test]
+    fn plaintexts_read_at_their_width() {
+        assert_eq!(scalar(FheType::Bool, &[1]), json!(true));
+        assert_eq!(scalar(FheType::Uint8, &[0xff]), json!(255));
+        assert_eq!(scalar(FheType::Int8, &[0xff]), json!(-1));
+        assert_eq!(scalar(FheType::Int16, &[0x00, 0x80]), json!(-32768));
+        assert_eq!(scalar(FheType::Uint64, &u64::MAX.to_le_bytes()), json!(u64::MAX));
+        assert_eq!(scalar(FheType::Int128, &(-5i128).to_le_bytes()), json!("-5"));
+        assert_eq!(scalar(FheType::Uint128, &u128::MAX.to_le_bytes()), json!(u128::MAX.to_string()));
+        assert_eq!(scalar(FheType::Int256, &[0xff; 32]), json!("-1"));
+        let mut min = [0; 32];
+        min[31] = 0x80;
+        assert_eq!(scalar(FheType::Int256, &min), json!(format!("-{}", U256::one() << 255)));
+    }
+
+    #[test]
+    fn rejects_unreachable_thresholds() {
+        let urls = vec!["http://a".to_string(), "http://b".to_string()];
+        assert!(ThresholdDecryptor::connect(&urls, 0, String::new(), "k".to_string()).is_err());
+        assert!(ThresholdDecryptor::connect(&urls, 3, String::new(), "k".to_string()).is_err());
+        let decryptor = ThresholdDecryptor::connect(&urls, 2, String::new(), "k".to_string()).unwrap();
+        assert_eq!(decryptor.parties[1].url, "http://b/partial_decrypt");
+    }
+
+    #[test]
+    fn refuses_partials_of_another_party_or_key_set() {
+        let urls = vec!["http://a".to_string(), "http://b".to_string()];
+        let decryptor = ThresholdDecryptor::connect(&urls, 2, String::new(), "k".to_string()).unwrap();
+        let response = |party: usize, key_id: &str, blocks: usize| PartialResponse {
+            party,
+            key_id: key_id.to_string(),
+            partials: vec![0; blocks],
+        };
+        assert!(decryptor.check(1, 4, response(1, "k", 4)).is_ok());
+        assert!(decryptor.check(1, 4, response(0, "k", 4)).is_err());
+        assert!(decryptor.check(1, 4, response(1, "other", 4)).is_err());
+        assert!(decryptor.check(1, 4, response(1, "k", 3)).is_err());
+    }
+}
@@ -1,9 +1,172 @@
 use serde::{Deserialize, Serialize};
 
+/// Encrypted types the server stores, persisted per row as `tag()`. The tags
+/// are the result types of `fhe_handles::fhe_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FheType {
+    Bool,
+    Uint8,
+    Uint16,
+    Uint32,
+    Uint64,
+    Uint128,
+    Uint256,
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    Int128,
+    Int256,
+}
+
+impl FheType {
+    const ALL: [FheType; 13] = [
+        FheType::Bool,
+        FheType::Uint8,
+        FheType::Uint16,
+        FheType::Uint32,
+        FheType::Uint64,
+        FheType::Uint128,
+        FheType::Uint256,
+        FheType::Int8,
+        FheType::Int16,
+        FheType::Int32,
+        FheType::Int64,
+        FheType::Int128,
+        FheType::Int256,
+    ];
+
+    pub fn tag(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.get(tag as usize).copied()
+    }
+
+    /// Width of the plaintext in bits, 1 for a boolean.
+    pub fn bits(self) -> u32 {
+        match self {
+            FheType::Bool => 1,
+            FheType::Uint8 | FheType::Int8 => 8,
+            FheType::Uint16 | FheType::Int16 => 16,
+            FheType::Uint32 | FheType::Int32 => 32,
+            FheType::Uint64 | FheType::Int64 => 64,
+            FheType::Uint128 | FheType::Int128 => 128,
+            FheType::Uint256 | FheType::Int256 => 256,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, FheType::Int8 | FheType::Int16 | FheType::Int32 | FheType::Int64 | FheType::Int128 | FheType::Int256)
+    }
+}
+
+impl std::fmt::Display for FheType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Homomorphic operations accepted by `/op`, see `compute::execute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Opcode {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Min,
+    Max,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Neg,
+    Not,
+    Select,
+}
+
+impl Opcode {
+    /// Number the opcode has in result handle derivations, see `fhe_handles`
+    pub fn code(self) -> u8 {
+        use fhe_handles::opcode;
+        match self {
+            Opcode::Add => opcode::ADD,
+            Opcode::Sub => opcode::SUB,
+            Opcode::Mul => opcode::MUL,
+            Opcode::Div => opcode::DIV,
+            Opcode::Rem => opcode::REM,
+            Opcode::Min => opcode::MIN,
+            Opcode::Max => opcode::MAX,
+            Opcode::And => opcode::AND,
+            Opcode::Or => opcode::OR,
+            Opcode::Xor => opcode::XOR,
+            Opcode::Shl => opcode::SHL,
+            Opcode::Shr => opcode::SHR,
+            Opcode::Eq => opcode::EQ,
+            Opcode::Ne => opcode::NE,
+            Opcode::Lt => opcode::LT,
+            Opcode::Le => opcode::LE,
+            Opcode::Gt => opcode::GT,
+            Opcode::Ge => opcode::GE,
+            Opcode::Neg => opcode::NEG,
+            Opcode::Not => opcode::NOT,
+            Opcode::Select => opcode::SELECT,
+        }
+    }
+
+    /// Number of operand handles the opcode takes
+    pub fn arity(self) -> usize {
+        match self {
+            Opcode::Neg | Opcode::Not => 1,
+            Opcode::Select => 3,
+            _ => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub value: u64,
     pub key: [u8; 32],
+    /// Solana pubkey allowed to decrypt the value, unowned values cannot be decrypted
+    #[serde(default)]
+    pub owner: Option<[u8; 32]>,
+    /// What `key` was derived from besides the value, checked before it is written
+    #[serde(default)]
+    pub origin: Option<HandleOrigin>,
+}
+
+/// A ciphertext encrypted client-side under the server's `CompactPublicKey`.
+/// `ciphertext` is a bincode-serialized `ProvenCompactCiphertextList` whose
+/// first element is any `FheType`. Its proof metadata is `key` followed by
+/// `owner` if there is one, see `ingest::proof_metadata`, so the proof only
+/// stores the value at that key for that owner.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptedRequest {
+    pub key: [u8; 32],
+    pub ciphertext: Vec<u8>,
+    #[serde(default)]
+    pub owner: Option<[u8; 32]>,
+    /// What `key` was derived from besides the value type, checked before it is written
+    #[serde(default)]
+    pub origin: Option<HandleOrigin>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,20 +176,217 @@ pub struct Transfer {
     pub transfer_value: [u8; 32],
 }
 
+/// A decryption request authorized by the owner of `key`.
+/// `signature` is the owner's ed25519 signature over `auth::decrypt_message`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Decrypt {
     pub key: [u8; 32],
+    pub nonce: u64,
+    /// Unix timestamp in seconds after which the signature is no longer accepted
+    pub expiry: i64,
+    pub signature: Vec<u8>,
 }
 
+/// A request to seal the value at `key` to the owner's X25519 key `recipient`.
+/// `signature` is the owner's ed25519 signature over `auth::reencrypt_message`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reencrypt {
+    pub key: [u8; 32],
+    pub recipient: [u8; 32],
+    pub nonce: u64,
+    pub expiry: i64,
+    pub signature: Vec<u8>,
+}
+
+/// Withdraws either the encrypted amount stored at `value` or the plaintext `amount`,
+/// authorized by the owner of `key`.
+/// `signature` is the owner's ed25519 signature over `auth::withdraw_message`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Withdraw {
     pub key: [u8; 32],
-    pub value: [u8; 32],
+    #[serde(default)]
+    pub value: Option<[u8; 32]>,
+    #[serde(default)]
+    pub amount: Option<u64>,
+    pub nonce: u64,
+    pub expiry: i64,
+    pub signature: Vec<u8>,
+}
+
+/// A plaintext operand. Values outside the i64/u64 range are given as decimal strings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    Bool(bool),
+    Unsigned(u64),
+    Signed(i64),
+    Decimal(String),
 }
 
+impl std::fmt::Display for Scalar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scalar::Bool(value) => write!(f, "{}", *value as u8),
+            Scalar::Unsigned(value) => write!(f, "{}", value),
+            Scalar::Signed(value) => write!(f, "{}", value),
+            Scalar::Decimal(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Applies `opcode` to the values at `operands` and stores the result at `result`.
+/// For `select` the operands are the condition, the value if true and the value if false.
+/// With a `scalar`, the plaintext is the right-hand operand in place of the last handle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Operation {
+    pub opcode: Opcode,
+    pub operands: Vec<[u8; 32]>,
+    #[serde(default)]
+    pub scalar: Option<Scalar>,
+    pub result: [u8; 32],
+    #[serde(default)]
+    pub owner: Option<[u8; 32]>,
+    /// Inputs `result` was derived from besides the operation, checked before it is written
+    #[serde(default)]
+    pub origin: Option<HandleOrigin>,
+}
+
+/// The program and account nonce a result handle was derived from, see `fhe_handles::Origin`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct HandleOrigin {
+    pub program_id: [u8; 32],
+    pub account: [u8; 32],
+    pub nonce: u64,
+}
+
+impl From<HandleOrigin> for fhe_handles::Origin {
+    fn from(origin: HandleOrigin) -> Self {
+        Self { program_id: origin.program_id, account: origin.account, nonce: origin.nonce }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OperationResponse {
+    pub result_type: FheType,
+}
+
+/// An operand of a graph node, either a stored value or the output of an earlier node.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphInput {
+    Handle([u8; 32]),
+    Node(usize),
+}
+
+/// One operation of a `Graph`. The result is stored at `output` if given,
+/// otherwise it is only an intermediate for later nodes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphNode {
+    pub opcode: Opcode,
+    pub inputs: Vec<GraphInput>,
+    #[serde(default)]
+    pub scalar: Option<Scalar>,
+    #[serde(default)]
+    pub output: Option<[u8; 32]>,
+}
+
+/// A computation DAG. Nodes may only reference earlier nodes, so the list
+/// order is a valid execution order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    #[serde(default)]
+    pub owner: Option<[u8; 32]>,
+    /// What the node outputs were derived from, see `operations::derive_graph_handles`
+    #[serde(default)]
+    pub origin: Option<HandleOrigin>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphOutput {
+    pub key: [u8; 32],
+    pub result_type: FheType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphResponse {
+    pub outputs: Vec<GraphOutput>,
+}
+
+/// Body of every error response, `code` is stable and machine-readable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+}
+
+/// A decrypted value and the type it was stored as. Integers wider than 64
+/// bits are decimal strings.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ViewResponse {
-    pub result: u64,
+    pub result: Scalar,
+    pub result_type: FheType,
+}
+
+/// The value at the requested key in little-endian two's complement at the
+/// width of `result_type`, sealed with `sealing::seal` so only the holder of
+/// the recipient key can read it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReencryptResponse {
+    pub result_type: FheType,
+    pub ephemeral_public_key: [u8; 32],
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Counters of the decompressed ciphertext cache since startup.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub size_bytes: usize,
+    pub capacity_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// An operation accepted through `/jobs`, as `GET /jobs/{id}` and the
+/// completion callback report it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    /// The endpoint the job runs, e.g. `transfer`
+    pub kind: String,
+    pub status: JobStatus,
+    /// What the synchronous endpoint would have answered, once done
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// The error the synchronous endpoint would have answered, once failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Load of the compute pool.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ComputeStats {
+    pub workers: usize,
+    /// Jobs queued or running
+    pub pending: usize,
+    pub queue_limit: usize,
+    /// Jobs refused since startup because the queue was full
+    pub rejected: u64,
 }
 
-pub const zero_key: [u8; 32] = [0; 32];
\ No newline at end of file
+pub const ZERO_KEY: [u8; 32] = [0; 32];
\ No newline at end of file